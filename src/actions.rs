@@ -1,26 +1,57 @@
 //!  Actions that don't fit other modules.
 
-use std::fmt::Display;
-use std::path::{Path, PathBuf};
+use std::collections::HashSet;
 use std::fs;
+use std::path::{Path, PathBuf};
 
 use anyhow::{bail, Context, Result};
-use base64::prelude::*;
+use glob::Pattern;
 use indicatif::{ProgressBar, ProgressStyle};
 use log::{error, info};
-use textwrap::{fill, Options};
+use serde_json::Value;
+use textwrap::fill;
 
+use crate::card_formats::sillytavern::{
+    import_sillytavern_char, is_sillytavern_export,
+};
+use crate::card_formats::strict::check_duplicate_keys;
 use crate::card_formats::tavern_card_v2::{TavernCardV2, TEXT_KEY_PNG};
-use crate::card_formats::tavern_card_v3::TavernCardV3;
-use crate::tools;
+use crate::card_formats::tavern_card_v3::{TavernCardV3, TEXT_KEY_CCV3};
+use crate::card_formats::{
+    card_source_url, check_spec_version, decode_payload, flatten_json,
+    json5_fallback, json_error_snippet, preferred_version, read_card_json,
+    read_card_text, set_tool_extension, CardFile, PreferredVersion,
+    SpecVersion,
+};
+use crate::config::IssueLayout;
+use crate::dates::{self, DateFilter};
+use crate::error::TavernError;
+use crate::report::RunReport;
+use crate::tools::{DownloadOptions, FileOptions, ImageFormat};
+use crate::wrap::PrintOptions;
+use crate::{
+    card_providers, diff, macros, manifest, output_template, tools, validate,
+    wrap,
+};
 
-enum AnyTavernCard {
+pub(crate) enum AnyTavernCard {
     V2(TavernCardV2),
     V3(TavernCardV3),
 }
 
 impl AnyTavernCard {
-    fn from_png_image(image_data: &bytes::Bytes) -> Result<Self> {
+    /// Reads card of either version from PNG image. When the card can be read
+    /// as both, the preferred version wins, V3 by default.
+    pub(crate) fn from_png_image(image_data: &bytes::Bytes) -> Result<Self> {
+        // Newer specs would pass for V3 or V2, losing their new fields
+        if let Ok(json) = read_card_json(image_data) {
+            check_spec_version(&json)?;
+        }
+        if preferred_version() == PreferredVersion::V2 {
+            if let Ok(card_v2) = TavernCardV2::from_png_image(image_data) {
+                return Ok(AnyTavernCard::V2(card_v2));
+            }
+        }
         // Try V3 first
         if let Ok(card_v3) = TavernCardV3::from_png_image(image_data) {
             return Ok(AnyTavernCard::V3(card_v3));
@@ -29,81 +60,653 @@ impl AnyTavernCard {
         if let Ok(card_v2) = TavernCardV2::from_png_image(image_data) {
             return Ok(AnyTavernCard::V2(card_v2));
         }
-        bail!("Failed to parse image as either TavernCardV2 or TavernCardV3");
+        let what = "image as either TavernCardV2 or TavernCardV3";
+        bail!(TavernError::Parse(what.to_string()));
     }
+
+    pub(crate) fn from_json(json: &str) -> Result<Self> {
+        let strict = json5_fallback(json.as_bytes());
+        let json = strict.as_deref().unwrap_or(json);
+        // SillyTavern exports would pass for V3, having its top-level fields
+        if let Ok(value) = serde_json::from_str::<serde_json::Value>(json) {
+            check_spec_version(&value)?;
+            if is_sillytavern_export(&value) {
+                info!("Reading SillyTavern character export");
+                return Ok(AnyTavernCard::V2(import_sillytavern_char(&value)?));
+            }
+        }
+        if preferred_version() == PreferredVersion::V2 {
+            if let Ok(card_v2) = TavernCardV2::from_json(json) {
+                return Ok(AnyTavernCard::V2(card_v2));
+            }
+        }
+        // Try V3 first
+        if let Ok(card_v3) = TavernCardV3::from_json(json) {
+            return Ok(AnyTavernCard::V3(card_v3));
+        }
+        // Fallback to V2. Its error tells where the JSON is broken.
+        match TavernCardV2::from_json(json) {
+            Ok(card_v2) => Ok(AnyTavernCard::V2(card_v2)),
+            Err(e) => Err(anyhow::Error::from(e).context(
+                "Failed to parse JSON as either TavernCardV2 or TavernCardV3",
+            )),
+        }
+    }
+
+    /// Parses JSON as the given version, whatever version the card declares.
+    ///
+    /// V1 cards are read into V2 card. The declared version is corrected.
+    fn from_json_as(json: &str, version: SpecVersion) -> Result<Self> {
+        let strict = json5_fallback(json.as_bytes());
+        let json = strict.as_deref().unwrap_or(json);
+        let card = match version {
+            SpecVersion::V1 => {
+                let data = serde_json::from_str(json).map_err(|e| {
+                    let snippet = json_error_snippet(json, &e);
+                    TavernError::Parse(format!("card JSON: {}{}", e, snippet))
+                })?;
+                let mut card = TavernCardV2 { data, ..Default::default() };
+                card.spec = Some("chara_card_v2".to_string());
+                card.spec_version = Some("2.0".to_string());
+                AnyTavernCard::V2(card)
+            }
+            SpecVersion::V2 => {
+                let mut card = TavernCardV2::from_json(json)?;
+                card.spec = Some("chara_card_v2".to_string());
+                card.spec_version = Some("2.0".to_string());
+                AnyTavernCard::V2(card)
+            }
+            SpecVersion::V3 => {
+                let mut card = TavernCardV3::from_json(json)?;
+                card.spec = Some("chara_card_v3".to_string());
+                card.spec_version = Some("3.0".to_string());
+                AnyTavernCard::V3(card)
+            }
+        };
+        Ok(card)
+    }
+
+    pub(crate) fn to_json(&self) -> Result<String> {
+        match self {
+            AnyTavernCard::V2(card) => card.to_json(),
+            AnyTavernCard::V3(card) => card.to_json(),
+        }
+    }
+
+    /// Calls `f` for every text field that is sent to the model, with the
+    /// name of the field, see [`TavernCardV3::for_each_text_field`].
+    pub(crate) fn for_each_text_field<F: FnMut(&str, &str)>(&self, f: F) {
+        match self {
+            AnyTavernCard::V2(card) => card.for_each_text_field(f),
+            AnyTavernCard::V3(card) => card.for_each_text_field(f),
+        }
+    }
+
+    /// Returns a text field of the card data by its name, if it is set.
+    fn text_field(&self, field: &str) -> Option<&str> {
+        macro_rules! field {
+            ($data:expr) => {
+                match field {
+                    "name" => &$data.name,
+                    "description" => &$data.description,
+                    "personality" => &$data.personality,
+                    "scenario" => &$data.scenario,
+                    "first_mes" => &$data.first_mes,
+                    "mes_example" => &$data.mes_example,
+                    "creator_notes" => &$data.creator_notes,
+                    "system_prompt" => &$data.system_prompt,
+                    "post_history_instructions" => {
+                        &$data.post_history_instructions
+                    }
+                    "creator" => &$data.creator,
+                    "character_version" => &$data.character_version,
+                    _ => &None,
+                }
+            };
+        }
+        let value = match self {
+            AnyTavernCard::V2(card) => field!(card.data),
+            AnyTavernCard::V3(card) => field!(card.data),
+        };
+        value.as_deref().filter(|x| !x.trim().is_empty())
+    }
+
+    /// Returns the number of greetings: `first_mes` and `alternate_greetings`.
+    fn greeting_count(&self) -> usize {
+        let alternate = match self {
+            AnyTavernCard::V2(card) => &card.data.alternate_greetings,
+            AnyTavernCard::V3(card) => &card.data.alternate_greetings,
+        };
+        1 + alternate.as_ref().map_or(0, |x| x.len())
+    }
+
+    /// Returns the greeting by its index: 0 is `first_mes`, and the others
+    /// are `alternate_greetings` in order. Fails if there is no such greeting.
+    fn greeting(&self, index: usize) -> Result<&str> {
+        let (first_mes, alternate) = match self {
+            AnyTavernCard::V2(card) => {
+                (&card.data.first_mes, &card.data.alternate_greetings)
+            }
+            AnyTavernCard::V3(card) => {
+                (&card.data.first_mes, &card.data.alternate_greetings)
+            }
+        };
+        let alternate = alternate.as_deref().unwrap_or_default();
+        if index == 0 {
+            return Ok(first_mes.as_deref().unwrap_or_default());
+        }
+        match alternate.get(index - 1) {
+            Some(greeting) => Ok(greeting),
+            None if alternate.is_empty() => bail!(
+                "No greeting {}, the card has only the first message, greeting 0",
+                index
+            ),
+            None => bail!(
+                "No greeting {}, the card has {} greetings: 0 is the first message, \
+                 1 to {} are alternate greetings",
+                index,
+                alternate.len() + 1,
+                alternate.len()
+            ),
+        }
+    }
+}
+
+/// Reads card from PNG image, JSON or YAML file, whichever the path is, as
+/// the given version if there is one.
+///
+/// When reading as a given version, tells if automatic detection would have
+/// failed.
+fn read_any_card(
+    path: &Path,
+    version: Option<SpecVersion>,
+    file_options: &FileOptions,
+) -> Result<AnyTavernCard> {
+    let image = tools::read_image_from_file(path, file_options)?;
+    if let Some(json) = read_card_file_text(path, &image)? {
+        return match version {
+            Some(version) => parse_card_as(&json, version),
+            None => AnyTavernCard::from_json(&json),
+        };
+    }
+    let Some(version) = version else {
+        return AnyTavernCard::from_png_image(&image);
+    };
+    let json = read_card_payload(&image, None)?
+        .ok_or_else(|| TavernError::MissingChunk(TEXT_KEY_PNG.to_string()))?;
+    parse_card_as(&json, version)
+}
+
+/// Fails if an object of the card JSON has the same key twice, see
+/// [`check_duplicate_keys`]. Reads PNG images and JSON files.
+pub fn check_duplicate_keys_in_file(
+    path: &Path,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let data = tools::read_image_from_file(path, file_options)?;
+    let json = match read_card_file_text(path, &data)? {
+        Some(json) => json,
+        None => read_card_text(&data)?,
+    };
+    check_duplicate_keys(&json)
+}
+
+/// Returns the card JSON of a JSON or YAML card file, or `None` for an
+/// image. The format is told by the extension, or by the content if the
+/// extension is not known.
+fn read_card_file_text(path: &Path, data: &[u8]) -> Result<Option<String>> {
+    let extension = path
+        .extension()
+        .and_then(|x| x.to_str())
+        .map(|x| x.to_ascii_lowercase());
+    let is_json = match extension.as_deref() {
+        Some("json") => true,
+        Some("yaml" | "yml") => {
+            let text = std::str::from_utf8(data)
+                .context("YAML card is not valid UTF-8")?;
+            let card: Value = serde_yaml::from_str(tools::strip_bom(text))
+                .with_context(|| {
+                    format!("Could not parse {}", path.display())
+                })?;
+            return Ok(Some(serde_json::to_string(&card)?));
+        }
+        Some("png") => false,
+        _ => {
+            let data =
+                data.strip_prefix(tools::UTF8_BOM.as_bytes()).unwrap_or(data);
+            data.trim_ascii_start().starts_with(b"{")
+        }
+    };
+    if !is_json {
+        return Ok(None);
+    }
+    let json =
+        std::str::from_utf8(data).context("JSON card is not valid UTF-8")?;
+    Ok(Some(tools::strip_bom(json).to_string()))
+}
+
+fn parse_card_as(json: &str, version: SpecVersion) -> Result<AnyTavernCard> {
+    let card = AnyTavernCard::from_json_as(json, version)?;
+    if let Err(e) = AnyTavernCard::from_json(json) {
+        println!("Read as {:?}, automatic detection failed: {:#}", version, e);
+    }
+    Ok(card)
 }
 
-impl Display for AnyTavernCard {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl AnyTavernCard {
+    /// Lists the card fields as printed text, see [`TavernCardV2::to_text`].
+    pub(crate) fn to_text(&self, print: &PrintOptions) -> String {
         match self {
-            AnyTavernCard::V2(card) => write!(f, "{}", card),
-            AnyTavernCard::V3(card) => write!(f, "{}", card),
+            AnyTavernCard::V2(card) => card.to_text(print),
+            AnyTavernCard::V3(card) => card.to_text(print),
         }
     }
 }
 
 /// Prints the content of tavern card from a given file path
-pub fn print_tavern_card_from_path(path: &Path) -> Result<()> {
-    let image = tools::read_image_from_file(path)?;
-    let card = AnyTavernCard::from_png_image(&image)?;
-    println!("{}", card);
+///
+/// With `version`, reads the card as that version, whatever it declares.
+/// With `lang`, shows creator notes in that language instead of the base
+/// ones.
+pub fn print_tavern_card_from_path(
+    path: &Path,
+    version: Option<SpecVersion>,
+    lang: Option<&str>,
+    file_options: &FileOptions,
+    print: &PrintOptions,
+) -> Result<()> {
+    let mut card = read_any_card(path, version, file_options)?;
+    if let Some(lang) = lang {
+        match &mut card {
+            AnyTavernCard::V2(_) => {
+                bail!("V2 cards have no creator notes in other languages")
+            }
+            AnyTavernCard::V3(card) => card.use_creator_notes_language(lang)?,
+        }
+    }
+    println!("{}", card.to_text(print));
 
     Ok(())
 }
 
-/// Prints the content of a JSON tavern card from a given file path
-pub fn print_json_card_from_path(path: &Path) -> Result<()> {
-    let json_text = std::fs::read_to_string(path)?;
-    let card_v3_result = serde_json::from_str::<TavernCardV3>(&json_text);
-    let card_v2_result = serde_json::from_str::<TavernCardV2>(&json_text);
+/// Prints which fields the card has and which of them are filled, without
+/// their content: length of texts, and number of greetings, tags and
+/// lorebook entries.
+pub fn print_fields_from_path(
+    path: &Path,
+    version: Option<SpecVersion>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let card = read_any_card(path, version, file_options)?;
+    let card_json: Value = serde_json::from_str(&card.to_json()?)?;
+    let spec = match card {
+        AnyTavernCard::V2(_) => "V2",
+        AnyTavernCard::V3(_) => "V3",
+    };
+    println!("{} card {}", spec, path.display());
+    let Some(data) = card_json.get("data").and_then(|x| x.as_object()) else {
+        bail!("The card has no data");
+    };
+    for field in data.keys() {
+        println!("  {:<28} {}", field, field_shape(&card_json, field));
+    }
+    Ok(())
+}
 
-    if let Ok(card_v3) = card_v3_result {
-        println!("{}", card_v3);
-    } else if let Ok(card_v2) = card_v2_result {
-        println!("{}", card_v2);
-    } else {
-        bail!("Failed to parse JSON as either TavernCardV2 or TavernCardV3");
+/// Describes a field of the card data without its content: whether it is
+/// empty, the length of text, or the number of items. Text is empty the way
+/// the `empty_field` lint sees it.
+fn field_shape(card_json: &Value, field: &str) -> String {
+    match &card_json["data"][field] {
+        Value::Null => "not set".to_string(),
+        Value::String(_) => {
+            let text = validate::get_text(card_json, field);
+            if validate::is_empty_text(text) {
+                return "empty".to_string();
+            }
+            format!("{} characters", text.chars().count())
+        }
+        Value::Array(items) if items.is_empty() => "empty".to_string(),
+        Value::Array(items) => format!("{} items", items.len()),
+        Value::Object(map) => match map.get("entries") {
+            Some(Value::Array(entries)) => {
+                format!("{} entries", entries.len())
+            }
+            _ if map.is_empty() => "empty".to_string(),
+            _ => format!("{} keys", map.len()),
+        },
+        other => other.to_string(),
+    }
+}
+
+/// Prints example dialogues of the card, numbered. With `json`, prints
+/// them as a JSON list of dialogues, each a list of turns with `speaker`
+/// and `text`.
+pub fn print_examples_from_path(
+    path: &Path,
+    version: Option<SpecVersion>,
+    json: bool,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let examples = match read_any_card(path, version, file_options)? {
+        AnyTavernCard::V2(card) => card.split_examples(),
+        AnyTavernCard::V3(card) => card.split_examples(),
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&examples)?);
+        return Ok(());
+    }
+    if examples.is_empty() {
+        println!("The card has no example dialogues");
+        return Ok(());
+    }
+    let options =
+        wrap::options(80).initial_indent("    ").subsequent_indent("    ");
+    for (index, turns) in examples.iter().enumerate() {
+        println!("Example {}:", index + 1);
+        for turn in turns {
+            let speaker = if turn.name.is_empty() { "-" } else { &turn.name };
+            println!("  {}", speaker);
+            println!("{}", fill(&turn.text, &options));
+        }
+    }
+    Ok(())
+}
+
+/// Builds the character definition as a frontend puts it into the prompt.
+///
+/// Follows the default story string of SillyTavern: system prompt,
+/// description, personality and scenario, skipping empty fields. With
+/// `with_examples`, example messages are added after them. The greeting
+/// comes last, as the first message of the chat. `{{char}}` is replaced
+/// with the card name.
+fn assemble_prompt(
+    card: &AnyTavernCard,
+    with_examples: bool,
+    greeting: &str,
+) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    let mut add = |prefix: &str, field: &str| {
+        if let Some(text) = card.text_field(field) {
+            parts.push(format!("{}{}", prefix, text.trim()));
+        }
+    };
+    add("", "system_prompt");
+    add("", "description");
+    add("{{char}}'s personality: ", "personality");
+    add("Scenario: ", "scenario");
+    if with_examples {
+        add("", "mes_example");
+    }
+    if !greeting.trim().is_empty() {
+        parts.push(format!("{{{{char}}}}: {}", greeting.trim()));
+    }
+    let name = card.text_field("name").unwrap_or("{{char}}");
+    parts.join("\n").replace("{{char}}", name)
+}
+
+/// Returns `seed`, or a seed from the clock if there is none.
+fn seed_or_clock(seed: Option<u64>) -> u64 {
+    seed.unwrap_or_else(|| {
+        let now = std::time::SystemTime::now();
+        let elapsed =
+            now.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+        elapsed.as_nanos() as u64
+    })
+}
+
+/// Returns the greeting of the given index, or a random one if there is no
+/// index, telling which one was picked.
+fn choose_greeting<'a>(
+    card: &'a AnyTavernCard,
+    greeting: Option<usize>,
+    picker: &mut macros::Picker,
+) -> Result<&'a str> {
+    let index = match greeting {
+        Some(index) => index,
+        None => {
+            let count = card.greeting_count();
+            let index = picker.pick(count);
+            println!(
+                "Picked greeting {} of {} in all, 0 is the first message",
+                index, count
+            );
+            index
+        }
+    };
+    card.greeting(index)
+}
+
+/// Writes the character definition of the card into a text file, with
+/// the greeting of the given index, see [`assemble_prompt`].
+///
+/// Without an index, a random greeting is picked with `seed`, or with a
+/// seed from the clock, which is printed so that the pick can be repeated.
+pub fn export_prompt(
+    path: &Path,
+    output_path: &Path,
+    with_examples: bool,
+    greeting: Option<usize>,
+    seed: Option<u64>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let card = read_any_card(path, None, file_options)?;
+    let used_seed = seed_or_clock(seed);
+    let mut picker = macros::Picker::new(used_seed);
+    let text = choose_greeting(&card, greeting, &mut picker)?;
+    let prompt = assemble_prompt(&card, with_examples, text);
+    tools::write_text_file(output_path, &prompt)?;
+    println!("Prompt written to {}", output_path.display());
+    if greeting.is_none() && seed.is_none() {
+        println!("Greeting picked with --seed {}", used_seed);
+    }
+    Ok(())
+}
+
+/// Prints the greeting of the given index with `{{char}}`, `{{user}}` and
+/// `{{random}}` macros filled in, see [`macros::render_macros`]. Without an
+/// index, a random greeting is printed.
+///
+/// Random greetings and options are picked with `seed`, or with a seed from
+/// the clock, which is printed so that the preview can be repeated.
+pub fn render_greeting(
+    path: &Path,
+    greeting: Option<usize>,
+    user_name: &str,
+    seed: Option<u64>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let card = read_any_card(path, None, file_options)?;
+    let used_seed = seed_or_clock(seed);
+    let mut picker = macros::Picker::new(used_seed);
+    let text = choose_greeting(&card, greeting, &mut picker)?;
+    let char_name = card.text_field("name").unwrap_or("{{char}}");
+    let (rendered, picked) =
+        macros::render_macros(text, char_name, user_name, &mut picker);
+    println!("{}", fill(&rendered, wrap::options(usize::MAX)));
+    if (picked || greeting.is_none()) && seed.is_none() {
+        println!("\nRandom picks made with --seed {}", used_seed);
+    }
+    Ok(())
+}
+
+/// Pretty-prints `extensions` of the card data.
+///
+/// `key` selects a nested value, as keys separated by dots, like
+/// "depth_prompt.prompt". Numbers select items of arrays.
+pub fn print_extensions_from_path(
+    path: &Path,
+    key: Option<&str>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let image = tools::read_image_from_file(path, file_options)?;
+    let card_json = read_card_json(&image)?;
+    let extensions = card_json.get("data").and_then(|x| x.get("extensions"));
+    let Some(mut value) = extensions else {
+        println!("The card has no extensions");
+        return Ok(());
+    };
+    for part in key.into_iter().flat_map(|x| x.split('.')) {
+        let next = match value {
+            serde_json::Value::Array(items) => {
+                part.parse::<usize>().ok().and_then(|i| items.get(i))
+            }
+            _ => value.get(part),
+        };
+        value = next.with_context(|| {
+            let key = key.unwrap_or_default();
+            format!("No \"{}\" in extensions, at \"{}\"", key, part)
+        })?;
+    }
+    println!("{}", pretty_json(&value.to_string())?);
+    Ok(())
+}
+
+/// Prints keyword and text of every text chunk of the PNG file, in the order
+/// of the file. Long texts, like the card payload, are cut short.
+pub fn print_text_chunks(
+    path: &Path,
+    file_options: &FileOptions,
+) -> Result<()> {
+    const SHOWN_BYTES: usize = 60;
+    let image = tools::read_image_from_file(path, file_options)?;
+    let chunks = tools::read_all_text_chunks(&image)?;
+    if chunks.is_empty() {
+        println!("The image has no text chunks");
+        return Ok(());
+    }
+    for (keyword, text) in &chunks {
+        let text = text.escape_debug().to_string();
+        println!("{}: {}", keyword, wrap::truncate_to(&text, SHOWN_BYTES));
     }
+    println!("Total text chunks: {}", chunks.len());
+    Ok(())
+}
 
+/// Prints technical details of the card image, see [`tools::png_info`], and
+/// the name and spec of the card it holds. The pixels are not decoded.
+pub fn print_info(path: &Path, file_options: &FileOptions) -> Result<()> {
+    let image = tools::read_image_from_file(path, file_options)?;
+    let info = tools::png_info(&image)?;
+    println!("File size: {} bytes", image.len());
+    println!("Dimensions: {}x{}", info.width, info.height);
+    println!("Color type: {:?}, {} bits", info.color_type, info.bit_depth);
+    println!("Transparency: {}", if info.has_alpha { "yes" } else { "no" });
+    println!("Chunks: {}", info.chunk_count);
+    match read_card_json(&image) {
+        Ok(json) => {
+            let name = json.pointer("/data/name").or_else(|| json.get("name"));
+            let name = name.and_then(|x| x.as_str()).unwrap_or_default();
+            let spec = json
+                .get("spec")
+                .and_then(|x| x.as_str())
+                .unwrap_or("no spec, V1");
+            println!("Card: {} ({})", name, spec);
+        }
+        Err(e) => println!("Card: none, {:#}", e),
+    }
     Ok(())
 }
 
 /// Prints the JSON of the tavern card from path
-pub fn print_json_from_path(path: &Path) -> Result<()> {
-    let image = tools::read_image_from_file(path)?;
-    let tag = tools::read_text_chunk(&image, TEXT_KEY_PNG)?;
-    let tag = tag.map(|x| BASE64_STANDARD.decode(x).unwrap_or_default());
-    let text = tag.map(|x| String::from_utf8_lossy(&x).to_string());
+pub fn print_json_from_path(
+    path: &Path,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let text = read_card_payload_from_file(path, None, file_options)?;
     let text = text.unwrap_or_else(|| "NO TEXT".to_string());
 
     // Attempt to pretty print the JSON. If it fails, just print the raw text.
     let pretty_text = pretty_json(&text).unwrap_or_else(|_| text.clone());
 
-    let options = Options::new(textwrap::termwidth());
-    let filled_text = fill(&pretty_text, options);
+    let filled_text = fill(&pretty_text, wrap::options(usize::MAX));
     println!("{}", filled_text);
     Ok(())
 }
 
-/// Processes all PNG cards in the input directory.
+/// Which files `process_all_cards` writes for each card.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Artifacts {
+    Json,
+    Image,
+    #[default]
+    Both,
+}
+
+/// Settings of [`process_all_cards`], from the options of `process_all`.
+#[derive(Debug, Default)]
+pub struct ProcessOptions {
+    /// Which files to extract from each card. Only failures to extract them
+    /// count, so with images only, cards with broken JSON are fine.
+    pub artifacts: Artifacts,
+    /// Stop at the first card that fails, rather than at the end.
+    pub fail_fast: bool,
+    pub follow_symlinks: bool,
+    /// Only cards within this range are processed.
+    pub dates: DateFilter,
+    /// Add hashes of the written files to the manifest in the output
+    /// directory, see [`manifest::update_manifest`].
+    pub checksum_manifest: bool,
+    /// Name outputs after the card data, see [`output_template`]. Cards
+    /// that would get the same name get numeric suffixes.
+    pub template: Option<String>,
+    /// Skip cards that did not change since they were processed.
+    pub incremental: bool,
+    /// Save the decoded card data of failed cards next to them.
+    pub dump_on_error: bool,
+    /// Copy failed cards to the issue subfolder, leaving the input as it is.
+    pub copy_issues: bool,
+    /// Inputs to skip, see [`tools::exclude_files`].
+    pub exclude: Vec<Pattern>,
+    /// Write a summary of the run there at the end, see [`RunReport`].
+    pub report_path: Option<PathBuf>,
+    /// Write the summary as JSON rather than text.
+    pub report_json: bool,
+    /// How the cards are read and written.
+    pub file_options: FileOptions,
+}
+
+impl ProcessOptions {
+    /// Returns the key of the card in the incremental state: its path, with
+    /// the options that decide which outputs are written for it, so that a
+    /// run with other options processes the card again.
+    fn state_key(&self, file_path: &Path) -> String {
+        let artifacts = format!("{:?}", self.artifacts).to_lowercase();
+        let mut key = format!("{} [only={}", file_path.display(), artifacts);
+        if let Some(template) = &self.template {
+            key += &format!(", template={}", template);
+        }
+        key + "]"
+    }
+}
+
+/// Processes all PNG cards in the input directory, or all files matching
+/// the input glob pattern, as `options` say.
 ///
 /// For each card, it extracts the JSON and image data, saving them to the output directory.
-/// If a card cannot be processed, it is moved to an appropriate issue subfolder.
+/// If a card cannot be processed, it is moved to an issue subfolder chosen by `issue_layout`.
+/// Processes all cards and returns error at the end if any of them failed, unless
+/// told to fail fast.
 pub fn process_all_cards(
     input_dir: &Path,
     output_dir: &Path,
     issue_dir: &Path,
+    issue_layout: &IssueLayout,
+    options: &ProcessOptions,
 ) -> Result<()> {
     info!("Starting batch processing of cards from: {}", input_dir.display());
 
-    let input_files: Vec<PathBuf> = fs::read_dir(input_dir)?
-        .filter_map(|entry| entry.ok())
-        .map(|entry| entry.path())
-        .filter(|path| path.is_file() && path.extension().map_or(false, |ext| ext == "png"))
-        .collect();
+    let input_files =
+        tools::list_input_files(input_dir, options.follow_symlinks)?;
+    let input_files = tools::exclude_files(input_files, &options.exclude);
+    let input_files =
+        dates::filter_cards(input_files, &options.dates, &options.file_options);
 
     if input_files.is_empty() {
-        info!("No PNG files found in the input directory: {}", input_dir.display());
+        info!(
+            "No PNG files found in the input directory: {}",
+            input_dir.display()
+        );
         return Ok(());
     }
 
@@ -116,72 +719,402 @@ pub fn process_all_cards(
             .progress_chars("#>-"),
     );
 
+    let total = input_files.len();
+    let mut failed = 0;
+    let mut written = Vec::new();
+    let mut used_paths = HashSet::new();
+    let mut state = if options.incremental {
+        manifest::read_state(output_dir)?
+    } else {
+        Default::default()
+    };
+    let mut skipped = 0;
+    let mut report = RunReport::new(issue_layout);
     for file_path in input_files {
         let file_name = file_path.file_name().context("Invalid file name")?;
-        let stem = file_path.file_stem().context("Invalid file stem")?;
         pb.set_message(format!("Processing {}", file_name.to_string_lossy()));
 
-        let output_json_path = output_dir.join(format!("{}.json", stem.to_string_lossy()));
-        let output_image_path = output_dir.join(file_name);
+        // Cards that can not be hashed are processed, to be reported
+        let state_key = options.state_key(&file_path);
+        let hash = options
+            .incremental
+            .then(|| manifest::file_sha256(&file_path).ok())
+            .flatten();
+        if hash.is_some() && state.get(&state_key) == hash.as_ref() {
+            skipped += 1;
+            report.skipped.push(file_path.display().to_string());
+            pb.inc(1);
+            continue;
+        }
 
-        let result = (|| -> Result<()> {
-            // Extract JSON
-            extract_json_from_png(&file_path, &output_json_path)?;
-            // Extract Image
-            extract_image_from_png(&file_path, &output_image_path)?;
-            Ok(())
-        })();
+        let result = output_template::output_base(
+            &file_path,
+            output_dir,
+            options.template.as_deref(),
+            &options.file_options,
+        )
+        .map(|base| output_template::unique_path(base, &mut used_paths))
+        .and_then(|base| {
+            extract_artifacts(
+                &file_path,
+                &base,
+                options.artifacts,
+                &options.file_options,
+            )
+        });
 
+        if let Ok(files) = &result {
+            written.extend(files.iter().cloned());
+        }
         if let Err(e) = result {
-            error!("Failed to process {}: {}", file_path.display(), e);
-            let issue_sub_dir = if e.to_string().contains("Failed to parse") {
-                issue_dir.join("format")
-            } else if e.to_string().contains("No Chara entry") {
-                issue_dir.join("no_data")
-            } else {
-                issue_dir.join("other")
-            };
-            fs::create_dir_all(&issue_sub_dir)?;
-            let destination_path = issue_sub_dir.join(file_name);
-            fs::rename(&file_path, &destination_path)?;
+            let issue_path = move_to_issues(
+                &file_path,
+                issue_dir,
+                issue_layout,
+                &e,
+                options.copy_issues,
+            )?;
+            let issue_sub_dir = issue_path.parent().unwrap_or(issue_dir);
+            let folder =
+                issue_sub_dir.strip_prefix(issue_dir).unwrap_or(issue_sub_dir);
+            report.add_issue(&folder.to_string_lossy(), &file_path);
             pb.println(format!(
-                "Moved {} to {} due to error: {}",
+                "{} {} to {} due to error: {}",
+                if options.copy_issues { "Copied" } else { "Moved" },
                 file_name.to_string_lossy(),
                 issue_sub_dir.display(),
                 e
             ));
+            if options.dump_on_error {
+                match dump_payload(&issue_path, &options.file_options) {
+                    Ok(Some(dump_path)) => pb.println(format!(
+                        "Card data saved to {}",
+                        dump_path.display()
+                    )),
+                    Ok(None) => {}
+                    Err(e) => {
+                        pb.println(format!("Could not save card data: {:#}", e))
+                    }
+                }
+            }
+            failed += 1;
+            if options.fail_fast {
+                pb.abandon();
+                if options.incremental {
+                    manifest::write_state(output_dir, &state)?;
+                }
+                if let Some(report_path) = &options.report_path {
+                    report.write(report_path, options.report_json)?;
+                }
+                return Err(e.context(format!(
+                    "Failed to process {}",
+                    file_path.display()
+                )));
+            }
         } else {
             info!("Successfully processed {}", file_name.to_string_lossy());
+            report.succeeded.push(file_path.display().to_string());
+            if let Some(hash) = hash {
+                state.insert(state_key, hash);
+            }
         }
         pb.inc(1);
     }
 
     pb.finish_with_message("Batch processing complete!");
+    if options.incremental {
+        manifest::write_state(output_dir, &state)?;
+        println!(
+            "Skipped {} cards unchanged since they were processed",
+            skipped
+        );
+    }
+    if let Some(report_path) = &options.report_path {
+        report.write(report_path, options.report_json)?;
+    }
+    if options.checksum_manifest {
+        let manifest_path = manifest::update_manifest(output_dir, &written)?;
+        println!(
+            "Checksums of {} files written to {}",
+            written.len(),
+            manifest_path.display()
+        );
+    }
+    if failed > 0 {
+        bail!("{} of {} cards failed", failed, total);
+    }
     Ok(())
 }
 
+/// Extracts the selected artifacts of the card as `<output_base>.json` and
+/// `<output_base>.png`, creating folders as needed. Returns paths of the
+/// written files.
+///
+/// Either all artifacts are written or none, so a card that fails halfway
+/// leaves no orphan JSON in the output, see [`tools::write_all_or_nothing`].
+pub fn extract_artifacts(
+    file_path: &Path,
+    output_base: &Path,
+    artifacts: Artifacts,
+    file_options: &FileOptions,
+) -> Result<Vec<PathBuf>> {
+    if let Some(parent) = output_base.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let with_extension = |extension: &str| {
+        let mut path = output_base.as_os_str().to_owned();
+        path.push(extension);
+        PathBuf::from(path)
+    };
+    let mut outputs: Vec<(PathBuf, tools::OutputWriter)> = Vec::new();
+    if artifacts != Artifacts::Image {
+        // Broken cards go to the issue folder, rather than into the output
+        let text = read_card_payload_from_file(file_path, None, file_options)?
+            .ok_or_else(|| {
+                TavernError::MissingChunk(TEXT_KEY_PNG.to_string())
+            })?;
+        match serde_json::from_str::<serde_json::Value>(&text) {
+            Ok(json) => check_spec_version(&json)?,
+            Err(e) => bail!(TavernError::Parse(format!(
+                "{} entry in PNG tEXt chunks: {}{}",
+                TEXT_KEY_PNG,
+                e,
+                json_error_snippet(&text, &e)
+            ))),
+        }
+        outputs.push((
+            with_extension(".json"),
+            Box::new(|path| {
+                extract_json_from_png(
+                    file_path,
+                    path,
+                    None,
+                    false,
+                    None,
+                    false,
+                    file_options,
+                )
+            }),
+        ));
+    }
+    if artifacts != Artifacts::Json {
+        outputs.push((
+            with_extension(".png"),
+            Box::new(|path| {
+                extract_image_from_png(
+                    file_path,
+                    path,
+                    ImageFormat::Png,
+                    None,
+                    file_options,
+                )
+            }),
+        ));
+    }
+    tools::write_all_or_nothing(outputs)
+}
+
+/// Moves the card that failed with `error` into the issue subfolder chosen
+/// by `issue_layout`, or copies it there with `copy`. Returns the new path
+/// of the card.
+///
+/// Cards already in the subfolder are not overwritten: when the name is
+/// taken, like by a card of the same name from another input folder, a
+/// numeric suffix is added, like "card_2.png".
+pub fn move_to_issues(
+    file_path: &Path,
+    issue_dir: &Path,
+    issue_layout: &IssueLayout,
+    error: &anyhow::Error,
+    copy: bool,
+) -> Result<PathBuf> {
+    error!("Failed to process {}: {}", file_path.display(), error);
+    let file_name = file_path.file_name().context("Invalid file name")?;
+    let issue_sub_dir = issue_dir.join(issue_layout.folder_for_error(error));
+    fs::create_dir_all(&issue_sub_dir)?;
+    let issue_path = free_path(&issue_sub_dir.join(file_name));
+    if copy {
+        fs::copy(file_path, &issue_path)?;
+    } else {
+        fs::rename(file_path, &issue_path)?;
+    }
+    Ok(issue_path)
+}
+
+/// Returns the path, or the path with a numeric suffix added to the file
+/// stem, like "card_2.png", if a file already exists there.
+fn free_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|x| x.to_string_lossy());
+    let mut free = path.to_path_buf();
+    let mut number = 2;
+    while free.exists() {
+        let name = match &extension {
+            Some(extension) => format!("{}_{}.{}", stem, number, extension),
+            None => format!("{}_{}", stem, number),
+        };
+        free = path.with_file_name(name);
+        number += 1;
+    }
+    free
+}
+
+/// Writes the decoded card payload of the card as `<name>.raw.json` next to
+/// it, as it is, to see why it could not be parsed. Returns the path of the
+/// written file, or None if the card has no payload that decodes.
+fn dump_payload(
+    card_path: &Path,
+    file_options: &FileOptions,
+) -> Result<Option<PathBuf>> {
+    let payload = read_card_payload_from_file(card_path, None, file_options)?;
+    let Some(payload) = payload.filter(|x| !x.is_empty()) else {
+        return Ok(None);
+    };
+    let mut dump_path = card_path.with_extension("").into_os_string();
+    dump_path.push(".raw.json");
+    let dump_path = PathBuf::from(dump_path);
+    tools::write_text_file(&dump_path, &payload)?;
+    Ok(Some(dump_path))
+}
+
 /// Extracts the JSON from a PNG image and saves it to a specified JSON file.
-pub fn extract_json_from_png(image_path: &Path, output_path: &Path) -> Result<()> {
-    let image = tools::read_image_from_file(image_path)?;
-    let tag = tools::read_text_chunk(&image, TEXT_KEY_PNG)?;
-    let tag = tag.map(|x| BASE64_STANDARD.decode(x).unwrap_or_default());
-    let text = tag.map(|x| String::from_utf8_lossy(&x).to_string());
-    let text = text.unwrap_or_else(|| "NO TEXT".to_string());
+///
+/// The payload is decoded with `charset` if given, otherwise its encoding is
+/// detected. With `include_image_hash`, the hash of the image pixels is added
+/// to the JSON as `extensions.taverntools.image_sha256`. With `version`, the
+/// card is read as that version and written back in its shape, which fixes
+/// cards that declare a wrong version.
+pub fn extract_json_from_png(
+    image_path: &Path,
+    output_path: &Path,
+    charset: Option<&str>,
+    include_image_hash: bool,
+    version: Option<SpecVersion>,
+    flatten: bool,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let text = read_card_payload_from_file(image_path, charset, file_options)?;
+    let mut text = text.unwrap_or_else(|| "NO TEXT".to_string());
+
+    if let Some(version) = version {
+        text = parse_card_as(&text, version)?.to_json()?;
+    }
+
+    if include_image_hash {
+        let mut json: serde_json::Value = serde_json::from_str(&text)
+            .context("Failed to parse card JSON, can not add image hash")?;
+        // Only the hash needs the whole image
+        let image = tools::read_image_from_file(image_path, file_options)?;
+        let hash = tools::image_pixel_hash(&image)?;
+        set_tool_extension(&mut json, "image_sha256", hash.into())?;
+        text = serde_json::to_string(&json)?;
+    }
+
+    if flatten {
+        let json: serde_json::Value = serde_json::from_str(&text)
+            .context("Failed to parse card JSON, can not flatten it")?;
+        text = serde_json::to_string(&flatten_json(&json))?;
+    }
 
     let pretty_text = pretty_json(&text).unwrap_or_else(|_| text.clone());
-    std::fs::write(output_path, pretty_text)?;
+    tools::write_text_file(output_path, &pretty_text)?;
     Ok(())
 }
 
 /// Extracts the image data from a PNG image (without the embedded JSON) and saves it to a specified PNG file.
-pub fn extract_image_from_png(image_path: &Path, output_path: &Path) -> Result<()> {
-    let image = tools::read_image_from_file(image_path)?;
+///
+/// Both card chunks are removed, the V2 one and the V3 `ccv3` one. With WEBP
+/// format, the image is converted, lossy if `quality` is given, and the
+/// extension of the output path is replaced with ".webp".
+pub fn extract_image_from_png(
+    image_path: &Path,
+    output_path: &Path,
+    format: ImageFormat,
+    quality: Option<u8>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    if quality.is_some() && format != ImageFormat::Webp {
+        bail!("--quality is only for --format webp, PNG images are lossless");
+    }
+    let image = tools::read_image_from_file(image_path, file_options)?;
     let image_without_text = tools::remove_text_chunk(&image, TEXT_KEY_PNG)?;
-    tools::write_image_to_file(&image_without_text, output_path)?;
+    let image_without_text =
+        tools::remove_text_chunk(&image_without_text, TEXT_KEY_CCV3)?;
+    match format {
+        ImageFormat::Png => {
+            tools::write_image_to_file(&image_without_text, output_path)?
+        }
+        ImageFormat::Webp => {
+            let webp_image =
+                tools::convert_to_webp(&image_without_text, quality)
+                    .context("Could not convert image to WEBP")?;
+            let output_path = output_path.with_extension(format.extension());
+            tools::write_image_to_file(&webp_image, &output_path)?
+        }
+    }
     Ok(())
 }
 
-fn pretty_json(text: &str) -> Result<String> {
+/// Checks if the card was changed at the site it was downloaded from.
+///
+/// Downloads the current version of the card and prints the changed fields.
+/// The image is not compared. With `update`, replaces the card with the
+/// current version, keeping the old one as `<name>.bak.png`.
+pub fn compare_with_source(
+    path: &Path,
+    update: bool,
+    options: &DownloadOptions,
+    file_options: &FileOptions,
+    print: &PrintOptions,
+) -> Result<()> {
+    let image = tools::read_image_from_file(path, file_options)?;
+    let old_json = read_card_json(&image)?;
+    let url = card_source_url(&old_json)
+        .context("The card does not record the URL it was downloaded from")?;
+    println!("Source: {}", url);
+
+    let new_card = card_providers::fetch_card(&url, options)?;
+    let new_json = serde_json::to_value(&new_card)?;
+    let changes = diff::diff_cards(&old_json, &new_json);
+    diff::print_changes(&changes, print);
+    if !update || changes.is_empty() {
+        return Ok(());
+    }
+
+    let backup_path = path.with_extension("bak.png");
+    fs::copy(path, &backup_path)?;
+    let new_image = new_card.into_png_image(file_options)?;
+    tools::write_image_to_file(&new_image, path)?;
+    println!("Card updated, old version saved as {}", backup_path.display());
+    Ok(())
+}
+
+/// Reads the card payload from PNG image and decodes it into text.
+fn read_card_payload(
+    image: &bytes::Bytes,
+    charset: Option<&str>,
+) -> Result<Option<String>> {
+    let tag = tools::read_text_chunk(image, TEXT_KEY_PNG)?;
+    tag.map(|tag| decode_card_payload(&tag, charset)).transpose()
+}
+
+/// Reads the card payload from PNG file, without loading the image data.
+fn read_card_payload_from_file(
+    path: &Path,
+    charset: Option<&str>,
+    file_options: &FileOptions,
+) -> Result<Option<String>> {
+    let tag =
+        tools::read_text_chunk_from_file(path, TEXT_KEY_PNG, file_options)?;
+    tag.map(|tag| decode_card_payload(&tag, charset)).transpose()
+}
+
+fn decode_card_payload(tag: &str, charset: Option<&str>) -> Result<String> {
+    let data = decode_payload(tag).unwrap_or_default();
+    Ok(tools::decode_text(&data, charset)?)
+}
+
+pub fn pretty_json(text: &str) -> Result<String> {
     // A JSON deserializer. You can use any Serde Deserializer here.
     let mut deserializer = serde_json::Deserializer::from_str(text);
 
@@ -195,3 +1128,164 @@ fn pretty_json(text: &str) -> Result<String> {
 
     Ok(String::from_utf8_lossy(&buf).to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::card_formats::{write_card_json, write_ccv3_json};
+
+    #[test]
+    fn test_state_key() {
+        let mut options =
+            ProcessOptions { artifacts: Artifacts::Json, ..Default::default() };
+        let path = Path::new("in/a.png");
+        assert_eq!(options.state_key(path), "in/a.png [only=json]");
+        options.template = Some("{name}".to_string());
+        let key = "in/a.png [only=json, template={name}]";
+        assert_eq!(options.state_key(path), key);
+    }
+
+    #[test]
+    fn test_read_as_v3_keeps_fields() -> Result<()> {
+        let data = json!({
+            "name": "Test",
+            "nickname": "Tess",
+            "source": ["https://example.com/test"],
+            "creation_date": 1700000000,
+            "modification_date": 1700000100,
+            "group_only_greetings": ["Hi all"],
+            "custom_field": { "kept": true }
+        });
+        let card = json!({
+            "spec": "chara_card_v3",
+            "spec_version": "3.0",
+            "data": data
+        });
+        let read =
+            AnyTavernCard::from_json_as(&card.to_string(), SpecVersion::V3)?;
+        let written: Value = serde_json::from_str(&read.to_json()?)?;
+        for (field, value) in data.as_object().unwrap() {
+            assert_eq!(&written["data"][field], value, "{}", field);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_to_issues_keeps_same_names() -> Result<()> {
+        let dir = std::env::temp_dir()
+            .join(format!("taverntools_issues_{}", std::process::id()));
+        let issue_dir = dir.join("issue");
+        let layout = IssueLayout::default();
+        let error = anyhow::anyhow!("broken");
+        for folder in ["a", "b"] {
+            std::fs::create_dir_all(dir.join(folder))?;
+            let path = dir.join(folder).join("card.png");
+            std::fs::write(&path, folder)?;
+            move_to_issues(&path, &issue_dir, &layout, &error, false)?;
+        }
+        let other = issue_dir.join("other");
+        assert_eq!(std::fs::read_to_string(other.join("card.png"))?, "a");
+        assert_eq!(std::fs::read_to_string(other.join("card_2.png"))?, "b");
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_field_shape() {
+        let card = json!({
+            "data": {
+                "name": "Test",
+                "description": " \n",
+                "tags": [],
+                "character_book": { "entries": [{}, {}] },
+                "extensions": {}
+            }
+        });
+        let shape = |field| field_shape(&card, field);
+        assert_eq!(shape("name"), "4 characters");
+        assert_eq!(shape("description"), "empty");
+        assert_eq!(shape("tags"), "empty");
+        assert_eq!(shape("character_book"), "2 entries");
+        assert_eq!(shape("extensions"), "empty");
+    }
+
+    #[test]
+    fn test_extract_artifacts_all_or_nothing() -> Result<()> {
+        let dir = std::env::temp_dir()
+            .join(format!("taverntools_extract_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let card = json!({
+            "spec": "chara_card_v2",
+            "spec_version": "2.0",
+            "data": { "name": "Test" }
+        });
+        let files = FileOptions::default();
+        let image =
+            write_card_json(&card, &tools::get_default_image(), &files)?;
+        let card_path = dir.join("card.png");
+        tools::write_image_to_file(&image, &card_path)?;
+        let output_base = dir.join("out").join("card");
+
+        let both = Artifacts::Both;
+        let written =
+            extract_artifacts(&card_path, &output_base, both, &files)?;
+        assert_eq!(written.len(), 2);
+        for path in &written {
+            fs::remove_file(path)?;
+        }
+
+        // The image can not be put in place, so the JSON is not kept either
+        fs::create_dir_all(dir.join("out/card.png/in_the_way"))?;
+        let result = extract_artifacts(&card_path, &output_base, both, &files);
+        assert!(result.is_err());
+        assert!(!dir.join("out/card.json").exists());
+        assert!(!dir.join("out/card.json.part").exists());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_image() -> Result<()> {
+        let dir = std::env::temp_dir()
+            .join(format!("taverntools_image_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let card = json!({ "spec": "chara_card_v3", "data": { "name": "A" } });
+        let files = FileOptions::default();
+        let image =
+            write_card_json(&card, &tools::get_default_image(), &files)?;
+        let image = write_ccv3_json(&card, &image, &files)?;
+        let card_path = dir.join("card.png");
+        tools::write_image_to_file(&image, &card_path)?;
+
+        let png_path = dir.join("image.png");
+        let format = ImageFormat::Png;
+        extract_image_from_png(&card_path, &png_path, format, None, &files)?;
+        let png = tools::read_image_from_file(&png_path, &files)?;
+        assert_eq!(tools::read_text_chunk(&png, TEXT_KEY_PNG)?, None);
+        assert_eq!(tools::read_text_chunk(&png, TEXT_KEY_CCV3)?, None);
+
+        let webp = ImageFormat::Webp;
+        for quality in [None, Some(50)] {
+            extract_image_from_png(
+                &card_path, &png_path, webp, quality, &files,
+            )?;
+            let data = fs::read(dir.join("image.webp"))?;
+            let format = image::guess_format(&data)?;
+            assert_eq!(format, image::ImageFormat::WebP);
+            image::load_from_memory(&data)?;
+        }
+        let png = ImageFormat::Png;
+        let result = extract_image_from_png(
+            &card_path,
+            &png_path,
+            png,
+            Some(50),
+            &files,
+        );
+        assert!(result.is_err());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+//! Anonymized copies of cards, for sharing reproducers of bugs.
+
+use std::path::Path;
+
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::card_formats::examples::EXAMPLE_START;
+use crate::card_formats::{read_card_json, write_card_json};
+use crate::tools::{self, FileOptions};
+
+/// Fields whose text is structure rather than prose, kept as they are.
+const KEPT_FIELDS: &[&str] =
+    &["spec", "spec_version", "position", "type", "ext", "uri"];
+
+/// Words that replace the text, in order.
+const LOREM: &str = "lorem ipsum dolor sit amet consectetur adipiscing elit \
+    sed do eiusmod tempor incididunt ut labore et dolore magna aliqua";
+
+/// Replaces every word of the text with a placeholder word of the same
+/// length. Whitespace, line breaks and punctuation are kept, so the text has
+/// the same length and number of lines, and so are macros like `{{user}}`
+/// and `<START>` separators of example dialogues.
+fn placeholder_text(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut words = LOREM.split_whitespace().cycle();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if let Some(markup) = markup_at_start(rest) {
+            result.push_str(markup);
+            rest = &rest[markup.len()..];
+            continue;
+        }
+        if !c.is_alphanumeric() {
+            result.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        let end = rest.find(|x: char| !x.is_alphanumeric());
+        let (word, tail) = rest.split_at(end.unwrap_or(rest.len()));
+        let placeholder = words.next().unwrap_or("lorem");
+        result.extend(placeholder.chars().cycle().take(word.chars().count()));
+        rest = tail;
+    }
+    result
+}
+
+/// Returns the macro or example separator the text starts with, if any.
+fn markup_at_start(text: &str) -> Option<&str> {
+    if text.starts_with("{{") {
+        let end = text.find("}}")?;
+        return Some(&text[..end + 2]);
+    }
+    let start = text.get(..EXAMPLE_START.len())?;
+    start.eq_ignore_ascii_case(EXAMPLE_START).then_some(start)
+}
+
+/// Replaces the text of every string in the JSON, except `KEPT_FIELDS`.
+/// Keys, numbers, booleans and the length of lists stay the same.
+///
+/// Returns the number of strings replaced.
+fn anonymize_value(value: &mut Value) -> usize {
+    match value {
+        Value::String(text) if text.chars().any(|x| x.is_alphanumeric()) => {
+            *text = placeholder_text(text);
+            1
+        }
+        Value::Array(items) => items.iter_mut().map(anonymize_value).sum(),
+        Value::Object(map) => map
+            .iter_mut()
+            .filter(|(key, _)| !KEPT_FIELDS.contains(&key.as_str()))
+            .map(|(_, x)| anonymize_value(x))
+            .sum(),
+        _ => 0,
+    }
+}
+
+/// Writes a copy of the card with all text replaced by placeholder text.
+///
+/// The copy keeps the structure of the card: spec version, fields, number of
+/// tags, greetings and lorebook entries, and the length and number of lines
+/// of every text. The image is replaced by the base image, so embedded
+/// assets are not copied.
+pub fn anonymize_card(
+    png_path: &Path,
+    output_path: &Path,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let image_data = tools::read_image_from_file(png_path, file_options)?;
+    let mut card_json = read_card_json(&image_data)?;
+    let count = anonymize_value(&mut card_json);
+    let new_image =
+        write_card_json(&card_json, &tools::base_image(), file_options)?;
+    tools::write_image_to_file(&new_image, output_path)?;
+    println!(
+        "Replaced {} texts, anonymized card written to {}",
+        count,
+        output_path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_anonymize_value() {
+        let text = "Hello, {{user}}!\nIt's 2024.";
+        let placeholder = placeholder_text(text);
+        assert_eq!(placeholder, "lorem, {{user}}!\nip'd sits.");
+        assert_eq!(placeholder.chars().count(), text.chars().count());
+        assert_eq!(placeholder.lines().count(), text.lines().count());
+        let text = "<start>\n{{user}}: Hi, {{char}}!\n{{char}}: Hello";
+        let placeholder = placeholder_text(text);
+        assert_eq!(
+            placeholder,
+            "<start>\n{{user}}: lo, {{char}}!\n{{char}}: ipsum"
+        );
+
+        let mut card = json!({
+            "spec": "chara_card_v3",
+            "data": {
+                "name": "Secret",
+                "tags": ["a", "b"],
+                "assets": [{ "type": "icon", "uri": "ccdefault:" }],
+                "character_book": {
+                    "entries": [{ "keys": ["key"], "position": "before_char" }]
+                }
+            }
+        });
+        assert_eq!(anonymize_value(&mut card), 4);
+        assert_eq!(card["spec"], "chara_card_v3");
+        assert_eq!(card["data"]["name"], "loreml");
+        assert_eq!(card["data"]["tags"], json!(["l", "l"]));
+        assert_eq!(card["data"]["assets"][0]["uri"], "ccdefault:");
+        let entry = &card["data"]["character_book"]["entries"][0];
+        assert_eq!(entry["position"], "before_char");
+    }
+}
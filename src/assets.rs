@@ -0,0 +1,248 @@
+//! Management of V3 card assets, like icons and backgrounds.
+//!
+//! Assets added to PNG cards are embedded the way RisuAI does it: the data
+//! goes base64-encoded into a tEXt chunk named `chara-ext-asset_:<id>`, and
+//! the asset gets `__asset:<id>` as its URI.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use base64::prelude::*;
+use bytes::Bytes;
+use serde_json::Value;
+
+use crate::card_formats::read_card_json;
+use crate::card_formats::tavern_card_v3::Asset;
+use crate::edit::{card_data_mut, edit_card_and_image, is_v3_card};
+use crate::tools::{self, FileOptions};
+
+const ASSETS_POINTER: &str = "/data/assets";
+
+/// Prefix of the PNG chunks with embedded asset data.
+pub(crate) const ASSET_CHUNK_PREFIX: &str = "chara-ext-asset_:";
+
+/// Prefix of the URIs of assets embedded in the card file.
+const EMBEDDED_URI_PREFIX: &str = "__asset:";
+
+/// Reads the assets from card JSON. Cards without assets give none.
+pub fn read_assets(card_json: &Value) -> Result<Vec<Asset>> {
+    match card_json.pointer(ASSETS_POINTER) {
+        None | Some(Value::Null) => Ok(Vec::new()),
+        Some(assets) => serde_json::from_value(assets.clone())
+            .context("Could not parse the assets"),
+    }
+}
+
+pub(crate) fn write_assets(
+    card_json: &mut Value,
+    assets: &[Asset],
+) -> Result<()> {
+    let data = card_data_mut(card_json)?;
+    data.insert("assets".to_string(), serde_json::to_value(assets)?);
+    Ok(())
+}
+
+/// Returns the id of the asset embedded in the card file, if it is one.
+fn embedded_id(asset: &Asset) -> Option<&str> {
+    asset.uri.strip_prefix(EMBEDDED_URI_PREFIX)
+}
+
+fn chunk_key(id: &str) -> String {
+    format!("{}{}", ASSET_CHUNK_PREFIX, id)
+}
+
+/// Prints all assets of the card, with the size of embedded ones.
+pub fn list_assets(png_path: &Path, file_options: &FileOptions) -> Result<()> {
+    let image_data = tools::read_image_from_file(png_path, file_options)?;
+    let assets = read_assets(&read_card_json(&image_data)?)?;
+    if assets.is_empty() {
+        println!("The card has no assets");
+        return Ok(());
+    }
+    for (index, asset) in assets.iter().enumerate() {
+        println!(
+            "[{}] {} ({}, {}): {}",
+            index, asset.name, asset.asset_type, asset.ext, asset.uri
+        );
+        let Some(id) = embedded_id(asset) else { continue };
+        match tools::read_text_chunk(&image_data, &chunk_key(id))? {
+            Some(text) => match BASE64_STANDARD.decode(text.trim()) {
+                Ok(data) => println!("    embedded, {} bytes", data.len()),
+                Err(_) => println!("    embedded data is not valid base64"),
+            },
+            None => println!("    embedded data is missing from the file"),
+        }
+    }
+    println!("Total assets: {}", assets.len());
+    Ok(())
+}
+
+/// Returns an id for a new embedded asset, used by neither the assets nor
+/// the chunks of the image.
+fn next_asset_id(assets: &[Asset], image_data: &Bytes) -> Result<String> {
+    let mut id = assets
+        .iter()
+        .filter_map(|x| embedded_id(x)?.parse::<u64>().ok())
+        .max()
+        .map_or(0, |x| x + 1);
+    while tools::read_text_chunk(image_data, &chunk_key(&id.to_string()))?
+        .is_some()
+    {
+        id += 1;
+    }
+    Ok(id.to_string())
+}
+
+/// Embeds asset data into the image, as a chunk no asset uses yet. Returns
+/// the URI of the embedded data.
+pub(crate) fn embed_asset(
+    assets: &[Asset],
+    image_data: &mut Bytes,
+    data: &[u8],
+    file_options: &FileOptions,
+) -> Result<String> {
+    let id = next_asset_id(assets, image_data)?;
+    let text = BASE64_STANDARD.encode(data);
+    let key = chunk_key(&id);
+    *image_data =
+        tools::write_text_to_png(&key, &text, image_data, file_options)?;
+    Ok(format!("{}{}", EMBEDDED_URI_PREFIX, id))
+}
+
+/// Embeds the file into the card as a new asset of the given type.
+///
+/// The asset is named after the file, unless `name` is given. Only V3 cards
+/// have assets.
+pub fn add_asset(
+    png_path: &Path,
+    asset_type: &str,
+    file_path: &Path,
+    name: Option<&str>,
+    output_path: Option<&Path>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let data = std::fs::read(file_path)
+        .with_context(|| format!("Could not read {}", file_path.display()))?;
+    let file_name = |part: Option<&std::ffi::OsStr>| {
+        part.map(|x| x.to_string_lossy().to_string()).unwrap_or_default()
+    };
+    let name =
+        name.map_or_else(|| file_name(file_path.file_stem()), String::from);
+    let ext = file_name(file_path.extension()).to_lowercase();
+
+    edit_card_and_image(
+        png_path,
+        output_path,
+        file_options,
+        |card_json, image_data| {
+            if !is_v3_card(card_json) {
+                bail!("Assets are supported by V3 cards only");
+            }
+            let mut assets = read_assets(card_json)?;
+            if assets
+                .iter()
+                .any(|x| x.name == name && x.asset_type == asset_type)
+            {
+                bail!("The card already has {} asset \"{}\"", asset_type, name);
+            }
+            let uri = embed_asset(&assets, image_data, &data, file_options)?;
+            println!(
+                "Added {} asset \"{}\", {} bytes, as {}",
+                asset_type,
+                name,
+                data.len(),
+                uri
+            );
+            assets.push(Asset {
+                asset_type: asset_type.to_string(),
+                uri,
+                name,
+                ext,
+            });
+            write_assets(card_json, &assets)
+        },
+    )
+}
+
+/// Removes all assets with the given name from the card, and the embedded
+/// data no other asset uses.
+pub fn remove_asset(
+    png_path: &Path,
+    name: &str,
+    output_path: Option<&Path>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    edit_card_and_image(
+        png_path,
+        output_path,
+        file_options,
+        |card_json, image_data| {
+            let (removed, kept): (Vec<Asset>, Vec<Asset>) =
+                read_assets(card_json)?
+                    .into_iter()
+                    .partition(|x| x.name == name);
+            if removed.is_empty() {
+                bail!("The card has no asset named \"{}\"", name);
+            }
+            for asset in &removed {
+                println!(
+                    "Removed {} asset \"{}\"",
+                    asset.asset_type, asset.name
+                );
+                let Some(id) = embedded_id(asset) else { continue };
+                let in_use = kept.iter().any(|x| x.uri == asset.uri);
+                let key = chunk_key(id);
+                if !in_use
+                    && tools::read_text_chunk(image_data, &key)?.is_some()
+                {
+                    *image_data = tools::remove_text_chunk(image_data, &key)?;
+                }
+            }
+            write_assets(card_json, &kept)
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::card_formats::write_card_json;
+
+    #[test]
+    fn test_add_and_remove_asset() -> Result<()> {
+        let dir = std::env::temp_dir().join("taverntools_test_assets");
+        std::fs::create_dir_all(&dir)?;
+        let card = json!({
+            "spec": "chara_card_v3",
+            "spec_version": "3.0",
+            "data": { "name": "Test" }
+        });
+        let files = FileOptions::default();
+        let image =
+            write_card_json(&card, &tools::get_default_image(), &files)?;
+        let card_path = dir.join("card.png");
+        tools::write_image_to_file(&image, &card_path)?;
+        std::fs::write(dir.join("Smile.PNG"), b"image")?;
+
+        let smile = dir.join("Smile.PNG");
+        add_asset(&card_path, "emotion", &smile, None, None, &files)?;
+        let image = tools::read_image_from_file(&card_path, &files)?;
+        let assets = read_assets(&read_card_json(&image)?)?;
+        assert_eq!(assets.len(), 1);
+        assert_eq!(assets[0].name, "Smile");
+        assert_eq!(assets[0].ext, "png");
+        assert_eq!(assets[0].uri, "__asset:0");
+        let chunk = tools::read_text_chunk(&image, "chara-ext-asset_:0")?;
+        assert_eq!(chunk, Some(BASE64_STANDARD.encode(b"image")));
+
+        remove_asset(&card_path, "Smile", None, &files)?;
+        let image = tools::read_image_from_file(&card_path, &files)?;
+        assert!(read_assets(&read_card_json(&image)?)?.is_empty());
+        assert_eq!(tools::read_text_chunk(&image, "chara-ext-asset_:0")?, None);
+        assert!(remove_asset(&card_path, "Smile", None, &files).is_err());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
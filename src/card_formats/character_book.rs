@@ -0,0 +1,249 @@
+//! Lorebook (character book) shared by V2 and V3 cards.
+
+use std::collections::BTreeSet;
+
+use serde_json::{json, Value};
+
+use super::{extensions_or_null, Extensions};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, PartialEq)]
+pub struct CharacterBook {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub scan_depth: Option<u32>,
+    pub token_budget: Option<u32>,
+    pub recursive_scanning: Option<bool>,
+    #[serde(default, deserialize_with = "extensions_or_null")]
+    pub extensions: Extensions,
+    #[serde(default)]
+    pub entries: Vec<CharacterBookEntry>,
+    /// Fields not in the spec, kept as they are.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, Value>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, PartialEq)]
+pub struct CharacterBookEntry {
+    #[serde(default)]
+    pub keys: Vec<String>,
+    #[serde(default)]
+    pub content: String,
+    #[serde(default, deserialize_with = "extensions_or_null")]
+    pub extensions: Extensions,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub insertion_order: Option<u32>,
+    /// Whether keys match only with the same case. Frontends ignore case
+    /// when it is not set.
+    pub case_sensitive: Option<bool>,
+    pub name: Option<String>,
+    pub priority: Option<u32>,
+    pub id: Option<u32>,
+    pub comment: Option<String>,
+    pub selective: Option<bool>,
+    pub secondary_keys: Option<Vec<String>>,
+    pub constant: Option<bool>,
+    pub position: Option<String>,
+    /// V3 only: keys are regular expressions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub use_regex: Option<bool>,
+    /// Fields not in the spec, kept as they are.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, Value>,
+}
+
+fn default_enabled() -> bool { true }
+
+impl CharacterBookEntry {
+    /// Returns the keys the way the entry matches them: trimmed, and
+    /// lowercased unless the entry is case-sensitive. SillyTavern keeps the
+    /// setting in the extensions. `ignore_case` lowercases them anyway.
+    pub fn matched_keys(&self, ignore_case: bool) -> BTreeSet<String> {
+        let case_sensitive = self.case_sensitive.or_else(|| {
+            self.extensions.get("case_sensitive").and_then(|x| x.as_bool())
+        });
+        let fold_case = ignore_case || case_sensitive != Some(true);
+        self.keys
+            .iter()
+            .map(|key| match fold_case {
+                true => key.trim().to_lowercase(),
+                false => key.trim().to_string(),
+            })
+            .collect()
+    }
+}
+
+impl CharacterBook {
+    /// Adds a new entry, giving it the next free id and insertion order.
+    pub fn add_entry(&mut self, keys: Vec<String>, content: String) -> usize {
+        let next_id = self.entries.iter().filter_map(|e| e.id).max();
+        let next_order =
+            self.entries.iter().filter_map(|e| e.insertion_order).max();
+        let entry = CharacterBookEntry {
+            keys,
+            content,
+            enabled: true,
+            id: Some(next_id.map_or(0, |x| x + 1)),
+            insertion_order: Some(next_order.map_or(0, |x| x + 1)),
+            ..Default::default()
+        };
+        self.entries.push(entry);
+        self.entries.len() - 1
+    }
+
+    /// Removes entries with the same keys and content as an earlier entry.
+    /// Returns the indices of removed entries, with the indices of the
+    /// entries they duplicate, as they were before removing.
+    ///
+    /// Keys are compared the way the entries match them, see
+    /// [`CharacterBookEntry::matched_keys`]. With `ignore_case_keys`, case of
+    /// all keys is ignored, whatever the entries say.
+    pub fn dedupe_entries(
+        &mut self,
+        ignore_case_keys: bool,
+    ) -> Vec<(usize, usize)> {
+        let mut seen: Vec<(BTreeSet<String>, &str, usize)> = Vec::new();
+        let mut removed = Vec::new();
+        for (index, entry) in self.entries.iter().enumerate() {
+            let keys = entry.matched_keys(ignore_case_keys);
+            let content = entry.content.trim();
+            let original = seen.iter().find(|x| x.0 == keys && x.1 == content);
+            match original {
+                Some(original) => removed.push((index, original.2)),
+                None => seen.push((keys, content, index)),
+            }
+        }
+        let mut index = 0;
+        self.entries.retain(|_| {
+            index += 1;
+            !removed.iter().any(|x| x.0 == index - 1)
+        });
+        removed
+    }
+
+    /// Converts lorebook into SillyTavern world info.
+    ///
+    /// Entries are keyed by their index. Settings that SillyTavern keeps in
+    /// entry extensions are taken from there, others get SillyTavern
+    /// defaults. The lorebook itself is kept as `originalData`, as
+    /// SillyTavern does when importing lorebooks from cards.
+    pub fn to_world_info(&self) -> Value {
+        let mut entries = serde_json::Map::new();
+        for (index, entry) in self.entries.iter().enumerate() {
+            let ext = |name: &str, default: Value| {
+                entry.extensions.get(name).cloned().unwrap_or(default)
+            };
+            let position = match entry.position.as_deref() {
+                Some("before_char") => 0,
+                _ => 1,
+            };
+            let case_sensitive =
+                entry.case_sensitive.map_or(Value::Null, Value::from);
+            let secondary_keys =
+                entry.secondary_keys.clone().unwrap_or_default();
+            let has_memo =
+                entry.comment.as_ref().is_some_and(|x| !x.is_empty());
+            let world_entry = json!({
+                "uid": index,
+                "key": entry.keys,
+                "keysecondary": secondary_keys,
+                "comment": entry.comment.clone().unwrap_or_default(),
+                "content": entry.content,
+                "constant": entry.constant.unwrap_or(false),
+                "selective": entry.selective.unwrap_or(false),
+                "order": entry.insertion_order.unwrap_or(100),
+                "position": ext("position", json!(position)),
+                "disable": !entry.enabled,
+                "addMemo": has_memo,
+                "excludeRecursion": ext("exclude_recursion", json!(false)),
+                "preventRecursion": ext("prevent_recursion", json!(false)),
+                "displayIndex": ext("display_index", json!(index)),
+                "probability": ext("probability", json!(100)),
+                "useProbability": ext("useProbability", json!(true)),
+                "depth": ext("depth", json!(4)),
+                "selectiveLogic": ext("selectiveLogic", json!(0)),
+                "group": ext("group", json!("")),
+                "groupOverride": ext("group_override", json!(false)),
+                "groupWeight": ext("group_weight", json!(100)),
+                "scanDepth": ext("scan_depth", Value::Null),
+                "caseSensitive": ext("case_sensitive", case_sensitive),
+                "matchWholeWords": ext("match_whole_words", Value::Null),
+                "automationId": ext("automation_id", json!("")),
+                "role": ext("role", json!(0)),
+                "vectorized": ext("vectorized", json!(false)),
+                "sticky": ext("sticky", json!(0)),
+                "cooldown": ext("cooldown", json!(0)),
+                "delay": ext("delay", json!(0)),
+            });
+            entries.insert(index.to_string(), world_entry);
+        }
+        json!({ "entries": entries, "originalData": self })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_world_info() {
+        let mut book = CharacterBook::default();
+        book.add_entry(vec!["castle".to_string()], "A castle".to_string());
+        let index =
+            book.add_entry(vec!["king".to_string()], "A king".to_string());
+        let entry = &mut book.entries[index];
+        entry.enabled = false;
+        entry.position = Some("before_char".to_string());
+        entry.extensions.insert("depth".to_string(), json!(2));
+
+        let world_info = book.to_world_info();
+        let entry = &world_info["entries"]["1"];
+        assert_eq!(entry["uid"], 1);
+        assert_eq!(entry["key"], json!(["king"]));
+        assert_eq!(entry["content"], "A king");
+        assert_eq!(entry["disable"], true);
+        assert_eq!(entry["position"], 0);
+        assert_eq!(entry["depth"], 2);
+        assert_eq!(world_info["entries"]["0"]["position"], 1);
+        assert_eq!(
+            world_info["originalData"]["entries"][0]["content"],
+            "A castle"
+        );
+    }
+
+    #[test]
+    fn test_unknown_fields_are_kept() {
+        let book = json!({
+            "entries": [{ "keys": ["king"], "content": "A king", "uid": 7 }],
+            "origin": "test"
+        });
+        let parsed: CharacterBook = serde_json::from_value(book).unwrap();
+        assert_eq!(parsed.entries[0].extra["uid"], 7);
+        let written = serde_json::to_value(&parsed).unwrap();
+        assert_eq!(written["origin"], "test");
+        assert_eq!(written["entries"][0]["uid"], 7);
+    }
+
+    #[test]
+    fn test_dedupe_entries() {
+        let make_book = || {
+            let mut book = CharacterBook::default();
+            let keys = |x: &[&str]| x.iter().map(|x| x.to_string()).collect();
+            book.add_entry(keys(&["King", "crown"]), "A king".into());
+            book.add_entry(keys(&["crown ", "king"]), "A king\n".into());
+            let index =
+                book.add_entry(keys(&["KING", "Crown"]), "A king".into());
+            book.entries[index].case_sensitive = Some(true);
+            book.add_entry(keys(&["king"]), "A king".into());
+            book
+        };
+
+        let mut book = make_book();
+        assert_eq!(book.dedupe_entries(false), vec![(1, 0)]);
+        assert_eq!(book.entries.len(), 3);
+        let mut book = make_book();
+        assert_eq!(book.dedupe_entries(true), vec![(1, 0), (2, 0)]);
+        assert_eq!(book.entries.len(), 2);
+        assert_eq!(book.entries[1].keys, vec!["king"]);
+    }
+}
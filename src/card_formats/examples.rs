@@ -0,0 +1,138 @@
+//! Parsing of example dialogues from `mes_example`.
+
+/// Who speaks a message of example dialogue.
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Speaker {
+    Char,
+    User,
+    /// Text before the first speaker of the dialogue, like narration
+    System,
+}
+
+/// A message of example dialogue.
+#[derive(serde::Serialize, Debug, PartialEq)]
+pub struct ExampleTurn {
+    pub speaker: Speaker,
+    /// Speaker as written before the colon, like "{{char}}". Empty for
+    /// system text.
+    #[serde(skip)]
+    pub name: String,
+    pub text: String,
+}
+
+/// Marker that starts each example dialogue.
+pub const EXAMPLE_START: &str = "<START>";
+
+/// Speakers recognized at the start of a line.
+const SPEAKERS: &[(&str, Speaker)] = &[
+    ("{{char}}", Speaker::Char),
+    ("{{user}}", Speaker::User),
+    ("<BOT>", Speaker::Char),
+    ("<USER>", Speaker::User),
+];
+
+/// Splits `mes_example` into example dialogues, and each into turns.
+///
+/// Dialogues are separated by `<START>`, in any case. A turn begins at a
+/// line that starts with a speaker followed by a colon, like "{{user}}:",
+/// and continues until the next such line. Text before the first speaker is
+/// a system turn. Other macros, like "{{original}}", are left in the text.
+/// Empty dialogues are skipped.
+pub fn split_examples(mes_example: &str) -> Vec<Vec<ExampleTurn>> {
+    split_ignore_case(mes_example, EXAMPLE_START)
+        .into_iter()
+        .map(split_turns)
+        .filter(|turns| !turns.is_empty())
+        .collect()
+}
+
+fn split_ignore_case<'a>(text: &'a str, separator: &str) -> Vec<&'a str> {
+    let lower = text.to_ascii_lowercase();
+    let separator = separator.to_ascii_lowercase();
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for (index, _) in lower.match_indices(&separator) {
+        parts.push(&text[start..index]);
+        start = index + separator.len();
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+fn split_turns(dialogue: &str) -> Vec<ExampleTurn> {
+    let mut turns: Vec<ExampleTurn> = Vec::new();
+    for line in dialogue.lines() {
+        let trimmed = line.trim_start();
+        let speaker = SPEAKERS.iter().find(|(name, _)| {
+            trimmed
+                .get(..name.len())
+                .is_some_and(|x| x.eq_ignore_ascii_case(name))
+                && trimmed[name.len()..].trim_start().starts_with(':')
+        });
+        match (speaker, turns.last_mut()) {
+            (Some((name, speaker)), _) => {
+                let text = trimmed[name.len()..].trim_start()[1..].trim();
+                turns.push(ExampleTurn {
+                    speaker: *speaker,
+                    name: name.to_string(),
+                    text: text.to_string(),
+                });
+            }
+            (None, Some(turn)) => {
+                turn.text.push('\n');
+                turn.text.push_str(line);
+            }
+            (None, None) if line.trim().is_empty() => (),
+            (None, None) => turns.push(ExampleTurn {
+                speaker: Speaker::System,
+                name: String::new(),
+                text: line.to_string(),
+            }),
+        }
+    }
+    for turn in &mut turns {
+        turn.text = turn.text.trim_end().to_string();
+    }
+    turns
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_examples() {
+        let text = "<START>\n{{user}}: Hi!\n{{char}}: Hello.\nHow are you?\n\n\
+                    <start>\nNarration first\n{{char}} : Bye {{original}}\n\
+                    <START>\n";
+        let examples = split_examples(text);
+        assert_eq!(examples.len(), 2);
+        assert_eq!(
+            examples[0],
+            vec![
+                ExampleTurn {
+                    speaker: Speaker::User,
+                    name: "{{user}}".to_string(),
+                    text: "Hi!".to_string()
+                },
+                ExampleTurn {
+                    speaker: Speaker::Char,
+                    name: "{{char}}".to_string(),
+                    text: "Hello.\nHow are you?".to_string()
+                },
+            ]
+        );
+        assert_eq!(examples[1][0].speaker, Speaker::System);
+        assert_eq!(examples[1][1].text, "Bye {{original}}");
+
+        let json = serde_json::to_value(&examples[1]).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                { "speaker": "system", "text": "Narration first" },
+                { "speaker": "char", "text": "Bye {{original}}" }
+            ])
+        );
+    }
+}
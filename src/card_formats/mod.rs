@@ -1,2 +1,673 @@
+pub mod character_book;
+pub mod examples;
+pub mod sillytavern;
+pub mod strict;
 pub mod tavern_card_v2;
-pub mod tavern_card_v3;
\ No newline at end of file
+pub mod tavern_card_v3;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{bail, Context, Result};
+use base64::prelude::*;
+use bytes::Bytes;
+use log::warn;
+use serde_json::Value;
+
+use crate::error::{TavernError, TavernResult};
+pub use crate::tokens::{count_tokens, FieldTokenCounts, Tokenizer};
+use crate::tools::{self, FileOptions};
+use tavern_card_v2::{CharacterData, TavernCardV2, TEXT_KEY_PNG};
+use tavern_card_v3::{TavernCardV3, TEXT_KEY_CCV3};
+
+/// Key in `extensions` under which this tool keeps its own data.
+pub const TOOL_EXTENSION_KEY: &str = "taverntools";
+
+/// Extensions of cards, lorebooks and lorebook entries: whatever frontends
+/// keep there, like depth prompts or regex scripts. Nested data is kept as
+/// it is, in the order it was written.
+pub type Extensions = serde_json::Map<String, Value>;
+
+/// Reads extensions of lorebooks and entries, taking `null` as none.
+pub(crate) fn extensions_or_null<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Extensions, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+    Ok(Option::<Extensions>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Reading and writing of cards of one spec version: as JSON, as base64 of
+/// the PNG chunk, and embedded into PNG image. Versions differ only in the
+/// `data` block, and in how a card is made of the block alone.
+pub trait CardFile:
+    Sized + serde::Serialize + serde::de::DeserializeOwned
+{
+    /// Type of the `data` block.
+    type Data: serde::de::DeserializeOwned;
+
+    /// Makes a card of the `data` block, read from JSON that holds only the
+    /// block. Returns None if the block can not be taken as such.
+    fn from_data(data: Self::Data) -> Option<Self>;
+
+    /// PNG image the card is kept with, if any.
+    fn image_data(&self) -> Option<&Bytes>;
+
+    fn set_image_data(&mut self, image_data: Bytes);
+
+    /// Parses card from JSON text.
+    ///
+    /// Some tools write only the `data` block instead of the full card, such
+    /// JSON is accepted too. Fails if the text is not a JSON object, or if it
+    /// does not match the card structure.
+    fn from_json(json: &str) -> TavernResult<Self> {
+        if !json.trim_start().starts_with('{') {
+            return Err(TavernError::Parse(
+                "card JSON: it does not start with '{'".to_string(),
+            ));
+        }
+        // Try to convert JSON into tavern card data
+        let card_error = match serde_json::from_str::<Self>(json) {
+            Ok(card) => return Ok(card),
+            Err(e) => e,
+        };
+        // Sometimes the JSON contains only the data portion
+        let data = serde_json::from_str::<Self::Data>(json).ok();
+        data.and_then(Self::from_data).ok_or_else(|| {
+            TavernError::Parse(format!(
+                "card JSON: {}{}",
+                card_error,
+                json_error_snippet(json, &card_error)
+            ))
+        })
+    }
+
+    /// Serializes card into JSON text. Image data is not included.
+    fn to_json(&self) -> Result<String> {
+        let json_string = serde_json::to_string(self)?;
+        Ok(json_string)
+    }
+
+    /// Parses card from base64-encoded JSON, as stored in PNG chunk.
+    ///
+    /// Fails if the text is not valid base64 or the decoded bytes are not
+    /// UTF-8, in addition to the failures of [`Self::from_json`].
+    fn from_base64(text: &str) -> TavernResult<Self> {
+        let decoded = decode_payload(text)?;
+        let json = String::from_utf8(decoded).map_err(|_| {
+            TavernError::Parse("card data: it is not valid UTF-8".to_string())
+        })?;
+        Self::from_json(&json)
+    }
+
+    /// Serializes card into base64-encoded JSON, as stored in PNG chunk.
+    fn to_base64(&self) -> Result<String> {
+        Ok(BASE64_STANDARD.encode(self.to_json()?))
+    }
+
+    /// Embeds card into given PNG image.
+    ///
+    /// Returns a copy of the image with the card chunk added, replacing the
+    /// existing card chunk if any. Fails if the image is not a valid PNG.
+    fn write_to_png(
+        &self,
+        image_data: &Bytes,
+        options: &FileOptions,
+    ) -> Result<Bytes> {
+        let text = self.to_base64()?;
+        tools::write_text_to_png(TEXT_KEY_PNG, &text, image_data, options)
+    }
+
+    /// Writes card into image
+    ///
+    /// Makes a copy of PNG image, with card tag added to it. Uses the
+    /// base image if the card has no image, see [`tools::base_image`].
+    #[allow(clippy::wrong_self_convention)]
+    fn into_png_image(&self, options: &FileOptions) -> Result<Bytes> {
+        match self.image_data() {
+            Some(image_data) => self.write_to_png(image_data, options),
+            None => self.write_to_png(&tools::base_image(), options),
+        }
+    }
+
+    /// Reads card from PNG image, keeping the image along.
+    ///
+    /// Fails if the image is not a valid PNG or has no card chunk, in
+    /// addition to the failures of [`Self::from_base64`].
+    fn from_png_image(image_data: &Bytes) -> TavernResult<Self> {
+        let raw_text = tools::read_text_chunk(image_data, TEXT_KEY_PNG)?;
+        let Some(raw_text) = raw_text else {
+            return Err(TavernError::MissingChunk(TEXT_KEY_PNG.to_string()));
+        };
+        let mut card = Self::from_base64(&raw_text).map_err(|e| {
+            TavernError::Parse(format!(
+                "{} entry in PNG tEXt chunks: {}",
+                TEXT_KEY_PNG, e
+            ))
+        })?;
+        card.set_image_data(image_data.clone());
+        Ok(card)
+    }
+}
+
+/// Character card of any spec version, or its JSON, as seen by code that
+/// only reads the character text, like [`count_tokens`].
+pub trait TavernCard {
+    /// Texts of the data field with the given name, like "description": one
+    /// for text fields, one per item for lists like "alternate_greetings",
+    /// and none if the card does not have the field.
+    fn field_texts(&self, name: &str) -> Vec<&str>;
+}
+
+impl TavernCard for TavernCardV2 {
+    fn field_texts(&self, name: &str) -> Vec<&str> {
+        let d = &self.data;
+        let text = match name {
+            "name" => &d.name,
+            "description" => &d.description,
+            "personality" => &d.personality,
+            "scenario" => &d.scenario,
+            "first_mes" => &d.first_mes,
+            "mes_example" => &d.mes_example,
+            "creator_notes" => &d.creator_notes,
+            "system_prompt" => &d.system_prompt,
+            "post_history_instructions" => &d.post_history_instructions,
+            "creator" => &d.creator,
+            "character_version" => &d.character_version,
+            "alternate_greetings" => return list_texts(&d.alternate_greetings),
+            "tags" => return list_texts(&d.tags),
+            _ => &None,
+        };
+        text.as_deref().into_iter().collect()
+    }
+}
+
+impl TavernCard for TavernCardV3 {
+    fn field_texts(&self, name: &str) -> Vec<&str> {
+        let d = &self.data;
+        let text = match name {
+            "name" => &d.name,
+            "nickname" => &d.nickname,
+            "description" => &d.description,
+            "personality" => &d.personality,
+            "scenario" => &d.scenario,
+            "first_mes" => &d.first_mes,
+            "mes_example" => &d.mes_example,
+            "creator_notes" => &d.creator_notes,
+            "system_prompt" => &d.system_prompt,
+            "post_history_instructions" => &d.post_history_instructions,
+            "creator" => &d.creator,
+            "character_version" => &d.character_version,
+            "alternate_greetings" => return list_texts(&d.alternate_greetings),
+            "group_only_greetings" => {
+                return list_texts(&d.group_only_greetings)
+            }
+            "tags" => return list_texts(&d.tags),
+            "source" => return list_texts(&d.source),
+            _ => &None,
+        };
+        text.as_deref().into_iter().collect()
+    }
+}
+
+/// Card JSON, with or without the `data` block. Fields of other types than
+/// text are skipped, as are the items of lists that are not text.
+impl TavernCard for Value {
+    fn field_texts(&self, name: &str) -> Vec<&str> {
+        let data = self.get("data").unwrap_or(self);
+        match data.get(name) {
+            Some(Value::String(text)) => vec![text],
+            Some(Value::Array(items)) => {
+                items.iter().filter_map(Value::as_str).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn list_texts(list: &Option<Vec<String>>) -> Vec<&str> {
+    list.iter().flatten().map(String::as_str).collect()
+}
+
+/// Reads the decoded card payload embedded into PNG image.
+fn read_card_bytes(image_data: &Bytes) -> TavernResult<Vec<u8>> {
+    let raw_text = tools::read_text_chunk(image_data, TEXT_KEY_PNG)?
+        .ok_or_else(|| TavernError::MissingChunk(TEXT_KEY_PNG.to_string()))?;
+    decode_payload(&raw_text)
+}
+
+/// Reads the card JSON embedded into PNG image as text, to check how it is
+/// written rather than what it holds.
+pub fn read_card_text(image_data: &Bytes) -> TavernResult<String> {
+    let text = read_card_bytes(image_data)?;
+    String::from_utf8(text).map_err(|_| {
+        TavernError::Parse(format!(
+            "{} entry in PNG tEXt chunks: card data is not valid UTF-8",
+            TEXT_KEY_PNG
+        ))
+    })
+}
+
+/// Reads the card JSON embedded into PNG image, without interpreting it.
+pub fn read_card_json(image_data: &Bytes) -> TavernResult<Value> {
+    let text = read_card_bytes(image_data)?;
+    let json = serde_json::from_slice(&text).map_err(|e| {
+        let snippet = json_error_snippet(&String::from_utf8_lossy(&text), &e);
+        TavernError::Parse(format!(
+            "{} entry in PNG tEXt chunks: {}{}",
+            TEXT_KEY_PNG, e, snippet
+        ))
+    })?;
+    Ok(json)
+}
+
+/// Decodes base64 card payload of the PNG chunk.
+///
+/// Some tools encode the payload twice. When the decoded bytes are not JSON
+/// but valid base64, they are decoded once more, and a warning is logged.
+/// Hand-edited JSON is rewritten as strict JSON, see [`json5_fallback`].
+pub fn decode_payload(text: &str) -> TavernResult<Vec<u8>> {
+    let mut decoded = BASE64_STANDARD.decode(text).map_err(|e| {
+        TavernError::Parse(format!("base64 card payload: {}", e))
+    })?;
+    if let Some(twice_decoded) = decode_second_layer(&decoded) {
+        warn!(
+            "{} entry is base64-encoded twice, decoding it again",
+            TEXT_KEY_PNG
+        );
+        decoded = twice_decoded;
+    }
+    if let Some(strict) = json5_fallback(&decoded) {
+        return Ok(strict.into_bytes());
+    }
+    Ok(decoded)
+}
+
+/// Reads card JSON that the strict parser rejects, like hand-edited JSON
+/// with comments or trailing commas, as JSON5, and logs a warning.
+///
+/// Returns the card as strict JSON, so that it is always written back as
+/// such. Returns None if the text is strict JSON already, or not even JSON5.
+pub fn json5_fallback(text: &[u8]) -> Option<String> {
+    if serde_json::from_slice::<serde::de::IgnoredAny>(text).is_ok() {
+        return None;
+    }
+    let value: Value = json5::from_str(std::str::from_utf8(text).ok()?).ok()?;
+    warn!("Card uses non-standard JSON, like comments or trailing commas");
+    Some(value.to_string())
+}
+
+/// Returns true if the card payload is base64-encoded twice.
+pub fn is_double_encoded(text: &str) -> bool {
+    BASE64_STANDARD
+        .decode(text)
+        .is_ok_and(|decoded| decode_second_layer(&decoded).is_some())
+}
+
+fn decode_second_layer(decoded: &[u8]) -> Option<Vec<u8>> {
+    if serde_json::from_slice::<serde::de::IgnoredAny>(decoded).is_ok() {
+        return None;
+    }
+    let twice_decoded = BASE64_STANDARD.decode(decoded.trim_ascii()).ok()?;
+    serde_json::from_slice::<serde::de::IgnoredAny>(&twice_decoded)
+        .is_ok()
+        .then_some(twice_decoded)
+}
+
+/// Shows the part of JSON text where parsing failed.
+///
+/// Returns a line break followed by up to 40 characters around the error
+/// position, and a caret under the position. Card JSON is often written in
+/// a single line, so printing the whole line is of little use. Returns empty
+/// string if the error has no position.
+pub fn json_error_snippet(json: &str, error: &serde_json::Error) -> String {
+    const RADIUS: usize = 40;
+    let Some(line) = json.lines().nth(error.line().wrapping_sub(1)) else {
+        return String::new();
+    };
+    let floor = |mut i: usize| {
+        i = i.min(line.len());
+        while !line.is_char_boundary(i) {
+            i -= 1;
+        }
+        i
+    };
+    // Column is 1-based, 0 means the end of the previous line
+    let position = floor(error.column().saturating_sub(1));
+    let start = floor(position.saturating_sub(RADIUS));
+    let end = floor(position + RADIUS);
+    let prefix = if start > 0 { "..." } else { "" };
+    let suffix = if end < line.len() { "..." } else { "" };
+    let caret_offset = prefix.len() + line[start..position].chars().count();
+    format!(
+        "\n{}{}{}\n{}^",
+        prefix,
+        &line[start..end],
+        suffix,
+        " ".repeat(caret_offset)
+    )
+}
+
+/// Embeds card JSON into PNG image, replacing the card data already there.
+pub fn write_card_json(
+    json: &Value,
+    image_data: &Bytes,
+    options: &FileOptions,
+) -> Result<Bytes> {
+    let json_string = serde_json::to_string(json)?;
+    let base64_json_string = BASE64_STANDARD.encode(json_string);
+    tools::write_text_to_png(
+        TEXT_KEY_PNG,
+        &base64_json_string,
+        image_data,
+        options,
+    )
+}
+
+/// Embeds card JSON into PNG image as the V3 `ccv3` chunk, replacing the one
+/// already there. The chunk holds V3 JSON, so V2 cards are labeled V3 in it,
+/// the way SillyTavern writes them.
+pub fn write_ccv3_json(
+    json: &Value,
+    image_data: &Bytes,
+    options: &FileOptions,
+) -> Result<Bytes> {
+    let mut json = json.clone();
+    json["spec"] = Value::from("chara_card_v3");
+    json["spec_version"] = Value::from("3.0");
+    let base64_json_string = BASE64_STANDARD.encode(json.to_string());
+    tools::write_text_to_png(
+        TEXT_KEY_CCV3,
+        &base64_json_string,
+        image_data,
+        options,
+    )
+}
+
+/// Version tried first when a card can be read as either V2 or V3.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum PreferredVersion {
+    V2,
+    #[default]
+    V3,
+}
+
+static PREFER_V2: AtomicBool = AtomicBool::new(false);
+
+/// Sets the version tried first when a card can be read as both.
+pub fn set_preferred_version(version: PreferredVersion) {
+    PREFER_V2.store(version == PreferredVersion::V2, Ordering::Relaxed);
+}
+
+pub fn preferred_version() -> PreferredVersion {
+    if PREFER_V2.load(Ordering::Relaxed) {
+        PreferredVersion::V2
+    } else {
+        PreferredVersion::V3
+    }
+}
+
+/// Start of the error about cards of a newer spec than this tool reads, by
+/// which such cards go to their own issue folder.
+pub const UNSUPPORTED_SPEC_ERROR: &str = "Unsupported spec version";
+
+/// Newest major version of the card spec this tool reads.
+const NEWEST_SPEC_MAJOR: u32 = 3;
+
+/// Fails if the card declares a spec newer than this tool reads, in `spec`
+/// like "chara_card_v4", or in `spec_version` like "4.0". Such cards would
+/// pass for V2 or V3 otherwise, and lose the fields of the new spec.
+///
+/// Cards that declare no version, or a version that is not a number, pass.
+pub fn check_spec_version(json: &Value) -> TavernResult<()> {
+    let declared = |field: &str| json.get(field).and_then(Value::as_str);
+    let spec_major = declared("spec")
+        .and_then(|x| x.strip_prefix("chara_card_v"))
+        .and_then(|x| x.parse::<u32>().ok());
+    let version_major = declared("spec_version")
+        .and_then(|x| x.split('.').next())
+        .and_then(|x| x.trim().parse::<u32>().ok());
+    if spec_major.max(version_major).is_some_and(|x| x > NEWEST_SPEC_MAJOR) {
+        return Err(TavernError::UnsupportedFormat(format!(
+            "{}: the card declares spec {} version {}, this tool reads cards \
+             up to V{}",
+            UNSUPPORTED_SPEC_ERROR,
+            declared("spec").unwrap_or("none"),
+            declared("spec_version").unwrap_or("none"),
+            NEWEST_SPEC_MAJOR
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that JSON can still be read as either V3 or V2 tavern card, trying
+/// the preferred version first.
+pub fn validate_card_json(json: &Value) -> Result<()> {
+    check_spec_version(json)?;
+    let is_v2 = || serde_json::from_value::<TavernCardV2>(json.clone()).is_ok();
+    if preferred_version() == PreferredVersion::V2 && is_v2() {
+        return Ok(());
+    }
+    let v3_error = match serde_json::from_value::<TavernCardV3>(json.clone()) {
+        Ok(_) => return Ok(()),
+        Err(e) => e,
+    };
+    if is_v2() {
+        return Ok(());
+    }
+    bail!("Edited JSON is not a valid tavern card: {}", v3_error);
+}
+
+/// Card specification version, for reading cards with a wrong `spec`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum SpecVersion {
+    /// Character fields at the top level, without `data`
+    V1,
+    V2,
+    V3,
+}
+
+/// Checks that JSON can be read as a card of the given version, whatever
+/// version the card declares.
+pub fn validate_card_json_as(json: &Value, version: SpecVersion) -> Result<()> {
+    let result = match version {
+        SpecVersion::V1 => {
+            serde_json::from_value::<CharacterData>(json.clone()).map(|_| ())
+        }
+        SpecVersion::V2 => {
+            serde_json::from_value::<TavernCardV2>(json.clone()).map(|_| ())
+        }
+        SpecVersion::V3 => {
+            serde_json::from_value::<TavernCardV3>(json.clone()).map(|_| ())
+        }
+    };
+    result.with_context(|| format!("Card can not be read as {:?}", version))
+}
+
+/// Returns the URL the card was downloaded from, if it is known.
+///
+/// Looks at the URL recorded by this tool, then at V3 `source` list.
+pub fn card_source_url(json: &Value) -> Option<String> {
+    let pointer = format!("/data/extensions/{}/source", TOOL_EXTENSION_KEY);
+    json.pointer(&pointer)
+        .or_else(|| json.pointer("/data/source/0"))
+        .and_then(|x| x.as_str())
+        .map(|x| x.to_string())
+}
+
+/// Returns `data` block of the card JSON for editing.
+pub fn card_data_mut(
+    card_json: &mut Value,
+) -> Result<&mut serde_json::Map<String, Value>> {
+    card_json
+        .get_mut("data")
+        .and_then(|x| x.as_object_mut())
+        .context("Card has no data block")
+}
+
+/// Sets a value in the `extensions` object this tool keeps in the card.
+pub fn set_tool_extension(
+    json: &mut Value,
+    key: &str,
+    value: Value,
+) -> Result<()> {
+    let data = card_data_mut(json)?;
+    let extensions = data.entry("extensions").or_insert(Value::Null);
+    if extensions.is_null() {
+        *extensions = serde_json::json!({});
+    }
+    let extensions = extensions
+        .as_object_mut()
+        .context("Card extensions is not an object")?;
+    let tool_extension = extensions
+        .entry(TOOL_EXTENSION_KEY)
+        .or_insert_with(|| serde_json::json!({}))
+        .as_object_mut()
+        .context("Card extensions of this tool is not an object")?;
+    tool_extension.insert(key.to_string(), value);
+    Ok(())
+}
+
+/// Flattens the JSON into a single-level object, for spreadsheets and
+/// data analysis. Keys are dotted paths, like
+/// `data.character_book.entries.0.content`, with array items by index.
+/// Empty arrays and objects become `null`, so their keys are not lost.
+pub fn flatten_json(value: &Value) -> Value {
+    let mut flat = serde_json::Map::new();
+    flatten_into(value, String::new(), &mut flat);
+    Value::Object(flat)
+}
+
+fn flatten_into(
+    value: &Value,
+    path: String,
+    flat: &mut serde_json::Map<String, Value>,
+) {
+    let join = |key: &dyn std::fmt::Display| {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    };
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, item) in map {
+                flatten_into(item, join(key), flat);
+            }
+        }
+        Value::Array(items) if !items.is_empty() => {
+            for (index, item) in items.iter().enumerate() {
+                flatten_into(item, join(&index), flat);
+            }
+        }
+        Value::Object(_) | Value::Array(_) => {
+            flat.insert(path, Value::Null);
+        }
+        _ => {
+            flat.insert(path, value.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_tool_extension() -> Result<()> {
+        let mut card = json!({ "data": { "extensions": null } });
+        set_tool_extension(&mut card, "source", json!("https://example.com"))?;
+        set_tool_extension(&mut card, "image_sha256", json!("abc"))?;
+        assert_eq!(card_source_url(&card).unwrap(), "https://example.com");
+        assert_eq!(
+            card["data"]["extensions"]["taverntools"]["image_sha256"],
+            "abc"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_flatten_json() {
+        let card = json!({
+            "spec": "chara_card_v3",
+            "data": {
+                "tags": ["a", "b"],
+                "alternate_greetings": [],
+                "character_book": { "entries": [{ "content": "Lore" }] }
+            }
+        });
+        let flat = flatten_json(&card);
+        let expected = json!({
+            "spec": "chara_card_v3",
+            "data.tags.0": "a",
+            "data.tags.1": "b",
+            "data.alternate_greetings": null,
+            "data.character_book.entries.0.content": "Lore"
+        });
+        assert_eq!(flat, expected);
+        let keys: Vec<&String> = flat.as_object().unwrap().keys().collect();
+        assert_eq!(keys[1], "data.tags.0");
+    }
+
+    #[test]
+    fn test_check_spec_version() {
+        let card = |spec: &str, version: &str| {
+            json!({
+                "spec": spec,
+                "spec_version": version,
+                "data": {}
+            })
+        };
+        assert!(check_spec_version(&card("chara_card_v2", "2.0")).is_ok());
+        assert!(check_spec_version(&card("chara_card_v3", "3.1")).is_ok());
+        assert!(check_spec_version(&json!({ "name": "V1" })).is_ok());
+        assert!(check_spec_version(&card("custom", "beta")).is_ok());
+        let error = check_spec_version(&card("chara_card_v4", "4.0"));
+        let error = error.unwrap_err().to_string();
+        assert!(error.starts_with(UNSUPPORTED_SPEC_ERROR));
+        assert!(check_spec_version(&card("chara_card_v3", "4.0")).is_err());
+        assert!(validate_card_json(&card("chara_card_v4", "")).is_err());
+    }
+
+    #[test]
+    fn test_json_error_snippet() {
+        let json = r#"{"name": "Test" "tags": []}"#;
+        let error = serde_json::from_str::<Value>(json).unwrap_err();
+        let snippet = json_error_snippet(json, &error);
+        let expected = format!("\n{}\n{}^", json, " ".repeat(16));
+        assert_eq!(snippet, expected);
+
+        let json = format!(r#"{{"description": "{}" oops}}"#, "x".repeat(100));
+        let error = serde_json::from_str::<Value>(&json).unwrap_err();
+        let snippet = json_error_snippet(&json, &error);
+        assert!(snippet.starts_with("\n...xxx"));
+        assert!(snippet.contains("\" oops}\n"));
+    }
+
+    #[test]
+    fn test_decode_double_encoded_payload() -> Result<()> {
+        let json = r#"{"name": "Test"}"#;
+        let once = BASE64_STANDARD.encode(json);
+        let twice = BASE64_STANDARD.encode(&once);
+        assert!(!is_double_encoded(&once));
+        assert!(is_double_encoded(&twice));
+        assert_eq!(decode_payload(&once)?, json.as_bytes());
+        assert_eq!(decode_payload(&twice)?, json.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn test_json5_fallback() -> Result<()> {
+        let json = "{\n  // Edited by hand\n  \"name\": \"Test\",\n  \"tags\": [\"a\",],\n}";
+        let payload = BASE64_STANDARD.encode(json);
+        let strict = decode_payload(&payload)?;
+        assert_eq!(strict, br#"{"name":"Test","tags":["a"]}"#);
+        assert_eq!(json5_fallback(&strict), None);
+        assert_eq!(json5_fallback(b"{broken"), None);
+        Ok(())
+    }
+}
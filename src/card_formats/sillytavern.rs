@@ -0,0 +1,116 @@
+//! Character exports of SillyTavern.
+//!
+//! SillyTavern exports V2 cards with the V1 fields duplicated at the top
+//! level, next to `data`, and adds fields of its own, like `fav` and `chat`.
+
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+
+use super::tavern_card_v2::{CharacterData, TavernCardV2};
+
+/// Key in `extensions` under which SillyTavern-only fields are kept.
+pub const ST_EXTENSION_KEY: &str = "sillytavern";
+
+/// Top-level fields that only SillyTavern writes.
+const ST_FIELDS: &[&str] = &[
+    "avatar",
+    "chat",
+    "create_date",
+    "creatorcomment",
+    "fav",
+    "talkativeness",
+];
+
+/// Top-level duplicates of `data` fields, as SillyTavern writes them.
+const DUPLICATED_FIELDS: &[&str] = &[
+    "name",
+    "description",
+    "personality",
+    "scenario",
+    "first_mes",
+    "mes_example",
+    "tags",
+];
+
+/// Returns true if the JSON looks like a SillyTavern export of V2 card:
+/// it has `data`, top-level duplicates of its fields, and fields that only
+/// SillyTavern writes.
+pub fn is_sillytavern_export(json: &Value) -> bool {
+    let Some(card) = json.as_object() else {
+        return false;
+    };
+    card.get("data").is_some_and(|x| x.is_object())
+        && card.get("spec").and_then(|x| x.as_str()) != Some("chara_card_v3")
+        && DUPLICATED_FIELDS.iter().any(|x| card.contains_key(*x))
+        && ST_FIELDS.iter().any(|x| card.contains_key(*x))
+}
+
+/// Reads SillyTavern export of V2 card.
+///
+/// Fields missing from `data` are taken from their top-level duplicates.
+/// SillyTavern-only fields are kept in `extensions.sillytavern`.
+pub fn import_sillytavern_char(json: &Value) -> Result<TavernCardV2> {
+    let card = json.as_object().context("Card JSON is not an object")?;
+    let mut data = card
+        .get("data")
+        .and_then(|x| x.as_object())
+        .cloned()
+        .context("Card has no data block")?;
+    for field in DUPLICATED_FIELDS {
+        let missing = data.get(*field).is_none_or(|x| x.is_null());
+        if let (true, Some(value)) = (missing, card.get(*field)) {
+            data.insert(field.to_string(), value.clone());
+        }
+    }
+
+    let st_fields: Map<String, Value> = ST_FIELDS
+        .iter()
+        .filter_map(|x| Some((x.to_string(), card.get(*x)?.clone())))
+        .collect();
+    if !st_fields.is_empty() {
+        let extensions = data
+            .entry("extensions")
+            .or_insert_with(|| Value::Object(Map::new()));
+        if !extensions.is_object() {
+            *extensions = Value::Object(Map::new());
+        }
+        extensions[ST_EXTENSION_KEY] = Value::Object(st_fields);
+    }
+
+    let data: CharacterData = serde_json::from_value(Value::Object(data))
+        .context("Failed to parse SillyTavern character export")?;
+    Ok(TavernCardV2 {
+        spec: Some("chara_card_v2".to_string()),
+        spec_version: Some("2.0".to_string()),
+        data,
+        image_data: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_import_sillytavern_char() -> Result<()> {
+        let export = json!({
+            "name": "Test",
+            "first_mes": "Hello",
+            "fav": true,
+            "chat": "Test - 2024-01-01",
+            "spec": "chara_card_v2",
+            "data": { "name": "Test", "first_mes": null, "extensions": {} }
+        });
+        assert!(is_sillytavern_export(&export));
+        assert!(!is_sillytavern_export(&json!({ "data": { "name": "A" } })));
+
+        let card = import_sillytavern_char(&export)?;
+        assert_eq!(card.data.first_mes, Some("Hello".to_string()));
+        let extensions = card.data.extensions.unwrap();
+        assert_eq!(extensions[ST_EXTENSION_KEY]["fav"], true);
+        assert_eq!(extensions[ST_EXTENSION_KEY]["chat"], "Test - 2024-01-01");
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+//! Strict reading of card JSON.
+//!
+//! `serde_json` keeps the last value when an object has the same key twice,
+//! so a card with two conflicting definitions of a field reads fine. The
+//! check here finds such keys.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use anyhow::Result;
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, SeqAccess};
+
+use super::json_error_snippet;
+
+/// Walks JSON value, failing on the first object with a duplicate key.
+/// `path` is the location of the value, like "data.tags[2]".
+struct DuplicateKeyCheck<'a> {
+    path: &'a str,
+}
+
+impl<'de> DeserializeSeed<'de> for DuplicateKeyCheck<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, d: D) -> Result<(), D::Error> {
+        d.deserialize_any(self)
+    }
+}
+
+impl<'de> de::Visitor<'de> for DuplicateKeyCheck<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "any JSON value")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let mut keys = HashSet::new();
+        while let Some(key) = map.next_key::<String>()? {
+            let path = if self.path.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", self.path, key)
+            };
+            if !keys.insert(key) {
+                let message = format!("duplicate key \"{}\"", path);
+                return Err(de::Error::custom(message));
+            }
+            map.next_value_seed(DuplicateKeyCheck { path: &path })?;
+        }
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let mut index = 0;
+        loop {
+            let path = format!("{}[{}]", self.path, index);
+            if seq
+                .next_element_seed(DuplicateKeyCheck { path: &path })?
+                .is_none()
+            {
+                return Ok(());
+            }
+            index += 1;
+        }
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_i64<E>(self, _: i64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_u64<E>(self, _: u64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_unit<E>(self) -> Result<(), E> {
+        Ok(())
+    }
+}
+
+/// Fails if any object in the JSON has the same key twice, telling the key
+/// and where it is.
+pub fn check_duplicate_keys(json: &str) -> Result<()> {
+    let mut deserializer = serde_json::Deserializer::from_str(json);
+    DuplicateKeyCheck { path: "" }
+        .deserialize(&mut deserializer)
+        .and_then(|_| deserializer.end())
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "Strict JSON: {}{}",
+                e,
+                json_error_snippet(json, &e)
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_duplicate_keys() {
+        assert!(check_duplicate_keys(r#"{"data": {"name": "A"}}"#).is_ok());
+        let json = r#"{"data": {"tags": [{"a": 1, "a": 2}]}}"#;
+        let error = check_duplicate_keys(json).unwrap_err().to_string();
+        assert!(
+            error.contains("duplicate key \"data.tags[0].a\""),
+            "{}",
+            error
+        );
+        assert!(error.contains("line 1"), "{}", error);
+        // Same key in different objects is fine
+        assert!(check_duplicate_keys(r#"[{"a": 1}, {"a": 2}]"#).is_ok());
+    }
+}
@@ -1,43 +1,16 @@
 use std::fmt::Display;
 
-use anyhow::{bail, Result};
-use base64::prelude::*;
 use bytes::Bytes;
-use textwrap::{fill, Options};
+use textwrap::fill;
 
-use crate::tools;
+pub use super::character_book::{CharacterBook, CharacterBookEntry};
+use super::examples::{split_examples, ExampleTurn};
+use super::{CardFile, Extensions};
+use crate::color::Color;
+use crate::wrap::{self, PrintOptions};
 
 pub const TEXT_KEY_PNG: &str = "Chara";
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Default, PartialEq)]
-pub struct CharacterBook {
-    pub name: Option<String>,
-    pub description: Option<String>,
-    pub scan_depth: Option<u32>,
-    pub token_budget: Option<u32>,
-    pub recursive_scanning: Option<bool>,
-    pub extensions: std::collections::HashMap<String, serde_json::Value>,
-    pub entries: Vec<CharacterBookEntry>,
-}
-
-#[derive(serde::Serialize, serde::Deserialize, Debug, Default, PartialEq)]
-pub struct CharacterBookEntry {
-    pub keys: Vec<String>,
-    pub content: String,
-    pub extensions: std::collections::HashMap<String, serde_json::Value>,
-    pub enabled: bool,
-    pub insertion_order: Option<u32>,
-    pub case_sensitive: Option<bool>,
-    pub name: Option<String>,
-    pub priority: Option<u32>,
-    pub id: Option<u32>,
-    pub comment: Option<String>,
-    pub selective: Option<bool>,
-    pub secondary_keys: Option<Vec<String>>,
-    pub constant: Option<bool>,
-    pub position: Option<String>,
-}
-
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default, PartialEq)]
 pub struct TavernCardV2 {
     pub spec: Option<String>,
@@ -63,8 +36,23 @@ pub struct CharacterData {
     pub tags: Option<Vec<String>>,
     pub creator: Option<String>,
     pub character_version: Option<String>,
-    pub extensions:
-        Option<std::collections::HashMap<String, serde_json::Value>>,
+    pub extensions: Option<Extensions>,
+}
+
+impl CardFile for TavernCardV2 {
+    type Data = CharacterData;
+
+    fn from_data(data: CharacterData) -> Option<Self> {
+        Some(TavernCardV2 { data, ..Default::default() })
+    }
+
+    fn image_data(&self) -> Option<&Bytes> {
+        self.image_data.as_ref()
+    }
+
+    fn set_image_data(&mut self, image_data: Bytes) {
+        self.image_data = Some(image_data);
+    }
 }
 
 impl TavernCardV2 {
@@ -74,64 +62,78 @@ impl TavernCardV2 {
         s
     }
 
-    /// Writes card into image
-    ///
-    /// Makes a copy of PNG image, with card tag added to it.
-    pub fn into_png_image(&self) -> Result<Bytes> {
-        let json_string = serde_json::to_string(self)?;
-        let base64_json_string = BASE64_STANDARD.encode(json_string);
-        let temp_image_holder;
-        let image_data;
-        match &self.image_data {
-            Some(img) => image_data = img,
-            None => {
-                temp_image_holder = tools::get_default_image();
-                image_data = &temp_image_holder;
+    /// Calls `f` for every text field that is sent to the model, with the
+    /// name of the field, like [`Self::for_each_text_field_mut`] does.
+    pub fn for_each_text_field<F>(&self, mut f: F)
+    where
+        F: FnMut(&str, &str),
+    {
+        let d = &self.data;
+        let fields = [
+            ("description", &d.description),
+            ("personality", &d.personality),
+            ("scenario", &d.scenario),
+            ("first_mes", &d.first_mes),
+            ("mes_example", &d.mes_example),
+            ("system_prompt", &d.system_prompt),
+            ("post_history_instructions", &d.post_history_instructions),
+        ];
+        for (name, field) in fields {
+            if let Some(text) = field {
+                f(name, text);
+            }
+        }
+        for (i, g) in d.alternate_greetings.iter().flatten().enumerate() {
+            f(&format!("alternate_greetings[{}]", i), g);
+        }
+        if let Some(cb) = &d.character_book {
+            for (i, e) in cb.entries.iter().enumerate() {
+                f(&format!("character_book.entries[{}]", i), &e.content);
             }
         }
-        let edited_card = tools::write_text_to_png(
-            TEXT_KEY_PNG,
-            &base64_json_string,
-            image_data,
-        )?;
-        Ok(edited_card)
     }
 
-    pub fn from_png_image(image_data: &Bytes) -> Result<Self> {
-        let raw_text = tools::read_text_chunk(image_data, TEXT_KEY_PNG)?;
-        if raw_text.is_none() {
-            bail!("No {} entry in PNG tEXt chunks", TEXT_KEY_PNG);
-        };
-        let text = BASE64_STANDARD.decode(raw_text.unwrap())?;
-        if !text.starts_with(&[b'{']) {
-            bail!(
-                "{} entry in PNG tEXt chunks does not start with '{{'",
-                TEXT_KEY_PNG
-            );
-        } // Added missing brace
-        // Try to convert tag into tavern card data
-        let mut card = serde_json::from_slice::<TavernCardV2>(&text);
-        if card.is_err() {
-            // Sometimes the tag contains only the data portion
-            match serde_json::from_slice::<CharacterData>(&text) {
-                Ok(card_data) => {
-                    card = Ok(TavernCardV2 {
-                        data: card_data,
-                        ..Default::default()
-                    });
-                }
-                Err(e) => {
-                    bail!(
-                        "Failed to parse {} entry in PNG tEXt chunks: {}",
-                        TEXT_KEY_PNG,
-                        e
-                    );
-                }
+    /// Calls `f` for every text field that is sent to the model, with the
+    /// name of the field.
+    ///
+    /// Lorebook entries and alternate greetings are named with their index,
+    /// like "alternate_greetings[0]".
+    pub fn for_each_text_field_mut<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&str, &mut String),
+    {
+        let d = &mut self.data;
+        let fields = [
+            ("description", &mut d.description),
+            ("personality", &mut d.personality),
+            ("scenario", &mut d.scenario),
+            ("first_mes", &mut d.first_mes),
+            ("mes_example", &mut d.mes_example),
+            ("system_prompt", &mut d.system_prompt),
+            ("post_history_instructions", &mut d.post_history_instructions),
+        ];
+        for (name, field) in fields {
+            if let Some(text) = field {
+                f(name, text);
+            }
+        }
+        if let Some(ag) = &mut d.alternate_greetings {
+            for (i, g) in ag.iter_mut().enumerate() {
+                f(&format!("alternate_greetings[{}]", i), g);
+            }
+        }
+        if let Some(cb) = &mut d.character_book {
+            for (i, e) in cb.entries.iter_mut().enumerate() {
+                f(&format!("character_book.entries[{}]", i), &mut e.content);
             }
         }
-        let mut card = card.unwrap();
-        card.image_data = Some(image_data.clone());
-        Ok(card)
+    }
+
+    /// Parses example dialogues of the card, see
+    /// [`split_examples`](super::examples::split_examples).
+    pub fn split_examples(&self) -> Vec<Vec<ExampleTurn>> {
+        let mes_example = self.data.mes_example.as_deref().unwrap_or_default();
+        split_examples(mes_example)
     }
 
     /// Make changes to better conform the specification
@@ -151,8 +153,10 @@ impl TavernCardV2 {
     }
 }
 
-impl Display for TavernCardV2 {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl TavernCardV2 {
+    /// Lists the card fields as printed text, wrapped to the wrap width,
+    /// see [`wrap::options`].
+    pub fn to_text(&self, print: &PrintOptions) -> String {
         // Turns &Option<String> into &str
         fn no_opt(o: &Option<String>) -> &str {
             const NONE_STR: &str = "NONE";
@@ -204,17 +208,22 @@ impl Display for TavernCardV2 {
 
         // Now to convert the lines vector into a pretty string
         let mut output = String::new();
-        let tw = *[textwrap::termwidth(), 80usize].iter().min().unwrap();
         let options =
-            Options::new(tw).initial_indent("").subsequent_indent("    ");
+            wrap::options(80).initial_indent("").subsequent_indent("    ");
         for (key, value) in lines {
+            let key = print.paint(key, Color::Bold);
+            let value = print.truncate_field(value);
             let mut line = format!("{}: {}\n", key, value);
             line = fill(&line, &options);
             output += &line;
         }
-        write!(f, "{}", output)?;
+        output
+    }
+}
 
-        Ok(())
+impl Display for TavernCardV2 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_text(&PrintOptions::default()))
     }
 }
 
@@ -225,7 +234,7 @@ mod tests {
     use anyhow::Result;
 
     #[allow(unused_imports)]
-    use tools;
+    use crate::tools;
 
     fn create_test_card() -> TavernCardV2 {
         let mut card = TavernCardV2::new();
@@ -236,16 +245,21 @@ mod tests {
         card.data.first_mes = Some(String::from("Test first message"));
         card.data.mes_example = Some(String::from("Test dialog example"));
         card.data.character_book = Some(CharacterBook::default());
-        let mut entry1 = CharacterBookEntry::default();
-        entry1.content = String::from("Test book entry 1");
+        let entry1 = CharacterBookEntry {
+            content: String::from("Test book entry 1"),
+            ..Default::default()
+        };
 
-        let mut entry2 = CharacterBookEntry::default();
-        entry2.content = String::from("Test book entry 2");
+        let entry2 = CharacterBookEntry {
+            content: String::from("Test book entry 2"),
+            ..Default::default()
+        };
 
         card.data.character_book.as_mut().unwrap().entries.push(entry1);
         card.data.character_book.as_mut().unwrap().entries.push(entry2);
         card.image_data = Some(tools::get_default_image());
-        let image_with_tag = card.into_png_image().unwrap();
+        let image_with_tag =
+            card.into_png_image(&tools::FileOptions::default()).unwrap();
         card.image_data = Some(image_with_tag);
         card
     }
@@ -260,10 +274,29 @@ mod tests {
     #[test]
     fn test_write_and_read() -> Result<()> {
         let card = create_test_card();
-        let image = card.into_png_image()?;
+        let image = card.into_png_image(&tools::FileOptions::default())?;
         let card2 = TavernCardV2::from_png_image(&image)?;
         assert_eq!(card, card2);
         // tools::write_image_to_file(&image, &std::path::Path::new("testing/test_card.png"))?;
         Ok(())
     }
+
+    #[test]
+    fn test_json_round_trip() -> Result<()> {
+        let mut card = create_test_card();
+        card.image_data = None;
+        let card2 = TavernCardV2::from_json(&card.to_json()?)?;
+        assert_eq!(card, card2);
+        let card3 = TavernCardV2::from_base64(&card.to_base64()?)?;
+        assert_eq!(card, card3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_json_data_only() -> Result<()> {
+        let card = TavernCardV2::from_json(r#"{"name": "Test name"}"#)?;
+        assert_eq!(card.data.name, Some(String::from("Test name")));
+        assert!(TavernCardV2::from_json("[]").is_err());
+        Ok(())
+    }
 }
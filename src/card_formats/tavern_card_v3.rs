@@ -1,42 +1,19 @@
+use std::collections::BTreeMap;
 use std::fmt::Display;
 
 use anyhow::{bail, Result};
-use base64::prelude::*;
 use bytes::Bytes;
-use textwrap::{fill, Options};
+use textwrap::fill;
 
-use crate::tools;
+pub use super::character_book::CharacterBook;
+use super::examples::{split_examples, ExampleTurn};
+use super::{CardFile, Extensions};
+use crate::color::Color;
+use crate::wrap::{self, PrintOptions};
 
 pub const TEXT_KEY_PNG: &str = "Chara";
-
-#[derive(serde::Serialize, serde::Deserialize, Debug, Default, PartialEq)]
-pub struct CharacterBook {
-    pub name: Option<String>,
-    pub description: Option<String>,
-    pub scan_depth: Option<u32>,
-    pub token_budget: Option<u32>,
-    pub recursive_scanning: Option<bool>,
-    pub extensions: std::collections::HashMap<String, serde_json::Value>,
-    pub entries: Vec<CharacterBookEntry>,
-}
-
-#[derive(serde::Serialize, serde::Deserialize, Debug, Default, PartialEq)]
-pub struct CharacterBookEntry {
-    pub keys: Vec<String>,
-    pub content: String,
-    pub extensions: std::collections::HashMap<String, serde_json::Value>,
-    pub enabled: bool,
-    pub insertion_order: Option<u32>,
-    pub case_sensitive: Option<bool>,
-    pub name: Option<String>,
-    pub priority: Option<u32>,
-    pub id: Option<u32>,
-    pub comment: Option<String>,
-    pub selective: Option<bool>,
-    pub secondary_keys: Option<Vec<String>>,
-    pub constant: Option<bool>,
-    pub position: Option<String>,
-}
+/// V3 cards may also keep the card in this chunk, next to `Chara`.
+pub const TEXT_KEY_CCV3: &str = "ccv3";
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default, PartialEq)]
 pub struct TavernCardV3 {
@@ -69,6 +46,9 @@ pub struct CharacterDataV3 {
     pub first_mes: Option<String>,
     pub mes_example: Option<String>,
     pub creator_notes: Option<String>,
+    /// Creator notes translated to other languages, keyed by ISO 639-1 code.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creator_notes_multilingual: Option<BTreeMap<String, String>>,
     pub system_prompt: Option<String>,
     pub post_history_instructions: Option<String>,
     pub alternate_greetings: Option<Vec<String>>,
@@ -76,9 +56,60 @@ pub struct CharacterDataV3 {
     pub tags: Option<Vec<String>>,
     pub creator: Option<String>,
     pub character_version: Option<String>,
-    pub extensions:
-        Option<std::collections::HashMap<String, serde_json::Value>>,
+    pub extensions: Option<Extensions>,
     pub group_only_greetings: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub assets: Option<Vec<Asset>>,
+    /// Name used in place of `name` in chats, when set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nickname: Option<String>,
+    /// Where the card came from, like URLs or ids of card sites.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<Vec<String>>,
+    /// Unix timestamp, in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creation_date: Option<i64>,
+    /// Unix timestamp, in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modification_date: Option<i64>,
+    /// Fields not in the spec, kept as they are.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Asset of V3 card, like an icon or a background image.
+///
+/// `uri` tells where the asset is: a web URL, `ccdefault:` for the card
+/// image itself, or `__asset:<id>` for data embedded in the card file.
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Default, Clone, PartialEq,
+)]
+#[serde(default)]
+pub struct Asset {
+    #[serde(rename = "type")]
+    pub asset_type: String,
+    pub uri: String,
+    pub name: String,
+    pub ext: String,
+}
+
+impl CardFile for TavernCardV3 {
+    type Data = CharacterDataV3;
+
+    /// A full card that failed to parse would pass for data with unknown
+    /// fields, one of them `data`, so such data is not taken.
+    fn from_data(data: CharacterDataV3) -> Option<Self> {
+        let is_card = data.extra.contains_key("data");
+        (!is_card).then(|| TavernCardV3 { data, ..Default::default() })
+    }
+
+    fn image_data(&self) -> Option<&Bytes> {
+        self.image_data.as_ref()
+    }
+
+    fn set_image_data(&mut self, image_data: Bytes) {
+        self.image_data = Some(image_data);
+    }
 }
 
 impl TavernCardV3 {
@@ -88,64 +119,82 @@ impl TavernCardV3 {
         s
     }
 
-    /// Writes card into image
+    /// Calls `f` for every text field that is sent to the model, with the
+    /// name of the field.
     ///
-    /// Makes a copy of PNG image, with card tag added to it.
-    pub fn into_png_image(&self) -> Result<Bytes> {
-        let json_string = serde_json::to_string(self)?;
-        let base64_json_string = BASE64_STANDARD.encode(json_string);
-        let temp_image_holder;
-        let image_data;
-        match &self.image_data {
-            Some(img) => image_data = img,
-            None => {
-                temp_image_holder = tools::get_default_image();
-                image_data = &temp_image_holder;
+    /// Lorebook entries and greetings are named with their index, like
+    /// "group_only_greetings[0]".
+    pub fn for_each_text_field<F>(&self, mut f: F)
+    where
+        F: FnMut(&str, &str),
+    {
+        let d = &self.data;
+        let fields = [
+            ("description", &d.description),
+            ("personality", &d.personality),
+            ("scenario", &d.scenario),
+            ("first_mes", &d.first_mes),
+            ("mes_example", &d.mes_example),
+            ("system_prompt", &d.system_prompt),
+            ("post_history_instructions", &d.post_history_instructions),
+        ];
+        for (name, field) in fields {
+            if let Some(text) = field {
+                f(name, text);
+            }
+        }
+        let greetings = [
+            ("alternate_greetings", &d.alternate_greetings),
+            ("group_only_greetings", &d.group_only_greetings),
+        ];
+        for (name, list) in greetings {
+            for (i, g) in list.iter().flatten().enumerate() {
+                f(&format!("{}[{}]", name, i), g);
+            }
+        }
+        if let Some(cb) = &d.character_book {
+            for (i, e) in cb.entries.iter().enumerate() {
+                f(&format!("character_book.entries[{}]", i), &e.content);
             }
         }
-        let edited_card = tools::write_text_to_png(
-            TEXT_KEY_PNG,
-            &base64_json_string,
-            image_data,
-        )?;
-        Ok(edited_card)
     }
 
-    pub fn from_png_image(image_data: &Bytes) -> Result<Self> {
-        let raw_text = tools::read_text_chunk(image_data, TEXT_KEY_PNG)?;
-        if raw_text.is_none() {
-            bail!("No {} entry in PNG tEXt chunks", TEXT_KEY_PNG);
-        };
-        let text = BASE64_STANDARD.decode(raw_text.unwrap())?;
-        if !text.starts_with(&[b'{']) {
-            bail!(
-                "{} entry in PNG tEXt chunks does not start with '{{'",
-                TEXT_KEY_PNG
-            );
-        }
-        // Try to convert tag into tavern card data
-        let mut card = serde_json::from_slice::<TavernCardV3>(&text);
-        if card.is_err() {
-            // Sometimes the tag contains only the data portion
-            match serde_json::from_slice::<CharacterDataV3>(&text) {
-                Ok(card_data) => {
-                    card = Ok(TavernCardV3 {
-                        data: card_data,
-                        ..Default::default()
-                    });
-                }
-                Err(e) => {
-                    bail!(
-                        "Failed to parse {} entry in PNG tEXt chunks: {}",
-                        TEXT_KEY_PNG,
-                        e
-                    );
-                }
+    /// Parses example dialogues of the card, see
+    /// [`split_examples`](super::examples::split_examples).
+    pub fn split_examples(&self) -> Vec<Vec<ExampleTurn>> {
+        let mes_example = self
+            .data
+            .mes_example
+            .as_deref()
+            .or(self.mes_example.as_deref())
+            .unwrap_or_default();
+        split_examples(mes_example)
+    }
+
+    /// Replaces `creator_notes` with the notes in the given language from
+    /// `creator_notes_multilingual`. Language codes match in any case.
+    pub fn use_creator_notes_language(&mut self, lang: &str) -> Result<()> {
+        let notes = self.data.creator_notes_multilingual.as_ref();
+        let note = notes.and_then(|notes| {
+            notes.iter().find(|(code, _)| code.eq_ignore_ascii_case(lang))
+        });
+        match note {
+            Some((_, note)) => {
+                self.data.creator_notes = Some(note.clone());
+                Ok(())
+            }
+            None => {
+                let available = notes
+                    .map(|x| x.keys().cloned().collect::<Vec<_>>().join(", "))
+                    .filter(|x| !x.is_empty())
+                    .unwrap_or_else(|| "none".to_string());
+                bail!(
+                    "No creator notes in language \"{}\", available: {}",
+                    lang,
+                    available
+                )
             }
         }
-        let mut card = card.unwrap();
-        card.image_data = Some(image_data.clone());
-        Ok(card)
     }
 
     /// Make changes to better conform the specification
@@ -165,8 +214,10 @@ impl TavernCardV3 {
     }
 }
 
-impl Display for TavernCardV3 {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl TavernCardV3 {
+    /// Lists the card fields as printed text, wrapped to the wrap width,
+    /// see [`wrap::options`].
+    pub fn to_text(&self, print: &PrintOptions) -> String {
         // Turns &Option<String> into &str
         fn no_opt(o: &Option<String>) -> &str {
             const NONE_STR: &str = "NONE";
@@ -189,6 +240,14 @@ impl Display for TavernCardV3 {
             ("Creator:", no_opt(&self.data.creator)),
         ];
 
+        // List languages of creator notes, if present
+        let languages_store;
+        if let Some(notes) = &self.data.creator_notes_multilingual {
+            languages_store =
+                notes.keys().cloned().collect::<Vec<String>>().join(", ");
+            lines.push(("Creator notes languages:", &languages_store));
+        }
+
         // Print alternative greetings, if present
         let ag_store;
         if let Some(alternative_greetings) = &self.data.alternate_greetings {
@@ -196,6 +255,26 @@ impl Display for TavernCardV3 {
             lines.push(("Alternative greetings:", &ag_store));
         }
 
+        // Print group-only greetings, if present
+        let gg_store;
+        if let Some(group_greetings) = &self.data.group_only_greetings {
+            gg_store = group_greetings.join("\n\n====\n\n");
+            lines.push(("Group-only greetings:", &gg_store));
+        }
+
+        // Print assets, if present
+        let assets_store;
+        if let Some(assets) =
+            self.data.assets.as_ref().filter(|x| !x.is_empty())
+        {
+            assets_store = assets
+                .iter()
+                .map(|x| format!("{} ({}): {}", x.name, x.asset_type, x.uri))
+                .collect::<Vec<String>>()
+                .join("\n");
+            lines.push(("Assets:", &assets_store));
+        }
+
         // Print lorebook
         let lb_store;
         if let Some(character_book) = &self.data.character_book {
@@ -218,17 +297,22 @@ impl Display for TavernCardV3 {
 
         // Now to convert the lines vector into a pretty string
         let mut output = String::new();
-        let tw = *[textwrap::termwidth(), 80usize].iter().min().unwrap();
         let options =
-            Options::new(tw).initial_indent("").subsequent_indent("    ");
+            wrap::options(80).initial_indent("").subsequent_indent("    ");
         for (key, value) in lines {
+            let key = print.paint(key, Color::Bold);
+            let value = print.truncate_field(value);
             let mut line = format!("{}: {}\n", key, value);
             line = fill(&line, &options);
             output += &line;
         }
-        write!(f, "{}", output)?;
+        output
+    }
+}
 
-        Ok(())
+impl Display for TavernCardV3 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_text(&PrintOptions::default()))
     }
 }
 
@@ -236,10 +320,11 @@ impl Display for TavernCardV3 {
 mod tests {
 
     use super::*;
+    use crate::card_formats::character_book::CharacterBookEntry;
     use anyhow::Result;
 
     #[allow(unused_imports)]
-    use tools;
+    use crate::tools;
 
     fn create_test_card() -> TavernCardV3 {
         let mut card = TavernCardV3::new();
@@ -266,16 +351,21 @@ mod tests {
         card.data.first_mes = Some(String::from("Test first message data"));
         card.data.mes_example = Some(String::from("Test dialog example data"));
         card.data.character_book = Some(CharacterBook::default());
-        let mut entry1 = CharacterBookEntry::default();
-        entry1.content = String::from("Test book entry 1");
+        let entry1 = CharacterBookEntry {
+            content: String::from("Test book entry 1"),
+            ..Default::default()
+        };
 
-        let mut entry2 = CharacterBookEntry::default();
-        entry2.content = String::from("Test book entry 2");
+        let entry2 = CharacterBookEntry {
+            content: String::from("Test book entry 2"),
+            ..Default::default()
+        };
 
         card.data.character_book.as_mut().unwrap().entries.push(entry1);
         card.data.character_book.as_mut().unwrap().entries.push(entry2);
         card.image_data = Some(tools::get_default_image());
-        let image_with_tag = card.into_png_image().unwrap();
+        let image_with_tag =
+            card.into_png_image(&tools::FileOptions::default()).unwrap();
         card.image_data = Some(image_with_tag);
         card
     }
@@ -290,10 +380,41 @@ mod tests {
     #[test]
     fn test_write_and_read() -> Result<()> {
         let card = create_test_card();
-        let image = card.into_png_image()?;
+        let image = card.into_png_image(&tools::FileOptions::default())?;
         let card2 = TavernCardV3::from_png_image(&image)?;
         assert_eq!(card, card2);
         // tools::write_image_to_file(&image, &std::path::Path::new("testing/test_card.png"))?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_json_round_trip() -> Result<()> {
+        let mut card = create_test_card();
+        card.image_data = None;
+        let card2 = TavernCardV3::from_json(&card.to_json()?)?;
+        assert_eq!(card, card2);
+        let card3 = TavernCardV3::from_base64(&card.to_base64()?)?;
+        assert_eq!(card, card3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_json_data_only() -> Result<()> {
+        let card = TavernCardV3::from_json(r#"{"name": "Test name"}"#)?;
+        assert_eq!(card.data.name, Some(String::from("Test name")));
+        assert!(TavernCardV3::from_json("[]").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_use_creator_notes_language() -> Result<()> {
+        let mut card = TavernCardV3::from_json(
+            r#"{"data": {"creator_notes": "Hello",
+                "creator_notes_multilingual": {"de": "Hallo"}}}"#,
+        )?;
+        assert!(card.use_creator_notes_language("fr").is_err());
+        card.use_creator_notes_language("DE")?;
+        assert_eq!(card.data.creator_notes, Some(String::from("Hallo")));
+        Ok(())
+    }
+}
@@ -1,22 +1,89 @@
 //! Tools to download a character from Backyard AI
 
 use std::{
+    collections::HashSet,
     io::{self, Write},
-    path::Path,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    sync::Mutex,
     thread,
     time::Duration,
 };
 
 use crate::{
-    card_formats::tavern_card_v2::*,
-    tools::{self, write_image_to_file},
+    card_formats::{tavern_card_v2::*, CardFile, TOOL_EXTENSION_KEY},
+    card_providers::CardProvider,
+    error::{TavernError, TavernResult},
+    tools::{
+        self, write_image_to_file, DownloadOptions, FileOptions,
+        SanitizeStrategy,
+    },
 };
 
 use anyhow::{bail, Context, Result};
 use chrono::{DateTime, Utc};
+use indicatif::{ProgressBar, ProgressStyle};
 use log::info;
 use soup::prelude::*;
 
+const BAYA_HOST: &str = "https://backyard.ai";
+const CHARACTER_PATH: &str = "/hub/character/";
+
+/// Most pages of a creator profile that are listed, in case the site keeps
+/// bringing characters on every page.
+const MAX_CREATOR_PAGES: usize = 100;
+
+/// Whether the steps of every download are printed. Catalog downloads only
+/// log them, as the lines of parallel workers would mix, and the progress
+/// bar shows how far the download is.
+static PRINT_STEPS: AtomicBool = AtomicBool::new(true);
+
+/// Prints a step of a download, flushing stdout, so that the line shows
+/// before a long operation. Only logs it during catalog downloads.
+fn print_step(text: &str) {
+    if PRINT_STEPS.load(Ordering::Relaxed) {
+        print!("{}", text);
+        io::stdout().flush().unwrap();
+    } else {
+        info!("{}", text.trim());
+    }
+}
+
+/// Backyard AI, downloading single characters by their hub page.
+pub struct Backyard;
+
+impl CardProvider for Backyard {
+    fn name(&self) -> &'static str {
+        "Backyard AI"
+    }
+
+    fn url_patterns(&self) -> &'static [&'static str] {
+        &["https://backyard.ai/hub/character/<id>"]
+    }
+
+    fn matches(&self, url: &str) -> bool {
+        url.contains("backyard.ai")
+    }
+
+    fn fetch_card(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> TavernResult<TavernCardV2> {
+        fetch_card_from_baya_url(url, options)
+    }
+
+    fn download(
+        &self,
+        url: &str,
+        output_path: &Path,
+        sanitize: SanitizeStrategy,
+        options: &DownloadOptions,
+    ) -> Result<()> {
+        download_card_from_baya_url(url, output_path, sanitize, options)
+    }
+}
+
 #[allow(non_snake_case, dead_code)]
 #[derive(serde::Deserialize, Debug)]
 pub struct BayaCharacter {
@@ -88,37 +155,301 @@ struct ModelFamily {
     promptFormat: String,
 }
 
-pub fn download_card_from_baya_url(url: &str, output_path: &Path) -> Result<()> {
+/// Downloads a character into the output folder, naming the file after the
+/// character, made safe with `sanitize`.
+pub fn download_card_from_baya_url(
+    url: &str,
+    output_path: &Path,
+    sanitize: SanitizeStrategy,
+    options: &DownloadOptions,
+) -> Result<()> {
     // Forcibly flush stdout before blocking operations, otherwise the line before long operations does not display.
     let flush = || io::stdout().flush().unwrap();
 
-    print!("Downloading web page: ");
+    let tavern_card = fetch_card_from_baya_url(url, options)?;
+    let card_path = card_file_path(&tavern_card, output_path, sanitize);
+    write_card(&tavern_card, &card_path, &options.file_options)?;
+    print!("Fap away!");
+    flush();
+    thread::sleep(Duration::from_millis(150));
+    println!("\rAll done!");
     flush();
-    let body = tools::download_page(url)?;
-    println!("Done!");
+    Ok(())
+}
+
+/// Downloads all characters published by a creator into the output folder.
+///
+/// Characters that already have a card with the same file name in the
+/// output folder are skipped. With `limit`, downloads only that many first
+/// characters of the creator. With `fail_fast`, stops at the first failed
+/// character, otherwise returns error at the end if any of them failed.
+/// File names are made safe with `sanitize`.
+///
+/// Up to `parallel` characters are downloaded at once, each by its own
+/// worker. All workers keep to the rate limit of `options` together.
+pub fn download_creator_catalog(
+    profile_url: &str,
+    output_path: &Path,
+    limit: Option<usize>,
+    fail_fast: bool,
+    sanitize: SanitizeStrategy,
+    options: &DownloadOptions,
+    parallel: usize,
+) -> Result<()> {
+    println!("Listing characters of the creator");
+    let urls = list_creator_characters(profile_url, limit, options)?;
+    println!("Found {} characters", urls.len());
+
+    let pb = ProgressBar::new(urls.len() as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template(
+                "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}",
+            )?
+            .progress_chars("#>-"),
+    );
+    PRINT_STEPS.store(false, Ordering::Relaxed);
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let claimed = Mutex::new(HashSet::new());
+    let first_error = Mutex::new(None);
+    let (downloaded, skipped, failed) =
+        (AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0));
+    thread::scope(|scope| {
+        for _ in 0..parallel.clamp(1, urls.len().max(1)) {
+            scope.spawn(|| {
+                while !stop.load(Ordering::Relaxed) {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(url) = urls.get(index) else {
+                        break;
+                    };
+                    pb.set_message(url.clone());
+                    let result = download_catalog_character(
+                        url,
+                        output_path,
+                        sanitize,
+                        options,
+                        &claimed,
+                        &pb,
+                    );
+                    match result {
+                        Ok(true) => downloaded.fetch_add(1, Ordering::Relaxed),
+                        Ok(false) => skipped.fetch_add(1, Ordering::Relaxed),
+                        Err(e) if fail_fast => {
+                            stop.store(true, Ordering::Relaxed);
+                            let e = e
+                                .context(format!("Failed to download {}", url));
+                            first_error.lock().unwrap().get_or_insert(e);
+                            break;
+                        }
+                        Err(e) => {
+                            pb.println(format!(
+                                "Failed to download {}: {:#}",
+                                url, e
+                            ));
+                            failed.fetch_add(1, Ordering::Relaxed)
+                        }
+                    };
+                    pb.inc(1);
+                }
+            });
+        }
+    });
+    PRINT_STEPS.store(true, Ordering::Relaxed);
+    if let Some(e) = first_error.into_inner().unwrap() {
+        pb.abandon();
+        return Err(e);
+    }
+    pb.finish_with_message("Catalog download complete!");
+    let failed = failed.into_inner();
+    println!(
+        "Downloaded {}, skipped {} already present, failed {}",
+        downloaded.into_inner(),
+        skipped.into_inner(),
+        failed
+    );
+    if failed > 0 {
+        bail!("Failed to download {} characters", failed);
+    }
+    Ok(())
+}
+
+/// Downloads one character of a catalog. Returns false if it was skipped,
+/// because its card already exists, or another worker is writing a card
+/// with the same file name.
+fn download_catalog_character(
+    url: &str,
+    output_path: &Path,
+    sanitize: SanitizeStrategy,
+    options: &DownloadOptions,
+    claimed: &Mutex<HashSet<PathBuf>>,
+    pb: &ProgressBar,
+) -> Result<bool> {
+    let character = fetch_baya_character(url, options)?;
+    let card_path =
+        card_file_path(&TavernCardV2::from(&character), output_path, sanitize);
+    let is_new = !card_path.exists()
+        && claimed.lock().unwrap().insert(card_path.clone());
+    if !is_new {
+        pb.println(format!("Skipping, {} already exists", card_path.display()));
+        return Ok(false);
+    }
+    let tavern_card = convert_baya_character(&character, url, options);
+    write_card(&tavern_card, &card_path, &options.file_options)?;
+    Ok(true)
+}
 
-    print!("Parsing downloaded page: ");
-    let baya_character =
-        parse_page(&body).context("Could not parse character JSON")?;
-    println!("Done!");
+/// Lists URLs of characters published by a creator, in the listed order.
+///
+/// Goes through the pages of the creator profile until a page brings no new
+/// characters, or until `limit` characters are found. Stops with a warning
+/// after [`MAX_CREATOR_PAGES`] pages.
+pub fn list_creator_characters(
+    profile_url: &str,
+    limit: Option<usize>,
+    options: &DownloadOptions,
+) -> Result<Vec<String>> {
+    let mut urls: Vec<String> = Vec::new();
+    let separator = if profile_url.contains('?') { '&' } else { '?' };
+    for page in 1..=MAX_CREATOR_PAGES {
+        let page_url = format!("{}{}page={}", profile_url, separator, page);
+        let body = tools::download_page(&page_url, options)?;
+        let new_urls: Vec<String> = find_character_ids(&body)
+            .into_iter()
+            .map(|id| format!("{}{}{}", BAYA_HOST, CHARACTER_PATH, id))
+            .filter(|url| !urls.contains(url))
+            .collect();
+        if new_urls.is_empty() {
+            return Ok(urls);
+        }
+        urls.extend(new_urls);
+        if let Some(limit) = limit {
+            if urls.len() >= limit {
+                urls.truncate(limit);
+                return Ok(urls);
+            }
+        }
+    }
+    println!(
+        "Warning: stopped listing after {} pages, the creator may have more \
+         characters",
+        MAX_CREATOR_PAGES
+    );
+    Ok(urls)
+}
+
+/// Finds ids of the characters linked on the page, without repeats. Only
+/// links count, not the path mentioned elsewhere, like in scripts.
+fn find_character_ids(body: &str) -> Vec<String> {
+    let mut ids: Vec<String> = Vec::new();
+    for (start, _) in body.match_indices("href=") {
+        let rest = &body[start + "href=".len()..];
+        let Some(quote) = rest.chars().next().filter(|c| "\"'".contains(*c))
+        else {
+            continue;
+        };
+        let link = rest[1..].split(quote).next().unwrap_or_default();
+        if let Some(id) = character_id(link) {
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+    }
+    ids
+}
+
+/// Returns the id of the character that the link leads to, or None if it
+/// does not lead to a character page of the site.
+fn character_id(link: &str) -> Option<String> {
+    let path = link.strip_prefix(BAYA_HOST).unwrap_or(link);
+    let rest = path.strip_prefix(CHARACTER_PATH)?;
+    let id = rest.split(['?', '#']).next().unwrap_or_default();
+    let is_id = !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric());
+    is_id.then(|| id.to_string())
+}
+
+/// Writes the card into file.
+fn write_card(
+    tavern_card: &TavernCardV2,
+    card_path: &Path,
+    file_options: &FileOptions,
+) -> Result<()> {
+    print_step("Writing tavern card: ");
+    let tavern_image = tavern_card
+        .into_png_image(file_options)
+        .context("Could not write tavern card")?;
+    // Make sure the card reads back, so that a broken file is never written
+    image::load_from_memory(&tavern_image)
+        .context("Card image does not decode")?;
+    TavernCardV2::from_png_image(&tavern_image)
+        .context("Card data does not read back")?;
+    info!("Card verified: image decodes and card data reads back");
+    write_image_to_file(&tavern_image, card_path)?;
+    print_step("Done!\n");
+    Ok(())
+}
+
+/// Path of the card file in the output folder, named after the character.
+fn card_file_path(
+    tavern_card: &TavernCardV2,
+    output_path: &Path,
+    sanitize: SanitizeStrategy,
+) -> PathBuf {
+    let name = tavern_card.data.name.as_deref().unwrap_or("NO_NAME_SET");
+    let file_name = tools::sanitize_filename(name, sanitize);
+    output_path.join(format!("{}.png", file_name))
+}
+
+/// Downloads character from Backyard AI and converts it into tavern card.
+///
+/// The URL is recorded in the card, so that it can be checked for updates.
+pub fn fetch_card_from_baya_url(
+    url: &str,
+    options: &DownloadOptions,
+) -> TavernResult<TavernCardV2> {
+    let baya_character = fetch_baya_character(url, options)?;
+    Ok(convert_baya_character(&baya_character, url, options))
+}
+
+/// Downloads character page from Backyard AI and parses character data.
+fn fetch_baya_character(
+    url: &str,
+    options: &DownloadOptions,
+) -> TavernResult<BayaCharacter> {
+    print_step("Downloading web page: ");
+    let body = tools::download_page(url, options)?;
+    print_step("Done!\n");
+
+    print_step("Parsing downloaded page: ");
+    let baya_character = parse_page(&body)?;
+    print_step("Done!\n");
 
     let display_char_name: String = baya_character
         .aiDisplayName
         .clone()
         .unwrap_or_else(|| "NO_NAME_SET".to_string());
-    println!("Character name is: {}", display_char_name);
+    print_step(&format!("Character name is: {}\n", display_char_name));
 
     info!("\nCHARACTER INFO:\n{:#?}", &baya_character);
+    Ok(baya_character)
+}
 
+/// Converts character into tavern card, downloading its image.
+///
+/// Uses the default image if the character has none or it fails to download.
+fn convert_baya_character(
+    baya_character: &BayaCharacter,
+    url: &str,
+    options: &DownloadOptions,
+) -> TavernCardV2 {
     // Download the image, if it is linked on the page. Otherwise, use default image.
     let mut card_image = None;
     if !baya_character.Images.is_empty() {
         // Download the first image linked on card.
         let url = &baya_character.Images[0].imageUrl;
-        print!("Downloading image: ");
-        flush();
+        print_step("Downloading image: ");
         // Try to download image and check result
-        let mut temp_img = tools::download_image(url);
+        let mut temp_img = tools::download_image(url, options);
         match temp_img {
             Err(e) => eprintln!("Could not download image because {}", e),
             Ok(img) => {
@@ -134,52 +465,48 @@ pub fn download_card_from_baya_url(url: &str, output_path: &Path) -> Result<()>
             }
         };
     } else {
-        print!("No image provided, using default image.");
+        print_step("No image provided, using base image.");
     }
-    println!("Done!");
+    print_step("Done!\n");
 
-    print!("Writing tavern card: ");
-    flush();
-    let mut tavern_card = TavernCardV2::from(&baya_character);
+    let mut tavern_card = TavernCardV2::from(baya_character);
     tavern_card.image_data = card_image;
+    let extensions =
+        tavern_card.data.extensions.get_or_insert_with(Default::default);
+    extensions.insert(
+        TOOL_EXTENSION_KEY.to_string(),
+        serde_json::json!({ "source": url }),
+    );
 
     info!("\nCONVERTED TAVERN CARD:\n{:#?}", &tavern_card);
-
-    let tavern_image =
-        tavern_card.into_png_image().context("Could not write tavern card")?;
-    let card_name = output_path.join(format!("{}.png", display_char_name));
-    write_image_to_file(&tavern_image, &card_name)?;
-    println!("Done!");
-    print!("Fap away!");
-    flush();
-    thread::sleep(Duration::from_millis(150));
-    println!("\rAll done!");
-    flush();
-    Ok(())
+    tavern_card
 }
 
 /// Extracts character data from the downloaded web page.
-fn parse_page(body: &str) -> Result<BayaCharacter> {
+fn parse_page(body: &str) -> TavernResult<BayaCharacter> {
+    let fail =
+        |what: &str| TavernError::Parse(format!("character page: {}", what));
     let soup = soup::Soup::new(body);
     let scr = soup
         .tag("script")
         .attr("id", "__NEXT_DATA__")
         .find()
-        .context("Did not find __NEXT_DATA__")?;
+        .ok_or_else(|| fail("did not find __NEXT_DATA__"))?;
 
     let scr_text = scr.text();
 
     info!("\nSCRIPT DATA:\n{:#?}", &scr_text);
 
     let mut json: serde_json::Value = serde_json::from_str(&scr.text())
-        .context("JSON was not well-formatted")?;
+        .map_err(|e| fail(&format!("JSON was not well-formatted: {}", e)))?;
 
     let pointer =
         "/props/pageProps/trpcState/json/queries/0/state/data/character";
-    let char_json =
-        json.pointer_mut(pointer).context("Could not find character block")?;
+    let char_json = json
+        .pointer_mut(pointer)
+        .ok_or_else(|| fail("could not find character block"))?;
 
-    let json_string = serde_json::to_string_pretty(&char_json)?;
+    let json_string = format!("{:#}", char_json);
     info!("\nCHAR JSON:\n{:#?}", &json_string);
 
     let ds = &mut serde_json::Deserializer::from_str(&json_string);
@@ -188,11 +515,10 @@ fn parse_page(body: &str) -> Result<BayaCharacter> {
         Ok(bc) => Ok(bc),
         Err(e) => {
             let err_path = e.path().to_string();
-            bail!(
-                "Could not parse character JSON: {:?}  Error path: {:?}",
-                e,
-                err_path
-            );
+            Err(fail(&format!(
+                "could not parse character JSON: {:?}  Error path: {:?}",
+                e, err_path
+            )))
         }
     }
 }
@@ -340,11 +666,27 @@ mod tests {
         if cache.page_cache.contains_key(url) {
             return Ok(cache.page_cache.get(url).unwrap());
         }
-        let page_content = tools::download_page(url)?;
+        let page_content =
+            tools::download_page(url, &DownloadOptions::default())?;
         cache.page_cache.insert(url.to_string(), page_content);
         return Ok(cache.page_cache.get(url).unwrap());
     }
 
+    #[test]
+    fn test_find_character_ids() {
+        let body = r#"<a href="/hub/character/abc123">A</a>
+            <a href="https://backyard.ai/hub/character/def456?x=1">B</a>
+            <a href="/hub/character/abc123">A again</a>
+            <a href='/hub/character/ghi789#top'>C</a>
+            <a href="/hub/character/abc123/chat">Chat</a>
+            <a href="https://example.com/hub/character/xyz">Elsewhere</a>
+            <script>{"url": "/hub/character/recommended1"}</script>"#;
+        assert_eq!(
+            find_character_ids(body),
+            vec!["abc123", "def456", "ghi789"]
+        );
+    }
+
     #[test_context(TestCache)]
     #[test]
     fn test_downloading_page(cache: &mut TestCache) -> Result<()> {
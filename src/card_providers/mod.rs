@@ -1 +1,109 @@
-pub mod baya_download;
\ No newline at end of file
+pub mod baya_download;
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::card_formats::tavern_card_v2::TavernCardV2;
+use crate::error::{TavernError, TavernResult};
+use crate::tools::{DownloadOptions, SanitizeStrategy};
+
+/// Site that serves tavern cards.
+pub trait CardProvider: Sync {
+    /// Name of the site, as shown to the user.
+    fn name(&self) -> &'static str;
+
+    /// Examples of supported URLs, as shown by `providers list`.
+    fn url_patterns(&self) -> &'static [&'static str];
+
+    /// Returns true if the provider can download from the URL.
+    fn matches(&self, url: &str) -> bool;
+
+    /// Downloads card from the URL.
+    fn fetch_card(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> TavernResult<TavernCardV2>;
+
+    /// Downloads card from the URL into the output folder, naming the file
+    /// after the character, made safe with `sanitize`.
+    fn download(
+        &self,
+        url: &str,
+        output_path: &Path,
+        sanitize: SanitizeStrategy,
+        options: &DownloadOptions,
+    ) -> Result<()>;
+}
+
+/// All supported providers, in the order they are tried.
+pub static PROVIDERS: &[&dyn CardProvider] = &[&baya_download::Backyard];
+
+/// Returns the provider that serves the URL.
+pub fn find_provider(url: &str) -> TavernResult<&'static dyn CardProvider> {
+    match PROVIDERS.iter().find(|x| x.matches(url)) {
+        Some(provider) => Ok(*provider),
+        None => Err(TavernError::UnsupportedFormat(format!(
+            "No provider supports URL {}, see `providers list` for \
+             supported sites",
+            url
+        ))),
+    }
+}
+
+/// Downloads card from the provider that serves the URL.
+pub fn fetch_card(
+    url: &str,
+    options: &DownloadOptions,
+) -> TavernResult<TavernCardV2> {
+    find_provider(url)?.fetch_card(url, options)
+}
+
+/// Checks that the text is a URL that a provider supports, and returns it
+/// without surrounding whitespace.
+fn check_card_url(text: &str) -> Result<&str> {
+    let url = text.trim();
+    if !url.starts_with("https://") && !url.starts_with("http://") {
+        let start: String = url.chars().take(40).collect();
+        bail!("The clipboard does not hold a URL, but \"{}\"", start);
+    }
+    find_provider(url)?;
+    Ok(url)
+}
+
+/// Reads the card URL from the system clipboard. Fails unless it is a URL
+/// that a provider supports.
+pub fn url_from_clipboard() -> Result<String> {
+    let mut clipboard =
+        arboard::Clipboard::new().context("Could not open the clipboard")?;
+    let text = clipboard.get_text().context("The clipboard has no text")?;
+    let url = check_card_url(&text)?;
+    println!("Using URL from the clipboard: {}", url);
+    Ok(url.to_string())
+}
+
+/// Prints supported sites with their URL patterns.
+pub fn list_providers() {
+    for provider in PROVIDERS {
+        println!("{}", provider.name());
+        for pattern in provider.url_patterns() {
+            println!("  {}", pattern);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_provider() {
+        let url = "https://backyard.ai/hub/character/abc";
+        assert_eq!(find_provider(url).unwrap().name(), "Backyard AI");
+        assert!(find_provider("https://example.com/character").is_err());
+
+        assert_eq!(check_card_url(&format!(" {}\n", url)).unwrap(), url);
+        assert!(check_card_url("backyard.ai/hub/character/abc").is_err());
+    }
+}
@@ -0,0 +1,310 @@
+//! Reading of CHARX archives.
+//!
+//! A CHARX file is a ZIP archive with the V3 card in `card.json`, and the
+//! files of its assets next to it. Assets stored in the archive have
+//! `embeded://<path in archive>` as their URI.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use base64::prelude::*;
+use bytes::Bytes;
+use serde_json::Value;
+
+use crate::assets::{embed_asset, read_assets, write_assets};
+use crate::convert::CardFormat;
+use crate::tools::{self, FileOptions};
+
+/// Name of the card JSON in the archive.
+const CARD_FILE: &str = "card.json";
+
+/// Prefixes of the URIs of assets stored in the archive. The spec spells it
+/// "embeded", some tools write "embedded".
+const ARCHIVE_URI_PREFIXES: &[&str] = &["embeded://", "embedded://"];
+
+/// Largest file read from the archive.
+const MAX_FILE_SIZE: u64 = 512 * 1024 * 1024;
+
+/// What to do with the assets stored in a CHARX archive when converting it
+/// to a plain card.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum AssetHandling {
+    /// Embed assets into the output: as PNG chunks, or as data URIs in JSON
+    #[default]
+    Keep,
+    /// Remove the assets stored in the archive from the card
+    Drop,
+    /// Write assets into a folder next to the output, and point to them
+    External,
+}
+
+/// Reads card JSON and all other files of the CHARX archive, by their path
+/// in the archive.
+pub fn read_charx(path: &Path) -> Result<(Value, BTreeMap<String, Vec<u8>>)> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Could not open {}", path.display()))?;
+    let mut archive = zip::ZipArchive::new(file)
+        .with_context(|| format!("{} is not a ZIP archive", path.display()))?;
+    let mut card = None;
+    let mut files = BTreeMap::new();
+    for index in 0..archive.len() {
+        let entry = archive.by_index(index)?;
+        if entry.is_dir() {
+            continue;
+        }
+        // Names that would leave the folder are not used
+        let Some(name) = entry.enclosed_name().map(|x| x.to_path_buf()) else {
+            println!("Skipping unsafe path in the archive: {}", entry.name());
+            continue;
+        };
+        let name = name.to_string_lossy().replace('\\', "/");
+        let mut data = Vec::new();
+        entry.take(MAX_FILE_SIZE).read_to_end(&mut data)?;
+        if name == CARD_FILE {
+            card = Some(serde_json::from_slice(&data).with_context(|| {
+                format!("Could not parse {} in the archive", CARD_FILE)
+            })?);
+        } else {
+            files.insert(name, data);
+        }
+    }
+    let Some(card) = card else {
+        bail!("{} has no {}", path.display(), CARD_FILE);
+    };
+    Ok((card, files))
+}
+
+/// Reads only the card JSON of the CHARX archive, leaving the assets be.
+pub fn read_charx_card(path: &Path) -> Result<Value> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Could not open {}", path.display()))?;
+    let mut archive = zip::ZipArchive::new(file)
+        .with_context(|| format!("{} is not a ZIP archive", path.display()))?;
+    let entry = archive
+        .by_name(CARD_FILE)
+        .with_context(|| format!("{} has no {}", path.display(), CARD_FILE))?;
+    let mut data = Vec::new();
+    entry.take(MAX_FILE_SIZE).read_to_end(&mut data)?;
+    serde_json::from_slice(&data).with_context(|| {
+        format!("Could not parse {} in the archive", CARD_FILE)
+    })
+}
+
+/// Returns the path in the archive of an asset stored in it.
+fn archive_path(uri: &str) -> Option<&str> {
+    ARCHIVE_URI_PREFIXES.iter().find_map(|x| uri.strip_prefix(x))
+}
+
+/// Returns MIME type of the asset for data URIs, by its extension.
+fn mime_type(ext: &str) -> &'static str {
+    match ext.to_lowercase().as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "gif" => "image/gif",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Resolves the assets stored in the archive, so that the card does not
+/// point into the archive anymore. Reports what was done with every asset.
+///
+/// The main icon becomes the card image when there is no other image, and
+/// the output is a PNG card, where it is `ccdefault:`. Other assets are
+/// handled as `handling` says. With `keep`, PNG output gets assets embedded
+/// the way [`crate::assets`] does it, and JSON output gets data URIs.
+/// With `external`, assets are written into `<output name>_assets` next to
+/// `output_path`, and pointed to by relative paths. Assets outside the
+/// archive, like links, are left as they are.
+pub fn dereference_assets(
+    card: &mut Value,
+    image: &mut Option<Bytes>,
+    files: &BTreeMap<String, Vec<u8>>,
+    handling: AssetHandling,
+    format_out: CardFormat,
+    output_path: &Path,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let stem = output_path.file_stem().unwrap_or_default().to_string_lossy();
+    let folder_name = format!("{}_assets", stem);
+    let folder = output_path.with_file_name(&folder_name);
+
+    let assets = read_assets(card)?;
+    let mut kept = Vec::new();
+    for asset in &assets {
+        let label = format!("{} asset \"{}\"", asset.asset_type, asset.name);
+        let Some(path) = archive_path(&asset.uri) else {
+            println!("{}: not in the archive, left as it is", label);
+            kept.push(asset.clone());
+            continue;
+        };
+        let Some(data) = files.get(path) else {
+            println!(
+                "{}: {} is missing from the archive, dropped",
+                label, path
+            );
+            continue;
+        };
+        let is_main_icon = asset.asset_type == "icon" && asset.name == "main";
+        if is_main_icon && image.is_none() {
+            *image = Some(tools::convert_to_png(&Bytes::from(data.clone()))?);
+            if format_out == CardFormat::Png {
+                println!("{}: used as the card image", label);
+                let mut asset = asset.clone();
+                asset.uri = "ccdefault:".to_string();
+                kept.push(asset);
+                continue;
+            }
+        }
+        let mut asset = asset.clone();
+        match handling {
+            AssetHandling::Drop => {
+                println!("{}: dropped", label);
+                continue;
+            }
+            AssetHandling::Keep if format_out == CardFormat::Png => {
+                let image = image.get_or_insert_with(tools::base_image);
+                asset.uri = embed_asset(&kept, image, data, file_options)?;
+                println!("{}: embedded as {}", label, asset.uri);
+            }
+            AssetHandling::Keep => {
+                let mime = mime_type(&asset.ext);
+                let data = BASE64_STANDARD.encode(data);
+                asset.uri = format!("data:{};base64,{}", mime, data);
+                println!("{}: embedded as data URI", label);
+            }
+            AssetHandling::External => {
+                let relative = path.strip_prefix("assets/").unwrap_or(path);
+                let file_path = folder.join(relative);
+                if let Some(parent) = file_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(&file_path, data).with_context(|| {
+                    format!("Could not write {}", file_path.display())
+                })?;
+                asset.uri = format!("{}/{}", folder_name, relative);
+                println!("{}: written to {}", label, file_path.display());
+            }
+        }
+        kept.push(asset);
+    }
+    if !assets.is_empty() {
+        write_assets(card, &kept)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_read_and_dereference_charx() -> Result<()> {
+        let dir = std::env::temp_dir().join("taverntools_test_charx");
+        std::fs::create_dir_all(&dir)?;
+        let card = json!({
+            "spec": "chara_card_v3",
+            "spec_version": "3.0",
+            "data": {
+                "name": "Test",
+                "assets": [
+                    {
+                        "type": "icon",
+                        "uri": "embeded://assets/icon/image/main.png",
+                        "name": "main",
+                        "ext": "png"
+                    },
+                    {
+                        "type": "emotion",
+                        "uri": "embeded://assets/emotion/image/smile.png",
+                        "name": "smile",
+                        "ext": "png"
+                    },
+                    {
+                        "type": "background",
+                        "uri": "https://example.com/bg.png",
+                        "name": "bg",
+                        "ext": "png"
+                    }
+                ]
+            }
+        });
+        let charx_path = dir.join("card.charx");
+        let mut writer =
+            zip::ZipWriter::new(std::fs::File::create(&charx_path)?);
+        let options = zip::write::FileOptions::default();
+        writer.start_file("card.json", options)?;
+        writer.write_all(card.to_string().as_bytes())?;
+        writer.start_file("assets/icon/image/main.png", options)?;
+        writer.write_all(&tools::get_default_image())?;
+        writer.start_file("assets/emotion/image/smile.png", options)?;
+        writer.write_all(b"smile")?;
+        writer.finish()?;
+
+        let (card, files) = read_charx(&charx_path)?;
+        assert_eq!(card["data"]["name"], "Test");
+        assert_eq!(files.len(), 2);
+
+        let output_path = dir.join("out.json");
+        let mut dropped = card.clone();
+        let mut image = None;
+        let handling = AssetHandling::Drop;
+        let format = CardFormat::Png;
+        dereference_assets(
+            &mut dropped,
+            &mut image,
+            &files,
+            handling,
+            format,
+            &output_path,
+            &FileOptions::default(),
+        )?;
+        let assets = read_assets(&dropped)?;
+        let uris: Vec<&str> = assets.iter().map(|x| x.uri.as_str()).collect();
+        assert_eq!(uris, ["ccdefault:", "https://example.com/bg.png"]);
+        assert!(image.is_some());
+
+        let mut external = card.clone();
+        let handling = AssetHandling::External;
+        let format = CardFormat::Json;
+        dereference_assets(
+            &mut external,
+            &mut None,
+            &files,
+            handling,
+            format,
+            &output_path,
+            &FileOptions::default(),
+        )?;
+        let assets = read_assets(&external)?;
+        assert_eq!(assets[1].uri, "out_assets/emotion/image/smile.png");
+        let written = dir.join("out_assets/emotion/image/smile.png");
+        assert_eq!(std::fs::read(written)?, b"smile");
+
+        let mut kept = card.clone();
+        let mut image = None;
+        let handling = AssetHandling::Keep;
+        dereference_assets(
+            &mut kept,
+            &mut image,
+            &files,
+            handling,
+            format,
+            &output_path,
+            &FileOptions::default(),
+        )?;
+        let assets = read_assets(&kept)?;
+        assert_eq!(assets[1].uri, "data:image/png;base64,c21pbGU=");
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
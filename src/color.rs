@@ -0,0 +1,41 @@
+//! Decides whether output is colored, and colors text.
+//!
+//! Colors are off when `--no-color` is given, when the `NO_COLOR` environment
+//! variable is set, or when stdout is not a terminal.
+
+use is_terminal::IsTerminal;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Color {
+    Red,
+    Green,
+    Yellow,
+    Bold,
+}
+
+impl Color {
+    fn code(self) -> &'static str {
+        match self {
+            Color::Red => "31",
+            Color::Green => "32",
+            Color::Yellow => "33",
+            Color::Bold => "1",
+        }
+    }
+}
+
+/// Returns whether output is colored, given `--no-color`.
+pub fn detect(no_color: bool) -> bool {
+    let no_color_env =
+        std::env::var_os("NO_COLOR").is_some_and(|x| !x.is_empty());
+    !no_color && !no_color_env && std::io::stdout().is_terminal()
+}
+
+/// Wraps text in ANSI codes of the color, if `enabled`.
+pub fn paint(text: &str, color: Color, enabled: bool) -> String {
+    if enabled {
+        format!("\x1b[{}m{}\x1b[0m", color.code(), text)
+    } else {
+        text.to_string()
+    }
+}
@@ -0,0 +1,177 @@
+//! Settings read from the optional `taverntools.toml` file.
+//!
+//! Example of the file, with all settings at their defaults, and a category
+//! of issues of its own:
+//! ```toml
+//! input_path = "inventory/input"
+//! output_path = "inventory/output"
+//! issue_path = "inventory/issue"
+//!
+//! [issues]
+//! fallback = "other"
+//!
+//! [[issues.categories]]
+//! name = "broken_png"
+//! error_contains = ["Invalid PNG signature", "CRC error"]
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::error::TavernError;
+use crate::{DEFAULT_INPUT_PATH, DEFAULT_ISSUE_PATH, DEFAULT_OUTPUT_PATH};
+
+pub const CONFIG_FILE_NAME: &str = "taverntools.toml";
+
+#[derive(serde::Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub input_path: PathBuf,
+    pub output_path: PathBuf,
+    pub issue_path: PathBuf,
+    pub issues: IssueLayout,
+}
+
+/// Decides into which subfolder of the issue folder a failed card goes.
+#[derive(serde::Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct IssueLayout {
+    /// Categories are checked in order, the first matching one is used.
+    /// Errors that match none go by their kind, see [`BUILTIN_FOLDERS`].
+    pub categories: Vec<IssueCategory>,
+    /// Subfolder for errors that match no category.
+    pub fallback: String,
+}
+
+#[derive(serde::Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct IssueCategory {
+    /// Name of the subfolder.
+    pub name: String,
+    /// The category matches if error message contains any of these.
+    pub error_contains: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            input_path: PathBuf::from(DEFAULT_INPUT_PATH),
+            output_path: PathBuf::from(DEFAULT_OUTPUT_PATH),
+            issue_path: PathBuf::from(DEFAULT_ISSUE_PATH),
+            issues: IssueLayout::default(),
+        }
+    }
+}
+
+impl Default for IssueLayout {
+    fn default() -> Self {
+        IssueLayout { categories: Vec::new(), fallback: "other".to_string() }
+    }
+}
+
+/// Subfolders of the kinds of errors that are told apart without any
+/// category: cards of a newer spec, cards that do not parse, and images
+/// without card data.
+pub const BUILTIN_FOLDERS: [&str; 3] = ["unsupported", "format", "no_data"];
+
+/// Returns the subfolder of the kind of error, if it has its own.
+fn builtin_folder(error: &TavernError) -> Option<&'static str> {
+    let [unsupported, format, no_data] = BUILTIN_FOLDERS;
+    match error {
+        TavernError::UnsupportedFormat(_) => Some(unsupported),
+        TavernError::Parse(_) => Some(format),
+        TavernError::MissingChunk(_) => Some(no_data),
+        _ => None,
+    }
+}
+
+impl IssueLayout {
+    /// Returns the subfolder name for the error.
+    ///
+    /// Categories match the error message. Errors that match none go by
+    /// their kind, see [`TavernError`], and the rest go to the fallback.
+    pub fn folder_for_error(&self, error: &anyhow::Error) -> &str {
+        let message = format!("{:#}", error);
+        let category = self
+            .categories
+            .iter()
+            .find(|c| c.error_contains.iter().any(|x| message.contains(x)));
+        if let Some(category) = category {
+            return &category.name;
+        }
+        TavernError::find(error)
+            .and_then(builtin_folder)
+            .unwrap_or(&self.fallback)
+    }
+
+    /// Returns all subfolders that errors can go to, in the order they are
+    /// chosen, without repeats.
+    pub fn folders(&self) -> Vec<&str> {
+        let mut folders: Vec<&str> = Vec::new();
+        let names = self.categories.iter().map(|x| x.name.as_str());
+        for folder in names.chain(BUILTIN_FOLDERS).chain([&*self.fallback]) {
+            if !folders.contains(&folder) {
+                folders.push(folder);
+            }
+        }
+        folders
+    }
+}
+
+impl Config {
+    /// Reads config from the file, or returns defaults if there is no file.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let text = std::fs::read_to_string(path)?;
+        let config = toml::from_str(&text)
+            .with_context(|| format!("Invalid config {}", path.display()))?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_layout() {
+        let layout = IssueLayout::default();
+        let error = TavernError::Parse("Chara entry".to_string());
+        assert_eq!(layout.folder_for_error(&error.into()), "format");
+        let error = TavernError::MissingChunk("Chara".to_string());
+        let error = anyhow::Error::from(error).context("Could not read card");
+        assert_eq!(layout.folder_for_error(&error), "no_data");
+        let error = TavernError::UnsupportedFormat("spec v4".to_string());
+        assert_eq!(layout.folder_for_error(&error.into()), "unsupported");
+        let error = anyhow::anyhow!("Failed to parse, but not a card");
+        assert_eq!(layout.folder_for_error(&error), "other");
+        let folders = ["unsupported", "format", "no_data", "other"];
+        assert_eq!(layout.folders(), folders);
+    }
+
+    #[test]
+    fn test_partial_config() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+            output_path = "cards"
+            [issues]
+            fallback = "misc"
+            [[issues.categories]]
+            name = "broken_png"
+            error_contains = ["Invalid PNG signature", "CRC error"]
+            "#,
+        )?;
+        assert_eq!(config.input_path, PathBuf::from(DEFAULT_INPUT_PATH));
+        assert_eq!(config.output_path, PathBuf::from("cards"));
+        let layout = &config.issues;
+        let error = anyhow::anyhow!("CRC error");
+        assert_eq!(layout.folder_for_error(&error), "broken_png");
+        let error = TavernError::Parse("Chara entry".to_string());
+        assert_eq!(layout.folder_for_error(&error.into()), "format");
+        assert_eq!(layout.folder_for_error(&anyhow::anyhow!("?")), "misc");
+        Ok(())
+    }
+}
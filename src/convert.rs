@@ -0,0 +1,479 @@
+//! Conversion of cards between file formats.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use serde_json::Value;
+
+use crate::actions::pretty_json;
+use crate::card_formats::sillytavern::{
+    import_sillytavern_char, is_sillytavern_export,
+};
+use crate::card_formats::tavern_card_v2::TEXT_KEY_PNG;
+use crate::card_formats::tavern_card_v3::TEXT_KEY_CCV3;
+use crate::card_formats::{
+    decode_payload, read_card_json, validate_card_json, write_card_json,
+    write_ccv3_json,
+};
+use crate::charx::{self, AssetHandling};
+use crate::edit::{card_data_mut, is_v3_card};
+use crate::tools::{self, FileOptions};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum CardFormat {
+    /// PNG image with the card embedded
+    Png,
+    /// Card JSON
+    Json,
+    /// Card as YAML
+    Yaml,
+    /// CHARX archive
+    Charx,
+    /// WEBP image, without card data
+    Webp,
+}
+
+impl CardFormat {
+    /// Guesses the format from the file extension.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let extension = path
+            .extension()
+            .map(|x| x.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let format = match extension.as_str() {
+            "png" => CardFormat::Png,
+            "json" => CardFormat::Json,
+            "yaml" | "yml" => CardFormat::Yaml,
+            "charx" => CardFormat::Charx,
+            "webp" => CardFormat::Webp,
+            _ => bail!(
+                "Can not tell the format of {} from its extension, \
+                 set it explicitly",
+                path.display()
+            ),
+        };
+        Ok(format)
+    }
+}
+
+/// What to do with V3 group-only greetings when converting to V2.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum GroupGreetings {
+    /// Move them into alternate greetings
+    #[default]
+    Keep,
+    /// Drop them, with a warning
+    Drop,
+}
+
+/// Fields of V3 card data that V2 does not have.
+pub(crate) const V3_ONLY_FIELDS: &[&str] = &[
+    "assets",
+    "nickname",
+    "creator_notes_multilingual",
+    "source",
+    "creation_date",
+    "modification_date",
+];
+
+/// Card data and image, as read from a file in any format.
+struct Contents {
+    card: Option<Value>,
+    image: Option<Bytes>,
+    /// Files of the CHARX archive, by their path in it
+    files: BTreeMap<String, Vec<u8>>,
+    /// Whether the PNG input kept the card in a `ccv3` chunk too
+    has_ccv3: bool,
+}
+
+/// Converts card file from one format to another.
+///
+/// Formats not given are guessed from the file extensions. `image_path`
+/// gives the image for outputs that need one when the input has none, like
+/// JSON to PNG. Without it, JSON to PNG uses the base image, see
+/// [`tools::base_image`].
+///
+/// With `to_v2`, V3 cards are converted to V2, and their group-only
+/// greetings are handled as it says. Assets stored in a CHARX input are
+/// handled as `assets` says, see [`charx::dereference_assets`].
+#[allow(clippy::too_many_arguments)]
+pub fn convert_card(
+    input_path: &Path,
+    output_path: &Path,
+    format_in: Option<CardFormat>,
+    format_out: Option<CardFormat>,
+    image_path: Option<&Path>,
+    to_v2: Option<GroupGreetings>,
+    assets: AssetHandling,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let format_in = match format_in {
+        Some(format) => format,
+        None => CardFormat::from_path(input_path)?,
+    };
+    let format_out = match format_out {
+        Some(format) => format,
+        None => CardFormat::from_path(output_path)?,
+    };
+
+    let mut contents = read_contents(input_path, format_in, file_options)?;
+    if let Some(image_path) = image_path {
+        let image = tools::read_image_from_file(image_path, file_options)?;
+        contents.image = Some(tools::convert_to_png(&image)?);
+    }
+    if let (CardFormat::Charx, Some(card)) = (format_in, &mut contents.card) {
+        charx::dereference_assets(
+            card,
+            &mut contents.image,
+            &contents.files,
+            assets,
+            format_out,
+            output_path,
+            file_options,
+        )?;
+    }
+    if let (Some(group_greetings), Some(card)) = (to_v2, &mut contents.card) {
+        downgrade_to_v2(card, group_greetings)?;
+    }
+    write_contents(&contents, output_path, format_out, file_options)?;
+    println!(
+        "Converted {} ({:?}) to {} ({:?})",
+        input_path.display(),
+        format_in,
+        output_path.display(),
+        format_out
+    );
+    Ok(())
+}
+
+/// Turns V3 card JSON into V2. Fields that V2 does not have are removed.
+fn downgrade_to_v2(
+    card_json: &mut Value,
+    group_greetings: GroupGreetings,
+) -> Result<()> {
+    if !is_v3_card(card_json) {
+        println!("The card is not V3, leaving its version as it is");
+        return Ok(());
+    }
+    card_json["spec"] = Value::from("chara_card_v2");
+    card_json["spec_version"] = Value::from("2.0");
+    let data = card_data_mut(card_json)?;
+    let group_only = match data.remove("group_only_greetings") {
+        Some(Value::Array(greetings)) => greetings,
+        _ => Vec::new(),
+    };
+    if !group_only.is_empty() {
+        match group_greetings {
+            GroupGreetings::Keep => {
+                println!(
+                    "Moved {} group-only greetings into alternate greetings",
+                    group_only.len()
+                );
+                let greetings = data
+                    .entry("alternate_greetings")
+                    .or_insert_with(|| Value::Array(Vec::new()));
+                if !greetings.is_array() {
+                    *greetings = Value::Array(Vec::new());
+                }
+                greetings.as_array_mut().unwrap().extend(group_only);
+            }
+            GroupGreetings::Drop => println!(
+                "Warning: dropped {} group-only greetings, V2 cards have none",
+                group_only.len()
+            ),
+        }
+    }
+    for field in V3_ONLY_FIELDS {
+        data.remove(*field);
+    }
+    Ok(())
+}
+
+/// Returns true if the file holds a card: a PNG image with card data, a card
+/// JSON, or a CHARX archive. The format is told by the extension.
+///
+/// Only the card JSON is read, not the image or the assets, and only checked
+/// to look like a card, not against the spec. WEBP images carry no card data,
+/// so they are never cards.
+pub fn is_tavern_card(path: &Path, file_options: &FileOptions) -> bool {
+    read_card_quickly(path, file_options)
+        .is_ok_and(|json| looks_like_card(&json))
+}
+
+fn read_card_quickly(path: &Path, file_options: &FileOptions) -> Result<Value> {
+    match CardFormat::from_path(path)? {
+        CardFormat::Png => {
+            for key in [TEXT_KEY_PNG, TEXT_KEY_CCV3] {
+                let text =
+                    tools::read_text_chunk_from_file(path, key, file_options)?;
+                if let Some(text) = text {
+                    let json = decode_payload(&text)?;
+                    return Ok(serde_json::from_slice(&json)?);
+                }
+            }
+            bail!("{} has no card data", path.display())
+        }
+        CardFormat::Json => {
+            let text = tools::read_text_file(path)?;
+            Ok(serde_json::from_str(&text)?)
+        }
+        CardFormat::Charx => charx::read_charx_card(path),
+        format => bail!("{:?} files carry no card data", format),
+    }
+}
+
+/// Returns true for V2 and V3 cards, with a name in their data, and for V1
+/// cards, with a name at the top.
+fn looks_like_card(json: &Value) -> bool {
+    let has_name = |x: &Value| x.get("name").is_some_and(Value::is_string);
+    match json.get("data") {
+        Some(data) => json.get("spec").is_some() && has_name(data),
+        None => has_name(json) && json.get("first_mes").is_some(),
+    }
+}
+
+fn read_contents(
+    path: &Path,
+    format: CardFormat,
+    file_options: &FileOptions,
+) -> Result<Contents> {
+    match format {
+        CardFormat::Png => {
+            let image = tools::read_image_from_file(path, file_options)?;
+            let card = read_card_json(&image)?;
+            let has_ccv3 = tools::read_text_chunk(&image, TEXT_KEY_CCV3)?;
+            let image = tools::remove_text_chunk(&image, TEXT_KEY_PNG)?;
+            let image = tools::remove_text_chunk(&image, TEXT_KEY_CCV3)?;
+            Ok(Contents {
+                card: Some(card),
+                image: Some(image),
+                files: BTreeMap::new(),
+                has_ccv3: has_ccv3.is_some(),
+            })
+        }
+        CardFormat::Json => {
+            let text = tools::read_text_file(path)?;
+            let mut card = serde_json::from_str(&text).with_context(|| {
+                format!("Could not parse {}", path.display())
+            })?;
+            if is_sillytavern_export(&card) {
+                println!("Reading SillyTavern character export");
+                card = serde_json::to_value(import_sillytavern_char(&card)?)?;
+            }
+            Ok(Contents {
+                card: Some(card),
+                image: None,
+                files: BTreeMap::new(),
+                has_ccv3: false,
+            })
+        }
+        CardFormat::Webp => {
+            let image = tools::read_image_from_file(path, file_options)?;
+            let image = tools::convert_to_png(&image)?;
+            Ok(Contents {
+                card: None,
+                image: Some(image),
+                files: BTreeMap::new(),
+                has_ccv3: false,
+            })
+        }
+        CardFormat::Charx => {
+            let (card, files) = charx::read_charx(path)?;
+            Ok(Contents {
+                card: Some(card),
+                image: None,
+                files,
+                has_ccv3: false,
+            })
+        }
+        CardFormat::Yaml => {
+            let text = tools::read_text_file(path)?;
+            let card = serde_yaml::from_str(&text).with_context(|| {
+                format!("Could not parse {}", path.display())
+            })?;
+            Ok(Contents {
+                card: Some(card),
+                image: None,
+                files: BTreeMap::new(),
+                has_ccv3: false,
+            })
+        }
+    }
+}
+
+fn write_contents(
+    contents: &Contents,
+    path: &Path,
+    format: CardFormat,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let need_card = || {
+        contents.card.as_ref().context(
+            "The input has no card data. Convert from a PNG or JSON card",
+        )
+    };
+    match format {
+        CardFormat::Png => {
+            let card = need_card()?;
+            validate_card_json(card)?;
+            let image =
+                contents.image.clone().unwrap_or_else(tools::base_image);
+            let mut card_image = write_card_json(card, &image, file_options)?;
+            // The ccv3 chunk holds the card as V3, so cards turned into V2
+            // are written without it
+            if contents.has_ccv3 && is_v3_card(card) {
+                card_image = write_ccv3_json(card, &card_image, file_options)?;
+            }
+            tools::write_image_to_file(&card_image, path)?;
+        }
+        CardFormat::Json => {
+            let text = serde_json::to_string(need_card()?)?;
+            tools::write_text_file(path, &pretty_json(&text)?)?;
+        }
+        CardFormat::Webp => {
+            let Some(image) = &contents.image else {
+                bail!(
+                    "The input has no image. Give the image to use \
+                     with --image <file>"
+                );
+            };
+            if contents.card.is_some() {
+                println!("Card data is not kept: WEBP holds only the image");
+            }
+            let webp_image = tools::convert_to_webp(image, None)?;
+            tools::write_image_to_file(&webp_image, path)?;
+        }
+        CardFormat::Yaml => {
+            let text = serde_yaml::to_string(need_card()?)?;
+            tools::write_text_file(path, &text)?;
+        }
+        CardFormat::Charx => {
+            bail!("Writing {:?} cards is not supported yet", format)
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_from_path() -> Result<()> {
+        assert_eq!(CardFormat::from_path(Path::new("a.PNG"))?, CardFormat::Png);
+        assert_eq!(
+            CardFormat::from_path(Path::new("a.yml"))?,
+            CardFormat::Yaml
+        );
+        assert!(CardFormat::from_path(Path::new("a.txt")).is_err());
+        assert!(CardFormat::from_path(Path::new("card")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_looks_like_card() {
+        use serde_json::json;
+        let v2 = json!({"spec": "chara_card_v2", "data": {"name": "Test"}});
+        assert!(looks_like_card(&v2));
+        let v1 = json!({"name": "Test", "first_mes": "Hi"});
+        assert!(looks_like_card(&v1));
+        assert!(!looks_like_card(&json!({"data": {"name": "Test"}})));
+        assert!(!looks_like_card(&json!({"name": "package"})));
+        assert!(!looks_like_card(&json!([1, 2])));
+    }
+
+    #[test]
+    fn test_downgrade_to_v2() -> Result<()> {
+        let card = serde_json::json!({
+            "spec": "chara_card_v3",
+            "spec_version": "3.0",
+            "data": {
+                "name": "Test",
+                "alternate_greetings": ["Hi"],
+                "group_only_greetings": ["Hi all"],
+                "assets": []
+            }
+        });
+        let mut kept = card.clone();
+        downgrade_to_v2(&mut kept, GroupGreetings::Keep)?;
+        assert_eq!(kept["spec"], "chara_card_v2");
+        assert_eq!(
+            kept["data"]["alternate_greetings"],
+            serde_json::json!(["Hi", "Hi all"])
+        );
+        assert!(kept["data"].get("group_only_greetings").is_none());
+        assert!(kept["data"].get("assets").is_none());
+        validate_card_json(&kept)?;
+
+        let mut dropped = card.clone();
+        downgrade_to_v2(&mut dropped, GroupGreetings::Drop)?;
+        assert_eq!(
+            dropped["data"]["alternate_greetings"],
+            serde_json::json!(["Hi"])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_convert_rewrites_ccv3() -> Result<()> {
+        let dir = std::env::temp_dir()
+            .join(format!("taverntools_ccv3_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let card = serde_json::json!({
+            "spec": "chara_card_v3",
+            "spec_version": "3.0",
+            "data": { "name": "Test", "group_only_greetings": ["Hi all"] }
+        });
+        let files = FileOptions::default();
+        let image =
+            write_card_json(&card, &tools::get_default_image(), &files)?;
+        let image = write_ccv3_json(&card, &image, &files)?;
+        let input = dir.join("v3.png");
+        tools::write_image_to_file(&image, &input)?;
+        let ccv3 = |path: &Path| {
+            let image = tools::read_image_from_file(path, &files)?;
+            tools::read_text_chunk(&image, TEXT_KEY_CCV3)
+        };
+
+        let output = dir.join("v3_copy.png");
+        let keep = AssetHandling::Keep;
+        convert_card(&input, &output, None, None, None, None, keep, &files)?;
+        assert!(ccv3(&output)?.is_some());
+        let output = dir.join("v2.png");
+        let to_v2 = Some(GroupGreetings::Keep);
+        convert_card(&input, &output, None, None, None, to_v2, keep, &files)?;
+        assert_eq!(ccv3(&output)?, None);
+        let v2 =
+            read_card_json(&tools::read_image_from_file(&output, &files)?)?;
+        assert_eq!(v2["spec"], "chara_card_v2");
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_yaml_round_trip() -> Result<()> {
+        let dir = std::env::temp_dir()
+            .join(format!("taverntools_yaml_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let card = serde_json::json!({
+            "spec": "chara_card_v2",
+            "spec_version": "2.0",
+            "data": {"name": "Test", "mes_example": "<START>\n{{user}}: Hi"}
+        });
+        let contents = Contents {
+            card: Some(card.clone()),
+            image: None,
+            files: BTreeMap::new(),
+            has_ccv3: false,
+        };
+        let path = dir.join("card.yaml");
+        let files = FileOptions::default();
+        write_contents(&contents, &path, CardFormat::Yaml, &files)?;
+        let read = read_contents(&path, CardFormat::Yaml, &files)?;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(read.card, Some(card));
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+//! Selection of cards by their V3 dates.
+
+use std::path::PathBuf;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde_json::Value;
+
+use crate::card_formats::decode_payload;
+use crate::card_formats::tavern_card_v2::TEXT_KEY_PNG;
+use crate::tools::{self, FileOptions};
+
+/// Date range for commands that process many cards.
+#[derive(clap::Args, Debug, Default, Clone)]
+pub struct DateFilter {
+    /// Only process cards modified on this date or later, like 2024-01-01
+    #[arg(long)]
+    pub since: Option<NaiveDate>,
+    /// Only process cards modified on this date or earlier
+    #[arg(long)]
+    pub until: Option<NaiveDate>,
+    /// With --since or --until, skip cards without dates, like V2 cards
+    #[arg(long)]
+    pub require_dates: bool,
+}
+
+impl DateFilter {
+    fn is_active(&self) -> bool {
+        self.since.is_some() || self.until.is_some()
+    }
+
+    /// Returns true if the card date is in the range. Cards without date
+    /// match unless dates are required.
+    pub fn matches(&self, card_json: &Value) -> bool {
+        let Some(date) = card_date(card_json) else {
+            return !self.require_dates;
+        };
+        let date = date.date_naive();
+        self.since.is_none_or(|since| date >= since)
+            && self.until.is_none_or(|until| date <= until)
+    }
+}
+
+/// Returns when the card was last modified, or created if it was never
+/// modified, from V3 `modification_date` and `creation_date`.
+pub fn card_date(card_json: &Value) -> Option<DateTime<Utc>> {
+    let data = card_json.get("data")?;
+    ["modification_date", "creation_date"]
+        .iter()
+        .filter_map(|field| parse_timestamp(data.get(*field)?))
+        .next()
+}
+
+/// Reads Unix timestamp, in seconds or milliseconds, as number or text.
+fn parse_timestamp(value: &Value) -> Option<DateTime<Utc>> {
+    let timestamp = match value {
+        Value::Number(x) => x.as_f64()? as i64,
+        Value::String(x) => x.trim().parse::<f64>().ok()? as i64,
+        _ => return None,
+    };
+    // Seconds would be far in the future at this size
+    if timestamp > 100_000_000_000 {
+        return DateTime::from_timestamp_millis(timestamp);
+    }
+    // Zero means the date was never set
+    if timestamp <= 0 {
+        return None;
+    }
+    DateTime::from_timestamp(timestamp, 0)
+}
+
+/// Keeps only the cards within the date range of the filter.
+///
+/// Cards that can not be read are kept, so that the command reports them.
+pub fn filter_cards(
+    paths: Vec<PathBuf>,
+    filter: &DateFilter,
+    file_options: &FileOptions,
+) -> Vec<PathBuf> {
+    if !filter.is_active() {
+        return paths;
+    }
+    let total = paths.len();
+    let kept: Vec<PathBuf> = paths
+        .into_iter()
+        .filter(|path| {
+            let text = tools::read_text_chunk_from_file(
+                path,
+                TEXT_KEY_PNG,
+                file_options,
+            );
+            let card_json = text
+                .ok()
+                .flatten()
+                .and_then(|text| decode_payload(&text).ok())
+                .and_then(|data| serde_json::from_slice(&data).ok());
+            card_json.is_none_or(|card_json| filter.matches(&card_json))
+        })
+        .collect();
+    if kept.len() < total {
+        println!(
+            "Skipping {} cards outside the date range",
+            total - kept.len()
+        );
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_date_filter() {
+        let filter = DateFilter {
+            since: NaiveDate::from_ymd_opt(2024, 1, 1),
+            until: NaiveDate::from_ymd_opt(2024, 12, 31),
+            require_dates: false,
+        };
+        // 2024-06-01 in seconds and milliseconds
+        let card = json!({ "data": { "modification_date": 1717200000 } });
+        assert!(filter.matches(&card));
+        let card = json!({ "data": { "creation_date": 1717200000000u64 } });
+        assert!(filter.matches(&card));
+        // 2023-06-01
+        let card = json!({ "data": { "modification_date": "1685577600" } });
+        assert!(!filter.matches(&card));
+
+        let v2_card = json!({ "data": { "name": "A" } });
+        assert!(filter.matches(&v2_card));
+        let filter = DateFilter { require_dates: true, ..filter };
+        assert!(!filter.matches(&v2_card));
+    }
+}
@@ -3,11 +3,16 @@
 use std::path::Path;
 
 use anyhow::{bail, Result};
+use glob::Pattern;
 use log::info;
 
 use crate::{
-    card_formats::tavern_card_v2::TavernCardV2,
-    tools::{self, read_image_from_file},
+    card_formats::{tavern_card_v2::TavernCardV2, CardFile},
+    dates::{self, DateFilter},
+    diff,
+    edit::edit_card_file,
+    tools::{self, read_image_from_file, FileOptions},
+    wrap::PrintOptions,
 };
 
 /// Remove asterisks from text
@@ -63,43 +68,43 @@ fn remove_paired_asterisks(input_str: &str) -> String {
     res
 }
 
+/// Fields left as they are: instructions to the model, not roleplay text.
+const KEPT_FIELDS: [&str; 2] = ["system_prompt", "post_history_instructions"];
+
 /// Removes asterisks from relevant fields of tavern card
 pub fn deasterisk_tavern_card(tavern_card: &mut TavernCardV2) {
-    let d = &mut tavern_card.data;
-    let de8 = |x: &mut Option<String>| {
-        let t = x.as_ref().map(|y| remove_paired_asterisks(&y));
-        *x = t;
-    };
-    de8(&mut d.description);
-    de8(&mut d.personality);
-    de8(&mut d.scenario);
-    de8(&mut d.first_mes);
-    de8(&mut d.mes_example);
-    if let Some(cb) = &mut d.character_book {
-        for e in &mut cb.entries {
-            e.content = remove_paired_asterisks(&e.content);
+    tavern_card.for_each_text_field_mut(|field, text| {
+        if !KEPT_FIELDS.contains(&field) {
+            *text = remove_paired_asterisks(text);
         }
-    }
-    if let Some(ag) = &mut d.alternate_greetings {
-        for g in ag.iter_mut() {
-            *g = remove_paired_asterisks(g);
-        }
-    }
+    });
 }
 
 // Opens file, applies deasterisk to it, saves in new location.
+//
+// With `preview`, prints how the fields would change instead, and writes
+// nothing.
 pub fn deasterisk_tavern_file(
     png_path: &Path,
     auto_overwrite: bool,
+    preview: bool,
+    file_options: &FileOptions,
+    print: &PrintOptions,
 ) -> Result<()> {
     println!("Deasterisk file: {}", &png_path.display());
-    let image_data = read_image_from_file(png_path)?;
+    let image_data = read_image_from_file(png_path, file_options)?;
     let mut card = TavernCardV2::from_png_image(&image_data)?;
     println!(
         "Character name is {}",
         card.data.name.to_owned().unwrap_or_else(|| "".to_string())
     );
+    let old_json = serde_json::to_value(&card)?;
     deasterisk_tavern_card(&mut card);
+    if preview {
+        let new_json = serde_json::to_value(&card)?;
+        diff::print_changes(&diff::diff_cards(&old_json, &new_json), print);
+        return Ok(());
+    }
 
     info!("\nCHARACTER INFO:\n{:#?}", &card.data);
 
@@ -134,12 +139,69 @@ pub fn deasterisk_tavern_file(
     };
 
     // Save image to new name
-    let new_image = card.into_png_image()?;
-    tools::write_image_to_file(&new_image, &new_path)?;
+    edit_card_file(png_path, Some(&new_path), file_options, |card_json| {
+        *card_json = serde_json::to_value(&card)?;
+        Ok(())
+    })?;
     println!("Done");
     Ok(())
 }
 
+/// Applies deasterisk to every PNG card in the folder, or to every file
+/// matching the glob pattern.
+///
+/// Files already named "de8.*" are skipped. With `fail_fast`, stops at the
+/// first failed card. Otherwise processes all cards and returns error at the
+/// end if any of them failed. Only cards within the range of `dates` are
+/// processed. With `preview`, only prints how the cards would change. Files
+/// matching `exclude` patterns are skipped, see [`tools::exclude_files`].
+#[allow(clippy::too_many_arguments)]
+pub fn deasterisk_directory(
+    dir_path: &Path,
+    auto_overwrite: bool,
+    preview: bool,
+    fail_fast: bool,
+    follow_symlinks: bool,
+    dates: &DateFilter,
+    exclude: &[Pattern],
+    file_options: &FileOptions,
+    print: &PrintOptions,
+) -> Result<()> {
+    let png_paths = tools::list_input_files(dir_path, follow_symlinks)?;
+    let png_paths = tools::exclude_files(png_paths, exclude);
+    let png_paths: Vec<_> = dates::filter_cards(png_paths, dates, file_options)
+        .into_iter()
+        .filter(|path| {
+            !path.file_name().unwrap().to_string_lossy().starts_with("de8.")
+        })
+        .collect();
+
+    let mut failed = 0;
+    for png_path in &png_paths {
+        if let Err(e) = deasterisk_tavern_file(
+            png_path,
+            auto_overwrite,
+            preview,
+            file_options,
+            print,
+        ) {
+            let e = e.context(format!(
+                "Failed to deasterisk {}",
+                png_path.display()
+            ));
+            if fail_fast {
+                return Err(e);
+            }
+            println!("{:#}", e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} cards failed", failed, png_paths.len());
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -188,7 +250,7 @@ mod tests {
         );
     }
 
-    use crate::tavern_card_v2::*;
+    use crate::card_formats::tavern_card_v2::*;
 
     #[test]
     fn test_deasterisk_tavern_card() {
@@ -202,13 +264,18 @@ mod tests {
         card.data.first_mes = Some(String::from("Only *paired* asterisks."));
         card.data.mes_example =
             Some(String::from("Only **unpaired** asterisks."));
+        card.data.system_prompt = Some(String::from("Keep *emphasis*."));
         card.data.character_book = Some(CharacterBook::default());
         //card.data.character_book.unwrap().entries
-        let mut entry1 = CharacterBookEntry::default();
-        entry1.content = String::from("*Example text of no importance*");
+        let entry1 = CharacterBookEntry {
+            content: String::from("*Example text of no importance*"),
+            ..Default::default()
+        };
 
-        let mut entry2 = CharacterBookEntry::default();
-        entry2.content = String::from("**Example text of no importance**");
+        let entry2 = CharacterBookEntry {
+            content: String::from("**Example text of no importance**"),
+            ..Default::default()
+        };
 
         card.data.character_book.as_mut().unwrap().entries.push(entry1);
         card.data.character_book.as_mut().unwrap().entries.push(entry2);
@@ -235,6 +302,10 @@ mod tests {
             card.data.mes_example,
             Some(String::from("Only **unpaired** asterisks."))
         );
+        assert_eq!(
+            card.data.system_prompt,
+            Some(String::from("Keep *emphasis*."))
+        );
         assert_eq!(
             card.data.character_book.as_ref().unwrap().entries[0].content,
             String::from("Example text of no importance")
@@ -0,0 +1,229 @@
+//! Field-by-field comparison of cards.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+use crate::card_formats::read_card_json;
+use crate::color::Color;
+use crate::tools::{self, FileOptions};
+use crate::wrap::PrintOptions;
+
+/// A card field that differs between two cards.
+#[derive(Debug, PartialEq)]
+pub struct FieldChange {
+    pub field: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+/// Compares the `data` blocks of two cards field by field.
+///
+/// Fields are reported in alphabetical order. A field set to `null` is
+/// treated the same as a missing one.
+pub fn diff_cards(old: &Value, new: &Value) -> Vec<FieldChange> {
+    let get_field = |card: &Value, field: &str| {
+        card.get("data")
+            .and_then(|data| data.get(field))
+            .filter(|x| !x.is_null())
+            .cloned()
+    };
+    let field_names = |card: &Value| {
+        card.get("data")
+            .and_then(|data| data.as_object())
+            .map(|data| data.keys().cloned().collect::<Vec<String>>())
+            .unwrap_or_default()
+    };
+    let fields: BTreeSet<String> =
+        field_names(old).into_iter().chain(field_names(new)).collect();
+
+    let mut changes = Vec::new();
+    for field in fields {
+        let old_value = get_field(old, &field);
+        let new_value = get_field(new, &field);
+        if old_value != new_value {
+            changes.push(FieldChange { field, old: old_value, new: new_value });
+        }
+    }
+    changes
+}
+
+/// Prints changes, with a line diff of each changed field.
+pub fn print_changes(changes: &[FieldChange], print: &PrintOptions) {
+    if changes.is_empty() {
+        println!("No changes");
+        return;
+    }
+    for change in changes {
+        println!("~ {}", print.paint(&change.field, Color::Bold));
+        let old_text = value_to_text(&change.old);
+        let new_text = value_to_text(&change.new);
+        let old_lines: Vec<&str> = old_text.lines().collect();
+        let new_lines: Vec<&str> = new_text.lines().collect();
+        for line in diff_lines(&old_lines, &new_lines) {
+            let (text, line_color) = match line {
+                DiffLine::Same(_) => continue,
+                DiffLine::Removed(x) => (format!("  - {}", x), Color::Red),
+                DiffLine::Added(x) => (format!("  + {}", x), Color::Green),
+            };
+            println!("{}", print.paint(&text, line_color));
+        }
+    }
+    println!("Changed fields: {}", changes.len());
+}
+
+/// Prints only which fields changed and how much, without their content.
+pub fn print_summary(changes: &[FieldChange], print: &PrintOptions) {
+    if changes.is_empty() {
+        println!("No changes");
+        return;
+    }
+    for change in changes {
+        let field = print.paint(&change.field, Color::Bold);
+        let old_text = value_to_text(&change.old);
+        let new_text = value_to_text(&change.new);
+        let count = |text: &str| (text.chars().count(), text.lines().count());
+        let (old_chars, old_lines) = count(&old_text);
+        let (new_chars, new_lines) = count(&new_text);
+        let sign = match (&change.old, &change.new) {
+            (None, _) => "+",
+            (_, None) => "-",
+            _ => "~",
+        };
+        println!(
+            "{} {}: {} -> {} characters ({:+}), {} -> {} lines ({:+})",
+            sign,
+            field,
+            old_chars,
+            new_chars,
+            new_chars as i64 - old_chars as i64,
+            old_lines,
+            new_lines,
+            new_lines as i64 - old_lines as i64
+        );
+    }
+    println!("Changed fields: {}", changes.len());
+}
+
+/// Prints differences between two card files.
+///
+/// With `fields`, only those fields are compared. With `summary`, only the
+/// sizes of changes are printed, see [`print_summary`].
+pub fn diff_card_files(
+    old_path: &Path,
+    new_path: &Path,
+    fields: &[String],
+    summary: bool,
+    file_options: &FileOptions,
+    print: &PrintOptions,
+) -> Result<()> {
+    let read_json = |path: &Path| -> Result<Value> {
+        let image = tools::read_image_from_file(path, file_options)?;
+        read_card_json(&image)
+            .with_context(|| format!("Could not read {}", path.display()))
+    };
+    let mut changes = diff_cards(&read_json(old_path)?, &read_json(new_path)?);
+    if !fields.is_empty() {
+        changes.retain(|change| fields.contains(&change.field));
+    }
+    if summary {
+        print_summary(&changes, print);
+    } else {
+        print_changes(&changes, print);
+    }
+    Ok(())
+}
+
+/// Turns field value into text suitable for a line diff.
+fn value_to_text(value: &Option<Value>) -> String {
+    match value {
+        None => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(v) => serde_json::to_string_pretty(v).unwrap_or_default(),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DiffLine<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Computes line diff using the longest common subsequence.
+pub fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<DiffLine<'a>> {
+    // lcs[i][j] is the LCS length of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut result = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            result.push(DiffLine::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            result.push(DiffLine::Removed(old[i]));
+            i += 1;
+        } else {
+            result.push(DiffLine::Added(new[j]));
+            j += 1;
+        }
+    }
+    result.extend(old[i..].iter().map(|x| DiffLine::Removed(x)));
+    result.extend(new[j..].iter().map(|x| DiffLine::Added(x)));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_diff_cards() {
+        let old = json!({ "data": {
+            "name": "Test name",
+            "description": "Line 1\nLine 2",
+            "tags": ["tag1"],
+            "creator": null
+        }});
+        let new = json!({ "data": {
+            "name": "Test name",
+            "description": "Line 1\nLine 3",
+            "scenario": "Test scenario"
+        }});
+        let changes = diff_cards(&old, &new);
+        let fields: Vec<&str> =
+            changes.iter().map(|x| x.field.as_str()).collect();
+        assert_eq!(fields, vec!["description", "scenario", "tags"]);
+        assert_eq!(changes[2].new, None);
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        let old = vec!["a", "b", "c"];
+        let new = vec!["a", "c", "d"];
+        assert_eq!(
+            diff_lines(&old, &new),
+            vec![
+                DiffLine::Same("a"),
+                DiffLine::Removed("b"),
+                DiffLine::Same("c"),
+                DiffLine::Added("d"),
+            ]
+        );
+    }
+}
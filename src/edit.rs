@@ -0,0 +1,1043 @@
+//! Commands that edit card data.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use log::info;
+use serde_json::Value;
+
+use crate::card_formats::tavern_card_v2::TEXT_KEY_PNG;
+use crate::card_formats::tavern_card_v3::TEXT_KEY_CCV3;
+use crate::card_formats::{
+    is_double_encoded, read_card_json, validate_card_json, write_card_json,
+    write_ccv3_json,
+};
+use crate::convert::V3_ONLY_FIELDS;
+use crate::error::TavernError;
+use crate::tools::{self, FileOptions};
+
+pub use crate::card_formats::card_data_mut;
+
+/// Applies `edit` to the card JSON and writes the edited card.
+///
+/// Writes into `output_path` if given, otherwise overwrites the card. The
+/// card is not written if `edit` fails, or if the edited JSON is not a valid
+/// card anymore.
+pub fn edit_card_file<F>(
+    png_path: &Path,
+    output_path: Option<&Path>,
+    file_options: &FileOptions,
+    edit: F,
+) -> Result<()>
+where
+    F: FnOnce(&mut Value) -> Result<()>,
+{
+    edit_card_and_image(png_path, output_path, file_options, |card_json, _| {
+        edit(card_json)
+    })
+}
+
+/// Like [`edit_card_file`], but `edit` may also change the image, for
+/// example to add or remove PNG chunks. The card data is written last.
+///
+/// When the card has a V3 `ccv3` chunk, it is written from the edited card
+/// too, even if `edit` replaced the image. Frontends that read `ccv3` first
+/// would show the card as it was otherwise.
+///
+/// With `stamp_dates` options, dates of V3 cards are stamped after the
+/// edit, see [`stamp_dates`].
+pub fn edit_card_and_image<F>(
+    png_path: &Path,
+    output_path: Option<&Path>,
+    file_options: &FileOptions,
+    edit: F,
+) -> Result<()>
+where
+    F: FnOnce(&mut Value, &mut Bytes) -> Result<()>,
+{
+    let mut image_data = tools::read_image_from_file(png_path, file_options)?;
+    let mut card_json = read_card_json(&image_data)?;
+    let had_ccv3 =
+        tools::read_text_chunk(&image_data, TEXT_KEY_CCV3)?.is_some();
+    edit(&mut card_json, &mut image_data)?;
+    if file_options.stamp_dates && is_v3_card(&card_json) {
+        stamp_dates(&mut card_json)?;
+    }
+    validate_card_json(&card_json)?;
+
+    let mut new_image = write_card_json(&card_json, &image_data, file_options)?;
+    if had_ccv3 {
+        new_image = write_ccv3_json(&card_json, &new_image, file_options)?;
+    }
+    let output_path = output_path.unwrap_or(png_path);
+    tools::write_image_to_file(&new_image, output_path)?;
+    info!("Edited card written to {}", output_path.display());
+    Ok(())
+}
+
+/// Replaces the image of the card, keeping the card data.
+///
+/// The new image may be in any format the tool reads. Card chunks it
+/// already has are removed, and the card data is written onto it, see
+/// [`edit_card_and_image`]. Writes into `output_path` if given, otherwise
+/// overwrites the card.
+pub fn set_avatar(
+    png_path: &Path,
+    new_image_path: &Path,
+    output_path: Option<&Path>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let new_image = tools::read_image_from_file(new_image_path, file_options)?;
+    let mut new_image =
+        tools::convert_to_png(&new_image).with_context(|| {
+            format!("Could not read {}", new_image_path.display())
+        })?;
+    let (width, height) = tools::png_dimensions(&new_image)?;
+    info!("New image is {}x{}", width, height);
+    println!("New image is {}x{} pixels", width, height);
+    for key in [TEXT_KEY_PNG, TEXT_KEY_CCV3] {
+        if tools::read_text_chunk(&new_image, key)?.is_some() {
+            println!("Removing {} chunk the new image already had", key);
+            new_image = tools::remove_text_chunk(&new_image, key)?;
+        }
+    }
+    edit_card_and_image(png_path, output_path, file_options, |_, image| {
+        *image = new_image;
+        Ok(())
+    })?;
+    let output_path = output_path.unwrap_or(png_path);
+    println!("Card written to {}", output_path.display());
+    Ok(())
+}
+
+/// Rewrites card whose data was base64-encoded twice by some buggy tool, so
+/// that it is encoded once.
+pub fn repair_card(
+    png_path: &Path,
+    output_path: Option<&Path>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let image_data = tools::read_image_from_file(png_path, file_options)?;
+    let raw_text = tools::read_text_chunk(&image_data, TEXT_KEY_PNG)?
+        .ok_or_else(|| TavernError::MissingChunk(TEXT_KEY_PNG.to_string()))?;
+    if !is_double_encoded(&raw_text) {
+        println!("Nothing to repair");
+        return Ok(());
+    }
+    // Reading decodes both layers, writing encodes once
+    edit_card_file(png_path, output_path, file_options, |_| Ok(()))?;
+    println!("Card data was base64-encoded twice, now it is encoded once");
+    Ok(())
+}
+
+/// Returns the canonical `spec` and `spec_version` for the card, judged by
+/// its fields rather than its labels: cards with any V3-only field, like
+/// `assets` or `group_only_greetings`, are V3, others are V2.
+pub fn detect_spec(card_json: &Value) -> Result<(&'static str, &'static str)> {
+    let data = card_json
+        .get("data")
+        .and_then(|x| x.as_object())
+        .context("Card has no data block, V1 cards can not be fixed")?;
+    let is_v3 = V3_ONLY_FIELDS
+        .iter()
+        .chain(["group_only_greetings"].iter())
+        .any(|field| data.get(*field).is_some_and(|x| !x.is_null()));
+    Ok(if is_v3 { ("chara_card_v3", "3.0") } else { ("chara_card_v2", "2.0") })
+}
+
+/// Sets `spec` and `spec_version` of the card JSON to the values that fit
+/// its fields. Returns descriptions of the corrections made.
+pub(crate) fn fix_spec_fields(card_json: &mut Value) -> Result<Vec<String>> {
+    let (spec, spec_version) = detect_spec(card_json)?;
+    let mut fixed = Vec::new();
+    for (field, correct) in [("spec", spec), ("spec_version", spec_version)] {
+        let old = card_json.get(field).cloned().unwrap_or(Value::Null);
+        if old.as_str() == Some(correct) {
+            continue;
+        }
+        fixed.push(format!("{}: {} -> \"{}\"", field, old, correct));
+        card_json[field] = Value::from(correct);
+    }
+    Ok(fixed)
+}
+
+/// Corrects `spec` and `spec_version` of the card to match the fields the
+/// card actually has, and prints what was corrected.
+pub fn fix_spec(
+    png_path: &Path,
+    output_path: Option<&Path>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let image_data = tools::read_image_from_file(png_path, file_options)?;
+    let mut card_json = read_card_json(&image_data)?;
+    if fix_spec_fields(&mut card_json)?.is_empty() {
+        println!("spec and spec_version are already correct");
+        return Ok(());
+    }
+    edit_card_file(png_path, output_path, file_options, |card_json| {
+        for fix in fix_spec_fields(card_json)? {
+            println!("Fixed {}", fix);
+        }
+        Ok(())
+    })
+}
+
+/// Removes alternate greetings that repeat an earlier one, or `first_mes`,
+/// keeping the first of each. Returns the number of greetings removed.
+fn dedupe_greetings(card_json: &mut Value) -> Result<usize> {
+    let data = card_data_mut(card_json)?;
+    let mut seen: Vec<Value> =
+        data.get("first_mes").cloned().into_iter().collect();
+    let Some(Value::Array(greetings)) = data.get_mut("alternate_greetings")
+    else {
+        return Ok(0);
+    };
+    let count = greetings.len();
+    greetings.retain(|greeting| {
+        if seen.contains(greeting) {
+            return false;
+        }
+        seen.push(greeting.clone());
+        true
+    });
+    Ok(count - greetings.len())
+}
+
+/// Trims whitespace from all text fields of card data, and from the texts
+/// of its lists, lowercases tags, dropping the ones that become duplicates,
+/// and collapses runs of spaces in `name`. With `dedupe`, also removes
+/// duplicate greetings, see [`dedupe_greetings`]. `extensions` and the
+/// lorebook are left alone. Returns descriptions of the changes made.
+fn tidy_fields(card_json: &mut Value, dedupe: bool) -> Result<Vec<String>> {
+    let data = card_data_mut(card_json)?;
+    let mut changes = Vec::new();
+    for (field, value) in data.iter_mut() {
+        match value {
+            Value::String(text) if text.trim() != text => {
+                *text = text.trim().to_string();
+                changes.push(format!("Trimmed whitespace in {}", field));
+            }
+            Value::Array(items) => {
+                let mut trimmed = 0;
+                for item in items.iter_mut() {
+                    if let Value::String(text) = item {
+                        if text.trim() != text {
+                            *text = text.trim().to_string();
+                            trimmed += 1;
+                        }
+                    }
+                }
+                if trimmed > 0 {
+                    changes.push(format!(
+                        "Trimmed whitespace in {} of {}",
+                        trimmed, field
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(Value::String(name)) = data.get_mut("name") {
+        let collapsed = name.split(' ').filter(|x| !x.is_empty());
+        let collapsed = collapsed.collect::<Vec<&str>>().join(" ");
+        if collapsed != *name {
+            changes.push(format!(
+                "Collapsed spaces in name: \"{}\" -> \"{}\"",
+                name, collapsed
+            ));
+            *name = collapsed;
+        }
+    }
+
+    if let Some(Value::Array(tags)) = data.get_mut("tags") {
+        let mut seen = Vec::new();
+        tags.retain_mut(|tag| {
+            let Value::String(text) = tag else { return true };
+            let lower = text.to_lowercase();
+            if seen.contains(&lower) {
+                changes.push(format!("Removed duplicate tag \"{}\"", text));
+                return false;
+            }
+            if lower != *text {
+                changes.push(format!(
+                    "Lowercased tag \"{}\" -> \"{}\"",
+                    text, lower
+                ));
+                *text = lower.clone();
+            }
+            seen.push(lower);
+            true
+        });
+    }
+
+    if dedupe {
+        let removed = dedupe_greetings(card_json)?;
+        if removed > 0 {
+            changes.push(format!("Removed {} duplicate greetings", removed));
+        }
+    }
+    Ok(changes)
+}
+
+/// Tidies the card, see [`tidy_fields`], printing every change. The card is
+/// not written if there is nothing to tidy.
+pub fn tidy_card(
+    png_path: &Path,
+    output_path: Option<&Path>,
+    dedupe: bool,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let image_data = tools::read_image_from_file(png_path, file_options)?;
+    if tidy_fields(&mut read_card_json(&image_data)?, dedupe)?.is_empty() {
+        println!("Nothing to tidy");
+        return Ok(());
+    }
+    edit_card_file(png_path, output_path, file_options, |card_json| {
+        for change in tidy_fields(card_json, dedupe)? {
+            println!("{}", change);
+        }
+        Ok(())
+    })
+}
+
+/// Tidies every card in the folder in place, see [`tidy_card`].
+///
+/// Returns error at the end if any card failed.
+pub fn tidy_directory(
+    dir_path: &Path,
+    dedupe: bool,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let png_paths = tools::list_png_files(dir_path, false)?;
+    let mut failed = 0;
+    for png_path in &png_paths {
+        println!("{}:", png_path.display());
+        if let Err(e) = tidy_card(png_path, None, dedupe, file_options) {
+            println!("Failed: {:#}", e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} cards failed", failed, png_paths.len());
+    }
+    Ok(())
+}
+
+/// Returns true if the card JSON declares V3 specification.
+pub fn is_v3_card(card_json: &Value) -> bool {
+    card_json.get("spec").and_then(|x| x.as_str()) == Some("chara_card_v3")
+}
+
+/// Sets V3 `modification_date` to current time, as Unix timestamp.
+pub fn stamp_modification_date(card_json: &mut Value) -> Result<()> {
+    let now = chrono::Utc::now().timestamp();
+    card_data_mut(card_json)?
+        .insert("modification_date".to_string(), Value::from(now));
+    Ok(())
+}
+
+/// Sets V3 `modification_date` to current time, and `creation_date` too if
+/// the card has none, as Unix timestamps in seconds.
+fn stamp_dates(card_json: &mut Value) -> Result<()> {
+    stamp_modification_date(card_json)?;
+    let data = card_data_mut(card_json)?;
+    let has_creation_date = data
+        .get("creation_date")
+        .and_then(|x| x.as_f64())
+        .is_some_and(|x| x > 0.0);
+    if !has_creation_date {
+        let now = data["modification_date"].clone();
+        data.insert("creation_date".to_string(), now);
+    }
+    Ok(())
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum VersionPart {
+    Major,
+    Minor,
+    Patch,
+}
+
+/// Increments a part of semver-style version.
+///
+/// Missing parts count as zero, so "2" becomes "2.0.1" with a patch bump.
+/// Text before the first digit (like "v") is kept, text after the version
+/// numbers (like "-beta") is dropped. Versions without any numbers are
+/// replaced by a fresh version. Fails if the part is too large to increment.
+pub fn bump_version(version: &str, part: VersionPart) -> Result<String> {
+    let start = version.find(|c: char| c.is_ascii_digit());
+    let (prefix, rest) = match start {
+        Some(start) => version.split_at(start),
+        None => ("", ""),
+    };
+    let mut numbers: Vec<u64> = Vec::new();
+    for number_text in rest.split('.') {
+        let digits: String =
+            number_text.chars().take_while(|c| c.is_ascii_digit()).collect();
+        let Ok(number) = digits.parse() else { break };
+        numbers.push(number);
+        // Stop at the number with a suffix, like "0-beta"
+        if digits.len() != number_text.len() || numbers.len() == 3 {
+            break;
+        }
+    }
+    numbers.resize(3, 0);
+
+    let index = match part {
+        VersionPart::Major => 0,
+        VersionPart::Minor => 1,
+        VersionPart::Patch => 2,
+    };
+    numbers[index] = numbers[index].checked_add(1).with_context(|| {
+        format!("Version \"{}\" has a number too large to bump", version)
+    })?;
+    numbers[index + 1..].fill(0);
+    Ok(format!("{}{}.{}.{}", prefix, numbers[0], numbers[1], numbers[2]))
+}
+
+/// Bumps `character_version` of the card.
+///
+/// For V3 cards, also sets `modification_date` to now.
+pub fn bump_card_version(
+    png_path: &Path,
+    part: VersionPart,
+    output_path: Option<&Path>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    edit_card_file(png_path, output_path, file_options, |card_json| {
+        let is_v3 = is_v3_card(card_json);
+        let data = card_data_mut(card_json)?;
+        let old_version = data
+            .get("character_version")
+            .and_then(|x| x.as_str())
+            .unwrap_or_default()
+            .to_string();
+        let new_version = bump_version(&old_version, part)?;
+        println!(
+            "Character version: \"{}\" -> \"{}\"",
+            old_version, new_version
+        );
+        data.insert("character_version".to_string(), Value::from(new_version));
+        if is_v3 {
+            stamp_modification_date(card_json)?;
+        }
+        Ok(())
+    })
+}
+
+/// Returns content of the file if `text` is a path to existing file,
+/// otherwise returns `text` itself.
+pub fn read_text_argument(text: &str) -> Result<String> {
+    let path = Path::new(text);
+    if path.is_file() {
+        return tools::read_text_file(path);
+    }
+    Ok(text.to_string())
+}
+
+/// Appends greeting to the array field of the card data, creating the
+/// field if needed. Returns the new number of greetings.
+fn push_greeting(
+    card_json: &mut Value,
+    field: &str,
+    greeting: String,
+) -> Result<usize> {
+    let data = card_data_mut(card_json)?;
+    let greetings =
+        data.entry(field).or_insert_with(|| Value::Array(Vec::new()));
+    if greetings.is_null() {
+        *greetings = Value::Array(Vec::new());
+    }
+    let Some(greetings) = greetings.as_array_mut() else {
+        bail!("Field {} is not a list", field);
+    };
+    greetings.push(Value::from(greeting));
+    Ok(greetings.len())
+}
+
+/// Appends greeting to `alternate_greetings` of the card, or to V3
+/// `group_only_greetings` if `group` is set.
+///
+/// `text` is either the greeting itself or a path to file with it.
+pub fn append_greeting(
+    png_path: &Path,
+    text: &str,
+    group: bool,
+    output_path: Option<&Path>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let greeting = read_text_argument(text)?;
+    let field =
+        if group { "group_only_greetings" } else { "alternate_greetings" };
+    edit_card_file(png_path, output_path, file_options, |card_json| {
+        if group && !is_v3_card(card_json) {
+            bail!("Group-only greetings are supported by V3 cards only");
+        }
+        let count = push_greeting(card_json, field, greeting)?;
+        println!("The card now has {} greetings in {}", count, field);
+        Ok(())
+    })
+}
+
+/// Removes greeting with the given index from `alternate_greetings` of the
+/// card, or from V3 `group_only_greetings` if `group` is set.
+pub fn remove_greeting(
+    png_path: &Path,
+    index: usize,
+    group: bool,
+    output_path: Option<&Path>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let field =
+        if group { "group_only_greetings" } else { "alternate_greetings" };
+    edit_card_file(png_path, output_path, file_options, |card_json| {
+        let data = card_data_mut(card_json)?;
+        let greetings = data
+            .get_mut(field)
+            .and_then(|x| x.as_array_mut())
+            .filter(|x| !x.is_empty())
+            .with_context(|| format!("The card has no {}", field))?;
+        if index >= greetings.len() {
+            bail!(
+                "No greeting with index {}, the card has {} in {}",
+                index,
+                greetings.len(),
+                field
+            );
+        }
+        let removed = greetings.remove(index);
+        println!(
+            "Removed greeting {}: {}",
+            index,
+            removed.as_str().unwrap_or_default()
+        );
+        Ok(())
+    })
+}
+
+/// Blanks the fields of card data, returning what was removed.
+///
+/// Text fields become empty strings, lists and objects become empty. A field
+/// named like "extensions.key" removes that key from `extensions`. With
+/// `all_extensions`, the whole `extensions` object is emptied. Fields that
+/// are already empty are not reported.
+fn redact_fields(
+    card_json: &mut Value,
+    fields: &[String],
+    all_extensions: bool,
+) -> Result<Vec<(String, Value)>> {
+    let data = card_data_mut(card_json)?;
+    let mut removed = Vec::new();
+    for field in fields {
+        if let Some(key) = field.strip_prefix("extensions.") {
+            let extension = data
+                .get_mut("extensions")
+                .and_then(|x| x.as_object_mut())
+                .and_then(|x| x.shift_remove(key));
+            if let Some(extension) = extension {
+                removed.push((field.clone(), extension));
+            }
+            continue;
+        }
+        let Some(value) = data.get_mut(field.as_str()) else { continue };
+        let blank = match value {
+            Value::String(_) => Value::from(""),
+            Value::Array(_) => Value::Array(Vec::new()),
+            Value::Object(_) => Value::Object(serde_json::Map::new()),
+            _ => Value::Null,
+        };
+        if *value != blank {
+            removed.push((field.clone(), std::mem::replace(value, blank)));
+        }
+    }
+    if all_extensions {
+        if let Some(extensions) = data.get_mut("extensions") {
+            let blank = Value::Object(serde_json::Map::new());
+            if *extensions != blank && !extensions.is_null() {
+                let old = std::mem::replace(extensions, blank);
+                removed.push(("extensions".to_string(), old));
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Blanks the fields of the card and prints what was removed.
+///
+/// See [`redact_fields`] for what the field names mean.
+pub fn redact_card(
+    png_path: &Path,
+    fields: &[String],
+    all_extensions: bool,
+    output_path: Option<&Path>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    edit_card_file(png_path, output_path, file_options, |card_json| {
+        let removed = redact_fields(card_json, fields, all_extensions)?;
+        if removed.is_empty() {
+            println!("Nothing to remove");
+        }
+        for (field, value) in removed {
+            println!("Removed {}: {}", field, value);
+        }
+        Ok(())
+    })
+}
+
+/// Sets fields of card data from `assignments`, a map of field paths to
+/// values, returning the paths whose values changed.
+///
+/// Paths are relative to `data`, with nested fields separated by dots, like
+/// "extensions.depth_prompt.depth". Objects missing on the way are created.
+fn set_fields(
+    card_json: &mut Value,
+    assignments: &serde_json::Map<String, Value>,
+) -> Result<Vec<String>> {
+    let data = card_data_mut(card_json)?;
+    let mut changed = Vec::new();
+    for (path, value) in assignments {
+        let mut keys: Vec<&str> = path.split('.').collect();
+        let last = keys.pop().unwrap_or_default();
+        if keys.iter().chain([&last]).any(|x| x.is_empty()) {
+            bail!("Invalid field path \"{}\"", path);
+        }
+        let mut object = &mut *data;
+        for key in keys {
+            let child = object
+                .entry(key)
+                .or_insert_with(|| Value::Object(serde_json::Map::new()));
+            object = child.as_object_mut().with_context(|| {
+                format!("Can not set {}: {} is not an object", path, key)
+            })?;
+        }
+        if object.get(last) != Some(value) {
+            object.insert(last.to_string(), value.clone());
+            changed.push(path.clone());
+        }
+    }
+    Ok(changed)
+}
+
+/// Reads field assignments for [`set_fields`] from a TOML or JSON file, by
+/// its extension. The file holds a single object of paths to values.
+fn read_fields_file(path: &Path) -> Result<serde_json::Map<String, Value>> {
+    let text = tools::read_text_file(path)?;
+    let is_toml =
+        path.extension().is_some_and(|x| x.eq_ignore_ascii_case("toml"));
+    let fields: Value = if is_toml {
+        toml::from_str(&text)
+            .with_context(|| format!("Could not parse {}", path.display()))?
+    } else {
+        serde_json::from_str(&text)
+            .with_context(|| format!("Could not parse {}", path.display()))?
+    };
+    match fields {
+        Value::Object(fields) => Ok(fields),
+        _ => bail!("{} must hold an object of fields", path.display()),
+    }
+}
+
+/// Sets many fields of the card at once, from a TOML or JSON file.
+///
+/// The card is written once, after all fields are set, and only if the
+/// edited card is still valid. See [`set_fields`] for how fields are named.
+pub fn apply_fields_file(
+    png_path: &Path,
+    fields_path: &Path,
+    output_path: Option<&Path>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let assignments = read_fields_file(fields_path)?;
+    edit_card_file(png_path, output_path, file_options, |card_json| {
+        let changed = set_fields(card_json, &assignments)?;
+        if changed.is_empty() {
+            println!("Nothing to change");
+            return Ok(());
+        }
+        for path in &changed {
+            println!("Set {}", path);
+        }
+        Ok(())
+    })
+}
+
+/// Field of [`merge_cards`] that takes the image rather than card data.
+const IMAGE_FIELD: &str = "image";
+
+/// Collects the values of `fields` of the overlay card data for
+/// [`set_fields`], by the same dotted paths. Fails if the overlay does not
+/// have one of them.
+fn overlay_fields(
+    overlay_json: &Value,
+    fields: &[String],
+) -> Result<serde_json::Map<String, Value>> {
+    let data = overlay_json.get("data").context("Overlay has no data block")?;
+    let mut assignments = serde_json::Map::new();
+    for path in fields.iter().filter(|x| *x != IMAGE_FIELD) {
+        let value = path
+            .split('.')
+            .try_fold(data, |value, key| value.get(key))
+            .with_context(|| format!("Overlay card has no {}", path))?;
+        assignments.insert(path.clone(), value.clone());
+    }
+    Ok(assignments)
+}
+
+/// Writes a new card made of the base card, with `fields` of card data
+/// taken from the overlay card. Fields are named as in [`set_fields`];
+/// `image` takes the image of the overlay.
+///
+/// The merged card is written only if it is a valid card.
+pub fn merge_cards(
+    base_path: &Path,
+    overlay_path: &Path,
+    fields: &[String],
+    output_path: &Path,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let overlay_image =
+        tools::read_image_from_file(overlay_path, file_options)?;
+    let overlay_json = read_card_json(&overlay_image)?;
+    let assignments = overlay_fields(&overlay_json, fields)?;
+    let take_image = fields.iter().any(|x| x == IMAGE_FIELD);
+    edit_card_and_image(
+        base_path,
+        Some(output_path),
+        file_options,
+        |card_json, image| {
+            for path in set_fields(card_json, &assignments)? {
+                println!("Took {} from the overlay", path);
+            }
+            if take_image {
+                // The merged card is written onto the overlay image
+                let mut new_image = overlay_image;
+                for key in [TEXT_KEY_PNG, TEXT_KEY_CCV3] {
+                    new_image = tools::remove_text_chunk(&new_image, key)?;
+                }
+                *image = new_image;
+                println!("Took the image from the overlay");
+            }
+            Ok(())
+        },
+    )?;
+    println!("Merged card written to {}", output_path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::card_formats::decode_payload;
+
+    #[test]
+    fn test_bump_version() -> Result<()> {
+        use VersionPart::*;
+        assert_eq!(bump_version("1.2.3", Patch)?, "1.2.4");
+        assert_eq!(bump_version("1.2.3", Minor)?, "1.3.0");
+        assert_eq!(bump_version("1.2.3", Major)?, "2.0.0");
+        assert_eq!(bump_version("2", Patch)?, "2.0.1");
+        assert_eq!(bump_version("v1.4", Minor)?, "v1.5.0");
+        assert_eq!(bump_version("1.0.0-beta", Patch)?, "1.0.1");
+        assert_eq!(bump_version("", Patch)?, "0.0.1");
+        assert_eq!(bump_version("main", Major)?, "1.0.0");
+        let max = format!("1.{}.7", u64::MAX);
+        assert!(bump_version(&max, Minor).is_err());
+        assert_eq!(bump_version(&max, Major)?, "2.0.0");
+        Ok(())
+    }
+
+    #[test]
+    fn test_push_greeting() -> Result<()> {
+        let mut card = serde_json::json!({
+            "data": { "alternate_greetings": ["Hi"], "first_mes": "Hello" }
+        });
+        let count =
+            push_greeting(&mut card, "alternate_greetings", "Hey".to_string())?;
+        assert_eq!(count, 2);
+        assert_eq!(card["data"]["alternate_greetings"][1], "Hey");
+        let count =
+            push_greeting(&mut card, "group_only_greetings", "Yo".to_string())?;
+        assert_eq!(count, 1);
+        let result = push_greeting(&mut card, "first_mes", "x".to_string());
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_redact_fields() -> Result<()> {
+        let mut card = serde_json::json!({
+            "data": {
+                "creator": "Me",
+                "creator_notes": "",
+                "tags": ["a"],
+                "extensions": { "private": 1, "depth_prompt": 2 }
+            }
+        });
+        let fields: Vec<String> = ["creator", "creator_notes", "tags"]
+            .iter()
+            .chain(["extensions.private", "missing"].iter())
+            .map(|x| x.to_string())
+            .collect();
+        let removed = redact_fields(&mut card, &fields, false)?;
+        let removed_fields: Vec<&str> =
+            removed.iter().map(|x| x.0.as_str()).collect();
+        assert_eq!(
+            removed_fields,
+            vec!["creator", "tags", "extensions.private"]
+        );
+        assert_eq!(card["data"]["creator"], "");
+        assert_eq!(
+            card["data"]["extensions"],
+            serde_json::json!({ "depth_prompt": 2 })
+        );
+
+        let removed = redact_fields(&mut card, &[], true)?;
+        assert_eq!(removed.len(), 1);
+        assert_eq!(card["data"]["extensions"], serde_json::json!({}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_stamp_dates() -> Result<()> {
+        let mut card = serde_json::json!({ "data": { "creation_date": 0 } });
+        stamp_dates(&mut card)?;
+        let now = card["data"]["modification_date"].as_i64().unwrap();
+        assert!(now > 1_700_000_000);
+        assert_eq!(card["data"]["creation_date"], now);
+
+        let mut card = serde_json::json!({ "data": { "creation_date": 5 } });
+        stamp_dates(&mut card)?;
+        assert_eq!(card["data"]["creation_date"], 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_fields() -> Result<()> {
+        let mut card = serde_json::json!({
+            "data": { "name": "A", "extensions": { "other": 1 } }
+        });
+        let assignments = serde_json::json!({
+            "name": "A",
+            "description": "New",
+            "extensions.depth_prompt.depth": 4
+        });
+        let changed = set_fields(&mut card, assignments.as_object().unwrap())?;
+        assert_eq!(
+            changed,
+            vec!["description", "extensions.depth_prompt.depth"]
+        );
+        assert_eq!(card["data"]["extensions"]["other"], 1);
+        assert_eq!(card["data"]["extensions"]["depth_prompt"]["depth"], 4);
+
+        let bad = serde_json::json!({ "name.first": "B" });
+        assert!(set_fields(&mut card, bad.as_object().unwrap()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlay_fields() -> Result<()> {
+        let overlay = serde_json::json!({
+            "data": {
+                "description": "Overlay",
+                "character_book": { "entries": [] },
+                "extensions": { "depth_prompt": { "depth": 4 } }
+            }
+        });
+        let fields: Vec<String> =
+            ["description", "extensions.depth_prompt", "image"]
+                .iter()
+                .map(|x| x.to_string())
+                .collect();
+        let assignments = overlay_fields(&overlay, &fields)?;
+        let expected = serde_json::json!({
+            "description": "Overlay",
+            "extensions.depth_prompt": { "depth": 4 }
+        });
+        assert_eq!(Value::Object(assignments), expected);
+
+        let missing = vec!["scenario".to_string()];
+        assert!(overlay_fields(&overlay, &missing).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested_extensions_survive_edits() -> Result<()> {
+        use crate::card_formats::tavern_card_v3::TavernCardV3;
+
+        let extensions = serde_json::json!({
+            "depth_prompt": { "prompt": "Stay in character", "depth": 4 },
+            "regex_scripts": [{
+                "scriptName": "Trim",
+                "findRegex": "/\\s+$/g",
+                "placement": [1, 2],
+                "options": { "flags": { "global": true, "multiline": null } }
+            }],
+            "chub": { "id": 1, "full_path": "a/b" }
+        });
+        let mut card = serde_json::json!({
+            "spec": "chara_card_v3",
+            "spec_version": "3.0",
+            "data": {
+                "name": "A",
+                "extensions": extensions,
+                "character_book": {
+                    "entries": [{
+                        "keys": ["k"],
+                        "content": "c",
+                        "extensions": extensions
+                    }]
+                }
+            }
+        });
+        let assignments = serde_json::json!({ "description": "New" });
+        set_fields(&mut card, assignments.as_object().unwrap())?;
+
+        // Through the typed card and back, as conversions do
+        let typed: TavernCardV3 = serde_json::from_value(card)?;
+        let card = serde_json::to_value(&typed)?;
+        let data = &card["data"];
+        assert_eq!(data["description"], "New");
+        assert_eq!(data["extensions"], extensions);
+        let entry = &data["character_book"]["entries"][0];
+        assert_eq!(entry["extensions"], extensions);
+        let keys: Vec<&String> =
+            data["extensions"].as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["depth_prompt", "regex_scripts", "chub"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fix_spec_fields() -> Result<()> {
+        let mut card = serde_json::json!({
+            "spec": "chara_card_v2",
+            "spec_version": "3.0",
+            "data": { "name": "A", "assets": [] }
+        });
+        let fixed = fix_spec_fields(&mut card)?;
+        assert_eq!(fixed, vec!["spec: \"chara_card_v2\" -> \"chara_card_v3\""]);
+        assert_eq!(card["spec_version"], "3.0");
+
+        let mut card = serde_json::json!({
+            "spec": "chara_card_v3",
+            "data": { "name": "A", "assets": null }
+        });
+        assert_eq!(fix_spec_fields(&mut card)?.len(), 2);
+        assert_eq!(card["spec"], "chara_card_v2");
+        assert_eq!(card["spec_version"], "2.0");
+        assert!(fix_spec_fields(&mut card)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tidy_fields() -> Result<()> {
+        let mut card = serde_json::json!({
+            "data": {
+                "name": " Big  Bad Wolf ",
+                "description": "Fine",
+                "tags": ["Fantasy", "fantasy ", "Horror"],
+                "extensions": { "note": " kept " }
+            }
+        });
+        let changes = tidy_fields(&mut card, false)?;
+        assert_eq!(card["data"]["name"], "Big Bad Wolf");
+        assert_eq!(
+            card["data"]["tags"],
+            serde_json::json!(["fantasy", "horror"])
+        );
+        assert_eq!(card["data"]["extensions"]["note"], " kept ");
+        assert_eq!(changes.len(), 6);
+        assert!(tidy_fields(&mut card, false)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedupe_greetings() -> Result<()> {
+        let mut card = serde_json::json!({
+            "data": {
+                "first_mes": "Hi",
+                "alternate_greetings": ["Hello", "Hi", "Hey", "Hello "]
+            }
+        });
+        assert_eq!(tidy_fields(&mut card.clone(), false)?.len(), 1);
+        let changes = tidy_fields(&mut card, true)?;
+        assert_eq!(changes.last().unwrap(), "Removed 2 duplicate greetings");
+        assert_eq!(
+            card["data"]["alternate_greetings"],
+            serde_json::json!(["Hello", "Hey"])
+        );
+        assert_eq!(dedupe_greetings(&mut card)?, 0);
+        Ok(())
+    }
+
+    /// Writes a V3 card holding "old" in its `ccv3` chunk, returns its path.
+    fn write_card_with_ccv3(dir: &Path, name: &str) -> Result<PathBuf> {
+        let card = serde_json::json!({
+            "spec": "chara_card_v3",
+            "spec_version": "3.0",
+            "data": { "name": name, "description": "new" }
+        });
+        let files = FileOptions::default();
+        let image =
+            write_card_json(&card, &tools::get_default_image(), &files)?;
+        let mut old = card.clone();
+        old["data"]["description"] = Value::from("old");
+        let image = write_ccv3_json(&old, &image, &files)?;
+        let path = dir.join(format!("{}.png", name));
+        tools::write_image_to_file(&image, &path)?;
+        Ok(path)
+    }
+
+    fn read_ccv3(path: &Path) -> Result<Value> {
+        let image = tools::read_image_from_file(path, &FileOptions::default())?;
+        let text = tools::read_text_chunk(&image, TEXT_KEY_CCV3)?.unwrap();
+        Ok(serde_json::from_slice(&decode_payload(&text)?)?)
+    }
+
+    #[test]
+    fn test_edits_rewrite_ccv3() -> Result<()> {
+        let dir = std::env::temp_dir().join("taverntools_test_edit_ccv3");
+        std::fs::create_dir_all(&dir)?;
+        let path = write_card_with_ccv3(&dir, "Test")?;
+        let files = FileOptions::default();
+        edit_card_file(&path, None, &files, |card_json| {
+            card_json["data"]["description"] = Value::from("edited");
+            Ok(())
+        })?;
+        assert_eq!(read_ccv3(&path)?["data"]["description"], "edited");
+
+        let base = write_card_with_ccv3(&dir, "Base")?;
+        let overlay = write_card_with_ccv3(&dir, "Overlay")?;
+        let output = dir.join("merged.png");
+        let fields = ["name".to_string(), IMAGE_FIELD.to_string()];
+        merge_cards(&base, &overlay, &fields, &output, &files)?;
+        let merged = read_ccv3(&output)?;
+        assert_eq!(merged["data"]["name"], "Overlay");
+        assert_eq!(merged["data"]["description"], "new");
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_avatar() -> Result<()> {
+        let dir = std::env::temp_dir().join("taverntools_test_set_avatar");
+        std::fs::create_dir_all(&dir)?;
+        let path = write_card_with_ccv3(&dir, "card")?;
+        let files = FileOptions::default();
+        let image = tools::get_default_image();
+        let other =
+            tools::write_text_to_png(TEXT_KEY_CCV3, "other", &image, &files)?;
+        tools::write_image_to_file(&other, &dir.join("new.png"))?;
+
+        set_avatar(&path, &dir.join("new.png"), None, &files)?;
+        let result = tools::read_image_from_file(&path, &files)?;
+        assert_eq!(read_card_json(&result)?["data"]["name"], "card");
+        assert_eq!(read_ccv3(&path)?["data"]["description"], "new");
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+//! Errors of reading cards, by what went wrong.
+//!
+//! Functions that read cards, images and web pages return [`TavernError`],
+//! so that callers can match on the failure rather than on the message.
+//! Actions that write files and talk to the user return `anyhow` errors, and
+//! the binary reports those; [`TavernError::find`] digs the kind back out of
+//! them, like `process_all` does to choose the issue folder. Messages are
+//! the ones the issue categories of `taverntools.toml` match against, so
+//! they do not change.
+
+use std::path::PathBuf;
+
+/// Why a card could not be read or fetched.
+#[derive(thiserror::Error, Debug)]
+pub enum TavernError {
+    /// The card data is not valid JSON, or not a card
+    #[error("Failed to parse {0}")]
+    Parse(String),
+    /// The image has no chunk with card data under this key
+    #[error("No {0} entry in PNG tEXt chunks")]
+    MissingChunk(String),
+    /// The file could not be read
+    #[error("Could not read {}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// The image is in a format that can not be decoded
+    #[error(transparent)]
+    ImageDecode(#[from] image::ImageError),
+    /// The card is in a format or spec version this tool does not read
+    #[error("{0}")]
+    UnsupportedFormat(String),
+    /// A download failed
+    #[error(transparent)]
+    Network(#[from] reqwest::Error),
+    /// The server answered, but its answer is of no use: an error status,
+    /// or a body over the size limit, cut off or corrupted
+    #[error("{0}")]
+    Download(String),
+}
+
+/// Result of the functions that fail with [`TavernError`].
+pub type TavernResult<T> = std::result::Result<T, TavernError>;
+
+impl From<png::DecodingError> for TavernError {
+    fn from(error: png::DecodingError) -> Self {
+        let format = image::ImageFormat::Png.into();
+        let error = image::error::DecodingError::new(format, error);
+        TavernError::ImageDecode(image::ImageError::Decoding(error))
+    }
+}
+
+impl TavernError {
+    /// Returns the error of this kind that caused `error`, if any.
+    pub fn find(error: &anyhow::Error) -> Option<&TavernError> {
+        error.chain().find_map(|x| x.downcast_ref::<TavernError>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use bytes::Bytes;
+    use serde_json::json;
+
+    use super::*;
+    use crate::card_formats::{check_spec_version, read_card_json};
+    use crate::card_providers::fetch_card;
+    use crate::tools::{self, DownloadOptions, FileOptions};
+
+    #[test]
+    fn test_errors_can_be_told_apart() {
+        let error = read_card_json(&tools::get_default_image()).unwrap_err();
+        assert!(matches!(error, TavernError::MissingChunk(_)));
+        assert_eq!(error.to_string(), "No Chara entry in PNG tEXt chunks");
+
+        // "{" in base64
+        let files = FileOptions::default();
+        let image = tools::write_text_to_png(
+            "Chara",
+            "ew==",
+            &tools::get_default_image(),
+            &files,
+        );
+        let error = read_card_json(&image.unwrap()).unwrap_err();
+        assert!(matches!(error, TavernError::Parse(_)));
+
+        let error = read_card_json(&Bytes::from_static(b"GIF89a")).unwrap_err();
+        assert!(matches!(error, TavernError::ImageDecode(_)));
+
+        let path = Path::new("no_such_card.png");
+        let error = tools::read_image_from_file(path, &files).unwrap_err();
+        assert!(matches!(error, TavernError::Io { .. }));
+        let error =
+            tools::read_image_from_file(Path::new("src"), &files).unwrap_err();
+        assert!(matches!(error, TavernError::Io { .. }));
+
+        let card = json!({ "spec": "chara_card_v4", "data": {} });
+        let error = check_spec_version(&card).unwrap_err();
+        assert!(matches!(error, TavernError::UnsupportedFormat(_)));
+
+        let url = "https://example.com/character";
+        let error = fetch_card(url, &DownloadOptions::default()).unwrap_err();
+        assert!(matches!(error, TavernError::UnsupportedFormat(_)));
+    }
+
+    #[test]
+    fn test_find_kind_in_anyhow_error() {
+        let error = anyhow::Error::from(TavernError::Parse("x".to_string()));
+        let error = error.context("Could not read card.png");
+        let kind = TavernError::find(&error);
+        assert!(matches!(kind, Some(TavernError::Parse(_))));
+        assert!(format!("{:#}", error).ends_with("Failed to parse x"));
+    }
+}
@@ -0,0 +1,124 @@
+//! Stable fingerprints of card contents.
+
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::card_formats::read_card_json;
+use crate::dates::{self, DateFilter};
+use crate::tools::{self, FileOptions};
+
+/// Fields that change without the card content changing.
+const VOLATILE_FIELDS: &[&str] =
+    &["create_date", "creation_date", "modification_date"];
+
+/// Returns SHA-256 of the normalized card JSON, as hex.
+///
+/// Object keys are sorted, line endings and trailing whitespace of strings
+/// are normalized, so that the same card saved by different tools gives the
+/// same hash. Timestamps are left out, unless `include_volatile` is set.
+pub fn card_fingerprint(card_json: &Value, include_volatile: bool) -> String {
+    let normalized = normalize(card_json, include_volatile);
+    // serde_json keeps key order, so the keys are sorted by normalize()
+    let text = serde_json::to_string(&normalized).unwrap_or_default();
+    format!("{:x}", Sha256::digest(text.as_bytes()))
+}
+
+/// Sorts object keys and normalizes text of the JSON, see
+/// [`card_fingerprint`]. Timestamps are left out, unless `include_volatile`
+/// is set.
+pub fn normalize(value: &Value, include_volatile: bool) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map
+                .keys()
+                .filter(|key| {
+                    include_volatile || !VOLATILE_FIELDS.contains(&key.as_str())
+                })
+                .collect();
+            keys.sort();
+            let normalized = keys
+                .into_iter()
+                .map(|key| {
+                    (key.clone(), normalize(&map[key], include_volatile))
+                })
+                .collect();
+            Value::Object(normalized)
+        }
+        Value::Array(items) => Value::Array(
+            items.iter().map(|x| normalize(x, include_volatile)).collect(),
+        ),
+        Value::String(text) => {
+            Value::String(text.replace("\r\n", "\n").trim_end().to_string())
+        }
+        _ => value.clone(),
+    }
+}
+
+/// Prints `hash  filename` for the card, or for every card in the folder.
+///
+/// Cards in a folder that can not be read are reported on stderr, so that
+/// the output can be piped into `sort` or `uniq`. Only cards in the folder
+/// within the range of `dates` are hashed.
+pub fn print_fingerprints(
+    path: &Path,
+    include_volatile: bool,
+    dates: &DateFilter,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let fingerprint = |path: &Path| -> Result<String> {
+        let image = tools::read_image_from_file(path, file_options)?;
+        Ok(card_fingerprint(&read_card_json(&image)?, include_volatile))
+    };
+    if !path.is_dir() {
+        println!("{}  {}", fingerprint(path)?, path.display());
+        return Ok(());
+    }
+
+    let png_paths = tools::list_png_files(path, false)?;
+    let png_paths = dates::filter_cards(png_paths, dates, file_options);
+    let mut failed = 0;
+    for png_path in &png_paths {
+        match fingerprint(png_path) {
+            Ok(hash) => println!("{}  {}", hash, png_path.display()),
+            Err(e) => {
+                eprintln!("Failed to hash {}: {:#}", png_path.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} cards failed", failed, png_paths.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_card_fingerprint() {
+        let card = json!({
+            "spec": "chara_card_v3",
+            "data": { "name": "Test", "modification_date": 1 }
+        });
+        let same = json!({
+            "data": { "modification_date": 2, "name": "Test \r\n" },
+            "spec": "chara_card_v3"
+        });
+        assert_eq!(
+            card_fingerprint(&card, false),
+            card_fingerprint(&same, false)
+        );
+        assert_ne!(
+            card_fingerprint(&card, true),
+            card_fingerprint(&same, true)
+        );
+        assert_eq!(card_fingerprint(&card, false).len(), 64);
+    }
+}
@@ -0,0 +1,47 @@
+//! Tools for tavern cards: reading, editing, converting and checking
+//! character cards of SillyTavern and other frontends.
+//!
+//! The `tavern_card_tools` binary is a command line front end to this
+//! library. Card formats are in [`card_formats`], and errors of reading
+//! cards in [`error`].
+#![allow(dead_code)]
+
+pub mod actions;
+pub mod anonymize;
+pub mod assets;
+pub mod card_formats;
+pub mod card_providers;
+pub mod charx;
+pub mod color;
+pub mod config;
+pub mod convert;
+pub mod dates;
+pub mod deasterisk;
+pub mod diff;
+pub mod edit;
+pub mod error;
+pub mod fingerprint;
+pub mod lorebook;
+pub mod macros;
+pub mod manifest;
+pub mod metadata;
+pub mod new_card;
+pub mod output_template;
+pub mod patch;
+pub mod report;
+pub mod roundtrip;
+pub mod search;
+pub mod self_test;
+pub mod stats;
+pub mod tokens;
+pub mod tools;
+pub mod validate;
+pub mod watch;
+pub mod wrap;
+
+pub const DEFAULT_INPUT_PATH: &str = "inventory/input";
+pub const DEFAULT_OUTPUT_PATH: &str = "inventory/output";
+pub const DEFAULT_LOG_PATH: &str = "inventory/last_run.log";
+pub const DEFAULT_ISSUE_PATH: &str = "inventory/issue";
+pub const DEFAULT_ISSUE_PATH_FORMAT: &str = "inventory/issue/format";
+pub const DEFAULT_ISSUE_PATH_NODATA: &str = "inventory/issue/no_data";
@@ -0,0 +1,213 @@
+//! Management of lorebook (character book) entries.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use textwrap::fill;
+
+use crate::card_formats::character_book::CharacterBook;
+use crate::card_formats::read_card_json;
+use crate::edit::{card_data_mut, edit_card_file};
+use crate::tools::{self, FileOptions};
+use crate::wrap;
+
+const BOOK_POINTER: &str = "/data/character_book";
+
+/// Reads the lorebook from card JSON, if the card has one.
+pub fn read_character_book(card_json: &Value) -> Result<Option<CharacterBook>> {
+    match card_json.pointer(BOOK_POINTER) {
+        None | Some(Value::Null) => Ok(None),
+        Some(book) => {
+            let book = serde_json::from_value(book.clone())
+                .context("Could not parse the lorebook")?;
+            Ok(Some(book))
+        }
+    }
+}
+
+/// Writes the lorebook into card JSON, replacing the existing one.
+pub fn write_character_book(
+    card_json: &mut Value,
+    book: &CharacterBook,
+) -> Result<()> {
+    let data = card_data_mut(card_json)?;
+    data.insert("character_book".to_string(), serde_json::to_value(book)?);
+    Ok(())
+}
+
+/// Prints all lorebook entries of the card.
+pub fn list_entries(png_path: &Path, file_options: &FileOptions) -> Result<()> {
+    let image_data = tools::read_image_from_file(png_path, file_options)?;
+    let card_json = read_card_json(&image_data)?;
+    let Some(book) = read_character_book(&card_json)? else {
+        println!("The card has no lorebook");
+        return Ok(());
+    };
+
+    let options =
+        wrap::options(80).initial_indent("    ").subsequent_indent("    ");
+    for (index, entry) in book.entries.iter().enumerate() {
+        let keys = if entry.keys.is_empty() {
+            "NO KEYS".to_string()
+        } else {
+            entry.keys.join(",")
+        };
+        let disabled = if entry.enabled { "" } else { " (disabled)" };
+        println!("[{}] {}{}", index, keys, disabled);
+        println!("{}", fill(&entry.content, &options));
+    }
+    println!("Total entries: {}", book.entries.len());
+    Ok(())
+}
+
+/// Adds a new lorebook entry to the card, creating lorebook if needed.
+pub fn add_entry(
+    png_path: &Path,
+    keys: Vec<String>,
+    content: String,
+    output_path: Option<&Path>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    edit_character_book(png_path, output_path, file_options, |book| {
+        let keys = keys.into_iter().map(|x| x.trim().to_string()).collect();
+        let index = book.add_entry(keys, content);
+        println!("Added entry [{}]", index);
+        Ok(())
+    })
+}
+
+/// Removes the lorebook entry with a given index from the card.
+pub fn remove_entry(
+    png_path: &Path,
+    index: usize,
+    output_path: Option<&Path>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    edit_character_book(png_path, output_path, file_options, |book| {
+        if index >= book.entries.len() {
+            bail!(
+                "No entry with index {}, the lorebook has {} entries",
+                index,
+                book.entries.len()
+            );
+        }
+        book.entries.remove(index);
+        println!("Removed entry [{}]", index);
+        Ok(())
+    })
+}
+
+/// Removes lorebook entries that duplicate an earlier entry: same keys and
+/// same content. See [`CharacterBook::dedupe_entries`].
+pub fn dedupe_entries(
+    png_path: &Path,
+    ignore_case_keys: bool,
+    output_path: Option<&Path>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    edit_character_book(png_path, output_path, file_options, |book| {
+        let removed = book.dedupe_entries(ignore_case_keys);
+        for (index, original) in &removed {
+            println!("Removed entry [{}], duplicate of [{}]", index, original);
+        }
+        if removed.is_empty() {
+            println!("No duplicate entries");
+        }
+        Ok(())
+    })
+}
+
+/// Writes the lorebook of the card as SillyTavern world info JSON file.
+pub fn export_lorebook(
+    png_path: &Path,
+    output_path: &Path,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let image_data = tools::read_image_from_file(png_path, file_options)?;
+    let card_json = read_card_json(&image_data)?;
+    let Some(book) = read_character_book(&card_json)? else {
+        bail!("The card {} has no lorebook", png_path.display());
+    };
+    let text = serde_json::to_string_pretty(&book.to_world_info())?;
+    tools::write_text_file(output_path, &text)?;
+    println!(
+        "Exported {} entries to {}",
+        book.entries.len(),
+        output_path.display()
+    );
+    Ok(())
+}
+
+/// Applies `edit` to the lorebook of the card and writes the edited card.
+///
+/// Writes into `output_path` if given, otherwise overwrites the card.
+fn edit_character_book<F>(
+    png_path: &Path,
+    output_path: Option<&Path>,
+    file_options: &FileOptions,
+    edit: F,
+) -> Result<()>
+where
+    F: FnOnce(&mut CharacterBook) -> Result<()>,
+{
+    let mut entry_count = 0;
+    edit_card_file(png_path, output_path, file_options, |card_json| {
+        let mut book = read_character_book(card_json)?.unwrap_or_default();
+        edit(&mut book)?;
+        entry_count = book.entries.len();
+        write_character_book(card_json, &book)
+    })?;
+    println!("Lorebook has {} entries now", entry_count);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_add_and_remove_entries() -> Result<()> {
+        let mut card = json!({
+            "spec": "chara_card_v2",
+            "data": { "name": "Test name", "first_mes": "Hello" }
+        });
+        assert!(read_character_book(&card)?.is_none());
+
+        let mut book = CharacterBook::default();
+        book.add_entry(vec!["key1".to_string()], "Entry 1".to_string());
+        book.add_entry(vec!["key2".to_string()], "Entry 2".to_string());
+        write_character_book(&mut card, &book)?;
+        assert_eq!(card["data"]["first_mes"], "Hello");
+
+        let mut book = read_character_book(&card)?.unwrap();
+        assert_eq!(book.entries.len(), 2);
+        assert_eq!(book.entries[1].id, Some(1));
+        assert!(book.entries[1].enabled);
+        book.entries.remove(0);
+        write_character_book(&mut card, &book)?;
+        let book = read_character_book(&card)?.unwrap();
+        assert_eq!(book.entries.len(), 1);
+        assert_eq!(book.entries[0].content, "Entry 2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_defaults() -> Result<()> {
+        let book: CharacterBook = serde_json::from_value(json!({
+            "entries": [{ "keys": ["a"], "content": "b" }]
+        }))?;
+        assert!(book.entries[0].enabled);
+        assert!(book.entries[0].extensions.is_empty());
+
+        let book: CharacterBook = serde_json::from_value(json!({
+            "extensions": null,
+            "entries": [{ "keys": ["a"], "content": "b", "extensions": null }]
+        }))?;
+        assert!(book.extensions.is_empty());
+        assert!(book.entries[0].extensions.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,332 @@
+//! Checks of `{{macro}}` placeholders used in card text, and previews of
+//! text with the simple ones filled in.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use crate::actions::AnyTavernCard;
+use crate::tools::{self, FileOptions};
+
+/// Macro names that SillyTavern understands, in lower case.
+const KNOWN_MACROS: &[&str] = &[
+    "char",
+    "user",
+    "bot",
+    "persona",
+    "description",
+    "personality",
+    "scenario",
+    "mesexamples",
+    "charprompt",
+    "charjailbreak",
+    "group",
+    "charifnotgroup",
+    "original",
+    "input",
+    "lastmessage",
+    "lastcharmessage",
+    "lastusermessage",
+    "lastmessageid",
+    "currentswipeid",
+    "model",
+    "maxprompt",
+    "time",
+    "date",
+    "weekday",
+    "isotime",
+    "isodate",
+    "datetimeformat",
+    "time_utc",
+    "timediff",
+    "idle_duration",
+    "random",
+    "pick",
+    "roll",
+    "newline",
+    "trim",
+    "noop",
+    "banned",
+    "reverse",
+    "setvar",
+    "getvar",
+    "addvar",
+    "incvar",
+    "decvar",
+    "setglobalvar",
+    "getglobalvar",
+    "addglobalvar",
+    "incglobalvar",
+    "decglobalvar",
+];
+
+/// Macros found in the card text.
+#[derive(Debug, Default, PartialEq)]
+pub struct MacroReport {
+    /// Number of uses of each macro, by lower case name.
+    pub counts: BTreeMap<String, usize>,
+    /// Descriptions of broken macros, prefixed with field name.
+    pub problems: Vec<String>,
+}
+
+/// Finds macros in the text and adds them to the report.
+///
+/// A macro must be closed on the same line it was opened. Names are
+/// compared without case, and arguments after ':' or whitespace are
+/// ignored. Comments like `{{// text}}` are allowed.
+fn scan_text(field: &str, text: &str, report: &mut MacroReport) {
+    for (line_number, line) in text.lines().enumerate() {
+        let mut rest = line;
+        let mut column = 0;
+        loop {
+            let open = rest.find("{{");
+            let close = rest.find("}}");
+            match (open, close) {
+                (None, None) => break,
+                (open, Some(close)) if open.is_none_or(|x| x > close) => {
+                    report.problems.push(format!(
+                        "{} line {}: \"}}}}\" without \"{{{{\" at column {}",
+                        field,
+                        line_number + 1,
+                        column + close + 1
+                    ));
+                    column += close + 2;
+                    rest = &rest[close + 2..];
+                }
+                (Some(open), close) => {
+                    let body = &rest[open + 2..];
+                    let next_open = body.find("{{");
+                    let close = close
+                        .filter(|x| *x > open)
+                        .map(|x| x - open - 2)
+                        .filter(|x| next_open.is_none_or(|y| y > *x));
+                    let Some(close) = close else {
+                        report.problems.push(format!(
+                            "{} line {}: unclosed macro at column {}",
+                            field,
+                            line_number + 1,
+                            column + open + 1
+                        ));
+                        column += open + 2;
+                        rest = body;
+                        continue;
+                    };
+                    check_macro(field, &body[..close], report);
+                    column += open + close + 4;
+                    rest = &body[close + 2..];
+                }
+                (None, Some(_)) => unreachable!(),
+            }
+        }
+    }
+}
+
+/// Counts the macro and reports it if it is not known.
+fn check_macro(field: &str, content: &str, report: &mut MacroReport) {
+    let content = content.trim();
+    if content.starts_with("//") {
+        *report.counts.entry("//".to_string()).or_default() += 1;
+        return;
+    }
+    let name = content
+        .split(|c: char| c == ':' || c.is_whitespace())
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    if !KNOWN_MACROS.contains(&name.as_str()) {
+        report
+            .problems
+            .push(format!("{}: unknown macro {{{{{}}}}}", field, content));
+    }
+    *report.counts.entry(name).or_default() += 1;
+}
+
+/// Scans all text fields of the card for macros.
+pub(crate) fn scan_card(card: &AnyTavernCard) -> MacroReport {
+    let mut report = MacroReport::default();
+    card.for_each_text_field(|field, text| scan_text(field, text, &mut report));
+    report
+}
+
+/// Prints macros used in the card, and the broken ones.
+///
+/// Returns error if any macro is malformed or unknown.
+pub fn print_macros_from_path(
+    png_path: &Path,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let image_data = tools::read_image_from_file(png_path, file_options)?;
+    let card = AnyTavernCard::from_png_image(&image_data)?;
+    let report = scan_card(&card);
+
+    if report.counts.is_empty() {
+        println!("No macros used");
+    }
+    let mut counts: Vec<(&String, &usize)> = report.counts.iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(a.1));
+    for (name, count) in counts {
+        println!("{{{{{}}}}}: {}", name, count);
+    }
+    if report.problems.is_empty() {
+        return Ok(());
+    }
+    println!("Problems:");
+    for problem in &report.problems {
+        println!("  {}", problem);
+    }
+    bail!("Found {} broken macros", report.problems.len());
+}
+
+/// Seedable picker for `{{random}}` macros, so that a preview can be
+/// repeated with the same seed. Uses SplitMix64.
+pub struct Picker(u64);
+
+impl Picker {
+    pub fn new(seed: u64) -> Self {
+        Picker(seed)
+    }
+
+    /// Returns a number below `count`, which must not be zero.
+    pub fn pick(&mut self, count: usize) -> usize {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z % count as u64) as usize
+    }
+}
+
+/// Returns the options of `{{random}}` macro, or None for other macros.
+/// Options are separated by "::" if the macro starts so, like
+/// `{{random::a, b::c}}`, otherwise by commas, like `{{random:a,b,c}}`.
+fn random_options(content: &str) -> Option<Vec<&str>> {
+    let (name, args) = content.split_once(':')?;
+    if !name.trim().eq_ignore_ascii_case("random") {
+        return None;
+    }
+    let options = match args.strip_prefix(':') {
+        Some(args) => args.split("::").collect(),
+        None => args.split(',').collect(),
+    };
+    Some(options)
+}
+
+/// Fills in `{{char}}`, `{{bot}}`, `{{user}}` and `{{random}}` macros of
+/// the text, without case. Random options are picked with `picker`. Other
+/// macros are left as they are. Returns the text and whether any random
+/// option was picked.
+pub fn render_macros(
+    text: &str,
+    char_name: &str,
+    user_name: &str,
+    picker: &mut Picker,
+) -> (String, bool) {
+    let mut result = String::with_capacity(text.len());
+    let mut picked = false;
+    let mut rest = text;
+    while let Some(open) = rest.find("{{") {
+        let Some(close) = rest[open + 2..].find("}}") else {
+            break;
+        };
+        result += &rest[..open];
+        let content = &rest[open + 2..open + 2 + close];
+        match content.trim().to_lowercase().as_str() {
+            "char" | "bot" => result += char_name,
+            "user" => result += user_name,
+            _ => match random_options(content) {
+                Some(options) => {
+                    result += options[picker.pick(options.len())].trim();
+                    picked = true;
+                }
+                None => result += &rest[open..open + close + 4],
+            },
+        }
+        rest = &rest[open + close + 4..];
+    }
+    result += rest;
+    (result, picked)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    fn scan(text: &str) -> MacroReport {
+        let mut report = MacroReport::default();
+        scan_text("first_mes", text, &mut report);
+        report
+    }
+
+    #[test]
+    fn test_macro_counts() {
+        let report = scan(
+            "{{Char}} waves at {{user}}. {{random:a,b}} {{char}} {{// note}}",
+        );
+        let counts: Vec<(&str, usize)> =
+            report.counts.iter().map(|(k, v)| (k.as_str(), *v)).collect();
+        assert_eq!(
+            counts,
+            vec![("//", 1), ("char", 2), ("random", 1), ("user", 1)]
+        );
+        assert!(report.problems.is_empty());
+    }
+
+    #[test]
+    fn test_render_macros() {
+        let text = "{{Char}} greets {{user}}. {{random:Hi, Hey}}! {{time}}";
+        let render =
+            |seed| render_macros(text, "Alice", "Bob", &mut Picker::new(seed));
+        let (rendered, picked) = render(1);
+        assert!(picked);
+        assert!(rendered.starts_with("Alice greets Bob. H"));
+        assert!(rendered.ends_with("! {{time}}"));
+        assert_eq!(render(1), render(1));
+        let picks: BTreeSet<String> = (0..20).map(|x| render(x).0).collect();
+        assert_eq!(picks.len(), 2);
+
+        let mut picker = Picker::new(0);
+        let (rendered, picked) =
+            render_macros("{{random::a, b}} {{user", "A", "B", &mut picker);
+        assert_eq!(rendered, "a, b {{user");
+        assert!(picked);
+        let (_, picked) = render_macros("{{bot}}", "A", "B", &mut picker);
+        assert!(!picked);
+    }
+
+    #[test]
+    fn test_broken_macros() {
+        let report = scan("{{char} waves.\n{{user}} }} {{usr}}\n{{char");
+        assert_eq!(
+            report.problems,
+            vec![
+                "first_mes line 1: unclosed macro at column 1",
+                "first_mes line 2: \"}}\" without \"{{\" at column 10",
+                "first_mes: unknown macro {{usr}}",
+                "first_mes line 3: unclosed macro at column 1",
+            ]
+        );
+        assert_eq!(report.counts.get("user"), Some(&1));
+    }
+
+    #[test]
+    fn test_scan_v3_card() {
+        let json = r#"{
+            "spec": "chara_card_v3",
+            "data": {
+                "first_mes": "{{char}} waves.",
+                "group_only_greetings": ["Hi, {{usr}}"]
+            }
+        }"#;
+        let card = AnyTavernCard::from_json(json).unwrap();
+        let report = scan_card(&card);
+        assert_eq!(report.counts.get("char"), Some(&1));
+        assert_eq!(
+            report.problems,
+            vec!["group_only_greetings[0]: unknown macro {{usr}}"]
+        );
+    }
+}
@@ -1,30 +1,25 @@
 #![allow(dead_code)]
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use clap::{Parser, ValueHint};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-mod actions;
-mod card_providers;
-mod deasterisk;
-mod card_formats;
-mod tools;
-//mod example;
+use tavern_card_tools::{
+    actions, anonymize, assets, card_formats, card_providers, charx, color,
+    config, convert, dates, deasterisk, diff, edit, fingerprint, lorebook,
+    macros, manifest, metadata, new_card, patch, roundtrip, search, self_test,
+    stats, tokens, tools, validate, watch, wrap, DEFAULT_INPUT_PATH,
+    DEFAULT_ISSUE_PATH, DEFAULT_ISSUE_PATH_FORMAT, DEFAULT_ISSUE_PATH_NODATA,
+    DEFAULT_LOG_PATH, DEFAULT_OUTPUT_PATH,
+};
 
 const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
-const DEFAULT_INPUT_PATH: &str = "inventory/input";
-const DEFAULT_OUTPUT_PATH: &str = "inventory/output";
-const DEFAULT_LOG_PATH: &str = "inventory/last_run.log";
-const DEFAULT_ISSUE_PATH: &str = "inventory/issue";
-const DEFAULT_ISSUE_PATH_FORMAT: &str = "inventory/issue/format";
-const DEFAULT_ISSUE_PATH_NODATA: &str = "inventory/issue/no_data";
-
 #[derive(Parser, Debug)]
 #[command(author = "Barafu Albino <barafu_develops@albino.email",
      version = APP_VERSION,
      about = "Tools for tavern cards", long_about = None)]
-#[group(multiple = false)]
 #[command(arg_required_else_help = true)]
 struct Cli {
     #[command(subcommand)]
@@ -33,6 +28,50 @@ struct Cli {
     /// If no command is provided, "print" command is used by default.
     #[arg(value_hint = ValueHint::FilePath)]
     card_path: Option<PathBuf>,
+
+    /// Base output folder for all commands that write new files. Output paths given to a command take precedence
+    #[arg(long, global = true, value_hint = ValueHint::DirPath)]
+    output_dir: Option<PathBuf>,
+
+    /// Do not color the output. Colors are also off when NO_COLOR is set or the output is not a terminal
+    #[arg(long, global = true)]
+    no_color: bool,
+
+    /// Wrap printed text at this many columns. Defaults to the terminal width, or no wrapping when the output is not a terminal
+    #[arg(long, global = true, conflicts_with = "no_wrap")]
+    width: Option<usize>,
+
+    /// Do not wrap printed text
+    #[arg(long, global = true)]
+    no_wrap: bool,
+
+    /// Version to read a card as when it can be read as both V2 and V3
+    #[arg(long, global = true, value_enum, default_value_t = card_formats::PreferredVersion::V3)]
+    prefer: card_formats::PreferredVersion,
+
+    /// Image for cards that come without one, like JSON cards turned into PNG. Defaults to a bundled placeholder
+    #[arg(long, global = true, value_hint = ValueHint::FilePath)]
+    base_image: Option<PathBuf>,
+
+    /// Encoding of written JSON and text files. Some Windows editors expect utf8-bom
+    #[arg(long, global = true, value_enum, default_value_t)]
+    encoding: tools::TextEncoding,
+
+    /// Write card data into PNG as compressed zTXt chunks, to make large cards smaller
+    #[arg(long, global = true)]
+    compress: bool,
+
+    /// When replacing card data in a PNG, write it where the old chunk was, rather than before the end of the image
+    #[arg(long, global = true)]
+    preserve_chunk_order: bool,
+
+    /// Read PNG files that have junk bytes before the PNG signature, reporting the skipped bytes
+    #[arg(long, global = true)]
+    lenient: bool,
+
+    /// When a command edits a V3 card, set its modification date to now, and its creation date if it has none
+    #[arg(long, global = true)]
+    stamp_dates: bool,
 }
 
 #[derive(Parser, Debug)]
@@ -40,31 +79,220 @@ enum Commands {
     /// Download tavern card from BackyardAI
     #[command(name = "baya_get")]
     #[command(arg_required_else_help = true)]
+    #[command(group(clap::ArgGroup::new("source").required(true)))]
     BayaGet {
         /// URL at Backyard AI website to download from
-        #[arg()]
-        url: String,
-        /// Path to output file. Defaults to "inventory/output/<character_name>.png"
-        #[arg(value_hint = ValueHint::FilePath, default_value = DEFAULT_OUTPUT_PATH)]
-        output_path: PathBuf,
+        #[arg(group = "source")]
+        url: Option<String>,
+        /// Download all characters from this creator profile URL instead
+        #[arg(long, group = "source")]
+        creator: Option<String>,
+        /// Take the character URL from the clipboard
+        #[arg(long, group = "source")]
+        from_clipboard: bool,
+        /// With --creator, download this many characters at once
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..), conflicts_with_all = ["url", "from_clipboard"])]
+        parallel_downloads: u16,
+        /// With --creator, download at most this many characters
+        #[arg(long, conflicts_with_all = ["url", "from_clipboard"])]
+        limit: Option<usize>,
+        /// With --creator, stop at the first character that fails to download
+        #[arg(long, conflicts_with_all = ["url", "from_clipboard"])]
+        fail_fast: bool,
+        /// Path to output folder. Defaults to "inventory/output", the card is saved as "<character_name>.png"
+        #[arg(long = "output", value_hint = ValueHint::DirPath)]
+        output_path: Option<PathBuf>,
+        /// Path to output folder given after the URL, as older versions took it. Same as --output
+        #[arg(hide = true, value_hint = ValueHint::DirPath, conflicts_with = "output_path")]
+        legacy_output_path: Option<PathBuf>,
+        /// Abort downloads larger than this many megabytes
+        #[arg(long, default_value_t = 50)]
+        max_size: u64,
+        /// Make at most this many requests per second, to not overload the site. 0 turns the limit off
+        #[arg(long, default_value_t = tools::DEFAULT_DOWNLOAD_RATE, value_parser = tools::parse_rate)]
+        rate: f64,
+        #[command(flatten)]
+        proxy: tools::ProxyArgs,
+        /// Save into a new "YYYY-MM-DD_HHMMSS" folder inside the output folder, to keep each run apart
+        #[arg(long)]
+        dated_subdir: bool,
+        /// Which characters of the character name to replace in the file name
+        #[arg(long, value_enum, default_value_t)]
+        sanitize: tools::SanitizeStrategy,
+    },
+    /// Download tavern card from any supported site, picking the provider by URL
+    #[command(arg_required_else_help = true)]
+    Get {
+        /// URL of the character page
+        #[arg(required_unless_present = "from_clipboard")]
+        url: Option<String>,
+        /// Take the URL from the clipboard
+        #[arg(long, conflicts_with = "url")]
+        from_clipboard: bool,
+        /// Path to output folder. Defaults to "inventory/output", the card is saved as "<character_name>.png"
+        #[arg(long = "output", value_hint = ValueHint::DirPath)]
+        output_path: Option<PathBuf>,
+        /// Path to output folder given after the URL, as older versions took it. Same as --output
+        #[arg(hide = true, value_hint = ValueHint::DirPath, conflicts_with = "output_path")]
+        legacy_output_path: Option<PathBuf>,
+        /// Abort downloads larger than this many megabytes
+        #[arg(long, default_value_t = 50)]
+        max_size: u64,
+        /// Make at most this many requests per second, to not overload the site. 0 turns the limit off
+        #[arg(long, default_value_t = tools::DEFAULT_DOWNLOAD_RATE, value_parser = tools::parse_rate)]
+        rate: f64,
+        #[command(flatten)]
+        proxy: tools::ProxyArgs,
+        /// Which characters of the character name to replace in the file name
+        #[arg(long, value_enum, default_value_t)]
+        sanitize: tools::SanitizeStrategy,
+    },
+    /// Show sites that cards can be downloaded from
+    Providers {
+        #[command(subcommand)]
+        command: ProvidersCommands,
     },
     /// Remove paired asterisks from text in tavern card. Makes a copy of the image and renames it to de8.<old_name.png>
     #[command(arg_required_else_help = true)]
     De8 {
-        /// Path to image.png. Defaults to "inventory/input/<filename.png>"
-        #[arg(value_hint = ValueHint::FilePath, default_value = DEFAULT_INPUT_PATH)]
+        /// Path to image.png, or to a folder to process all cards in it. Defaults to "inventory/input"
+        #[arg(value_hint = ValueHint::AnyPath, default_value = DEFAULT_INPUT_PATH)]
         path: PathBuf,
 
         /// Overwrite output file if it exists already
         #[arg(long)]
         force: bool,
+
+        /// Print how the text of every field would change, without writing any file
+        #[arg(long)]
+        preview: bool,
+
+        /// Process all files matching this glob pattern, like "inventory/input/**/*.png"
+        #[arg(long = "input", conflicts_with = "path")]
+        input_pattern: Option<String>,
+
+        /// When processing a folder, stop at the first card that fails
+        #[arg(long)]
+        fail_fast: bool,
+        /// When processing a folder, also process cards that are symbolic links
+        #[arg(long)]
+        follow_symlinks: bool,
+        #[command(flatten)]
+        dates: dates::DateFilter,
+        #[command(flatten)]
+        exclude: tools::ExcludeArgs,
+    },
+    /// Find text in the fields of the card, or of all cards in the folder
+    #[command(arg_required_else_help = true)]
+    Search {
+        /// Text to find, ignoring case
+        query: String,
+        /// Path to image.png, to a folder to search all cards in it, or a glob pattern like "inventory/input/**/*.png". Defaults to "inventory/input"
+        #[arg(value_hint = ValueHint::AnyPath, default_value = DEFAULT_INPUT_PATH)]
+        path: PathBuf,
+        /// Print every match as a JSON object on its own line, with file, field and snippet
+        #[arg(long)]
+        json: bool,
+        #[command(flatten)]
+        exclude: tools::ExcludeArgs,
+    },
+    /// Print SHA-256 of the normalized card JSON, to find duplicate cards
+    #[command(arg_required_else_help = true)]
+    Hash {
+        /// Path to image.png, or to a folder to hash all cards in it
+        #[arg(value_hint = ValueHint::AnyPath)]
+        path: PathBuf,
+        /// Also hash timestamps like modification_date
+        #[arg(long)]
+        include_volatile: bool,
+        #[command(flatten)]
+        dates: dates::DateFilter,
+    },
+    /// Print statistics of all cards in a folder
+    #[command(arg_required_else_help = true)]
+    Stats {
+        /// Path to the folder with cards
+        #[arg(value_hint = ValueHint::DirPath)]
+        path: PathBuf,
+        /// Also show how many tokens the permanent fields of the cards take
+        #[arg(long)]
+        tokens: bool,
+        /// How to count tokens. All tokenizers are estimates for models that do not use them
+        #[arg(long, value_enum, default_value_t, requires = "tokens")]
+        tokenizer: tokens::Tokenizer,
+        /// Also show how many cards are V1, V2 and V3, and how many declare their version wrong
+        #[arg(long)]
+        spec_report: bool,
+        #[command(flatten)]
+        dates: dates::DateFilter,
     },
     /// Print the content of the card
     #[command(arg_required_else_help = true)]
     Print {
-        /// Path to image.png. Defaults to "inventory/input/<filename.png>"
+        /// Path to image.png or card.json. Defaults to "inventory/input/<filename.png>"
         #[arg(value_hint = ValueHint::FilePath, default_value = DEFAULT_INPUT_PATH)]
         path: PathBuf,
+        /// Print only example dialogues, split into numbered conversations
+        #[arg(long)]
+        examples: bool,
+        /// With --examples, print dialogues as JSON, with speaker (char, user or system) and text of every turn
+        #[arg(long, requires = "examples")]
+        json: bool,
+        /// Read the card as this version, whatever version it declares
+        #[arg(long = "as", value_enum)]
+        as_version: Option<card_formats::SpecVersion>,
+        /// Show creator notes in this language, like "de". Defaults to the base creator notes
+        #[arg(long)]
+        lang: Option<String>,
+        /// Fail if an object of the card JSON has the same key twice
+        #[arg(long)]
+        strict: bool,
+        /// List the fields and whether they are filled, with counts of greetings and lorebook entries, without their content
+        #[arg(long, conflicts_with_all = ["examples", "lang"])]
+        fields_only: bool,
+        /// Cut every field at this many bytes, telling how many more there are
+        #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        field_max_bytes: Option<usize>,
+    },
+    /// Print the extensions of the card, where frontends keep their own data
+    #[command(arg_required_else_help = true)]
+    Extensions {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Print only this nested value, as keys separated by dots, like "depth_prompt.prompt"
+        #[arg(long)]
+        key: Option<String>,
+    },
+    /// List all text chunks of the PNG image with their keywords, not only the card data
+    #[command(arg_required_else_help = true)]
+    Chunks {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+    },
+    /// Show technical details of the card image: size, color type, bit depth, transparency, and the card it holds
+    #[command(arg_required_else_help = true)]
+    Info {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+    },
+    /// List the metadata chunks of the image that are not card data, and flag the suspicious ones
+    #[command(name = "scan_metadata")]
+    #[command(arg_required_else_help = true)]
+    ScanMetadata {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+    },
+    /// Check if the file is a card, for shell scripts: exits with 0 if it is, 1 if not
+    #[command(name = "is_card")]
+    #[command(arg_required_else_help = true)]
+    IsCard {
+        /// Path to card.png, card.json or card.charx
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
     },
     /// Print the JSON of the card
     #[command(name = "print_all")]
@@ -74,13 +302,16 @@ enum Commands {
         #[arg(value_hint = ValueHint::FilePath, default_value = DEFAULT_INPUT_PATH)]
         path: PathBuf,
     },
-    /// Print the content of a JSON card file
+    /// Print the content of a JSON card file, same as print
     #[command(name = "print_json_file")]
     #[command(arg_required_else_help = true)]
     PrintJsonFile {
         /// Path to JSON file. Defaults to "inventory/input/<filename>.json"
         #[arg(value_hint = ValueHint::FilePath, default_value = DEFAULT_INPUT_PATH)]
         path: PathBuf,
+        /// Fail if an object of the card JSON has the same key twice
+        #[arg(long)]
+        strict: bool,
     },
     /// Extract JSON from a PNG card and save it to a .json file
     #[command(name = "extract_json")]
@@ -89,9 +320,21 @@ enum Commands {
         /// Path to the PNG image file. Defaults to "inventory/input/<filename>.png"
         #[arg(value_hint = ValueHint::FilePath, default_value = DEFAULT_INPUT_PATH)]
         image_path: PathBuf,
-        /// Path to the output JSON file. Defaults to "inventory/output/<filename>.json"
-        #[arg(value_hint = ValueHint::FilePath, default_value = DEFAULT_OUTPUT_PATH)]
-        output_path: PathBuf,
+        /// Path to the output JSON file. Defaults to "<output folder>/<filename>.json"
+        #[arg(value_hint = ValueHint::FilePath)]
+        output_path: Option<PathBuf>,
+        /// Charset of the embedded JSON, like "windows-1251". Detected automatically if not given
+        #[arg(long)]
+        charset: Option<String>,
+        /// Add SHA-256 of the image pixels to the JSON, as extensions.taverntools.image_sha256
+        #[arg(long)]
+        include_image_hash: bool,
+        /// Read the card as this version, whatever version it declares, and write JSON in its shape
+        #[arg(long = "as", value_enum)]
+        as_version: Option<card_formats::SpecVersion>,
+        /// Write a single-level object with dotted keys, like "data.tags.0", for spreadsheets
+        #[arg(long)]
+        flatten: bool,
     },
     /// Extract the image from a PNG card (without embedded JSON) and save it to a new .png file
     #[command(name = "extract_image")]
@@ -100,23 +343,564 @@ enum Commands {
         /// Path to the PNG image file. Defaults to "inventory/input/<filename>.png"
         #[arg(value_hint = ValueHint::FilePath, default_value = DEFAULT_INPUT_PATH)]
         image_path: PathBuf,
-        /// Path to the output PNG file. Defaults to "inventory/output/<filename>.png"
-        #[arg(value_hint = ValueHint::FilePath, default_value = DEFAULT_OUTPUT_PATH)]
-        output_path: PathBuf,
+        /// Path to the output PNG file. Defaults to "<output folder>/<filename>.png"
+        #[arg(value_hint = ValueHint::FilePath)]
+        output_path: Option<PathBuf>,
+        /// Format of the output image
+        #[arg(long, value_enum, default_value_t = tools::ImageFormat::Png)]
+        format: tools::ImageFormat,
+        /// Quality of WEBP images, from 0 to 100. Makes them lossy and much
+        /// smaller. Without it, WEBP images are lossless
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+        quality: Option<u8>,
+    },
+    /// Create a new card from a template: a minimal valid card with placeholder text and the base image
+    #[command(arg_required_else_help = true)]
+    New {
+        /// Name of the character
+        #[arg(long)]
+        name: String,
+        /// Spec version of the card
+        #[arg(long, value_enum, default_value_t)]
+        version: card_formats::PreferredVersion,
+        /// Path to output file. Defaults to <name>.png in the output directory
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Convert card between formats: png, json, webp, and from charx. Formats are guessed from file extensions
+    #[command(arg_required_else_help = true)]
+    Convert {
+        /// Path to the input file
+        #[arg(long = "in", value_hint = ValueHint::FilePath)]
+        input: PathBuf,
+        /// Path to the output file
+        #[arg(long = "out", value_hint = ValueHint::FilePath)]
+        output: PathBuf,
+        /// Format of the input file, if it can not be guessed from extension
+        #[arg(long, value_enum)]
+        format_in: Option<convert::CardFormat>,
+        /// Format of the output file, if it can not be guessed from extension
+        #[arg(long, value_enum)]
+        format_out: Option<convert::CardFormat>,
+        /// Image to use when the input has none, like when converting JSON to PNG
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        image: Option<PathBuf>,
+        /// Convert V3 card to V2
+        #[arg(long)]
+        to_v2: bool,
+        /// With --to-v2, move group-only greetings into alternate greetings, or drop them
+        #[arg(long, value_enum, default_value_t, requires = "to_v2")]
+        group_greetings: convert::GroupGreetings,
+        /// What to do with assets stored in a CHARX input: embed them into the output, drop them, or write them next to it
+        #[arg(long, value_enum, default_value_t)]
+        dereference_assets: charx::AssetHandling,
     },
     /// Process all PNG cards in the input directory, extracting JSON and image, and handling errors.
     #[command(name = "process_all")]
     ProcessAll {
         /// Path to the input directory. Defaults to "inventory/input"
-        #[arg(value_hint = ValueHint::DirPath, default_value = DEFAULT_INPUT_PATH)]
-        input_dir: PathBuf,
+        #[arg(value_hint = ValueHint::DirPath)]
+        input_dir: Option<PathBuf>,
+        /// Process all files matching this glob pattern, like "inventory/input/**/*.png", instead of the input directory
+        #[arg(long = "input", conflicts_with = "input_dir")]
+        input_pattern: Option<String>,
+        /// Path to the output directory. Defaults to "inventory/output"
+        #[arg(value_hint = ValueHint::DirPath)]
+        output_dir: Option<PathBuf>,
+        /// Path to the issue directory. Defaults to "inventory/issue"
+        #[arg(value_hint = ValueHint::DirPath)]
+        issue_dir: Option<PathBuf>,
+        /// Which files to extract from each card
+        #[arg(long, value_enum, default_value_t = actions::Artifacts::Both)]
+        only: actions::Artifacts,
+        /// Stop at the first card that fails
+        #[arg(long)]
+        fail_fast: bool,
+        /// Also process cards that are symbolic links
+        #[arg(long)]
+        follow_symlinks: bool,
+        #[command(flatten)]
+        dates: dates::DateFilter,
+        /// Add SHA-256 of every written file to checksums.txt in the output directory
+        #[arg(long)]
+        checksum_manifest: bool,
+        /// Name outputs after the card, like "{creator}/{name}". Placeholders: {name}, {creator}, {tag}, {version}, {stem}
+        #[arg(long)]
+        output_template: Option<String>,
+        /// Skip cards that were processed before and did not change since, by their SHA-256 kept in the output directory
+        #[arg(long)]
+        incremental: bool,
+        /// Save the decoded card data of failed cards as "<name>.raw.json" next to them in the issue directory
+        #[arg(long)]
+        dump_on_error: bool,
+        /// Copy failed cards to the issue directory instead of moving them, leaving the input as it is
+        #[arg(long, alias = "keep-issue-structure")]
+        copy_issues: bool,
+        #[command(flatten)]
+        exclude: tools::ExcludeArgs,
+        /// Write a summary of the run into this file: cards that succeeded and failed, by issue folder
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        report: Option<PathBuf>,
+        /// Write the report as JSON
+        #[arg(long, requires = "report")]
+        report_json: bool,
+    },
+    /// Hash the files listed in checksums.txt again and report the ones that changed
+    #[command(name = "verify_manifest")]
+    VerifyManifest {
+        /// Path to checksums.txt, or to the folder with it. Defaults to the output folder
+        #[arg(value_hint = ValueHint::AnyPath)]
+        path: Option<PathBuf>,
+    },
+    /// Watch the input folder and extract new cards as they appear, like process_all
+    Watch {
+        /// Path to the input directory. Defaults to "inventory/input"
+        #[arg(value_hint = ValueHint::DirPath)]
+        input_dir: Option<PathBuf>,
         /// Path to the output directory. Defaults to "inventory/output"
-        #[arg(value_hint = ValueHint::DirPath, default_value = DEFAULT_OUTPUT_PATH)]
-        output_dir: PathBuf,
+        #[arg(value_hint = ValueHint::DirPath)]
+        output_dir: Option<PathBuf>,
         /// Path to the issue directory. Defaults to "inventory/issue"
-        #[arg(value_hint = ValueHint::DirPath, default_value = DEFAULT_ISSUE_PATH)]
-        issue_dir: PathBuf,
+        #[arg(value_hint = ValueHint::DirPath)]
+        issue_dir: Option<PathBuf>,
+        /// Which files to extract from each card
+        #[arg(long, value_enum, default_value_t = actions::Artifacts::Both)]
+        only: actions::Artifacts,
+        /// Also process cards that are symbolic links
+        #[arg(long)]
+        follow_symlinks: bool,
+        /// Seconds a card must stay unchanged before it is processed, from
+        /// 0.1 to 3600
+        #[arg(long, default_value_t = 2.0, value_parser = watch::parse_debounce)]
+        debounce: f64,
+    },
+    /// Apply a JSON Patch (RFC 6902) or a set of JSON Pointer assignments to the card
+    #[command(arg_required_else_help = true)]
+    Patch {
+        /// Path to image.png. Defaults to "inventory/input/<filename.png>"
+        #[arg(value_hint = ValueHint::FilePath, default_value = DEFAULT_INPUT_PATH)]
+        path: PathBuf,
+        /// Path to the JSON file with the patch
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        patch: PathBuf,
+        /// Path to output file. Defaults to overwriting the input card
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Print the fields that differ between two cards
+    #[command(arg_required_else_help = true)]
+    Diff {
+        /// Path to the old card
+        #[arg(value_hint = ValueHint::FilePath)]
+        old_path: PathBuf,
+        /// Path to the new card
+        #[arg(value_hint = ValueHint::FilePath)]
+        new_path: PathBuf,
+        /// Print only which fields changed and by how many characters and lines
+        #[arg(long)]
+        summary: bool,
+        /// Compare only these fields, comma-separated
+        #[arg(long, value_delimiter = ',')]
+        fields: Vec<String>,
+    },
+    /// Check the card for spec compliance and common quality problems
+    #[command(arg_required_else_help = true)]
+    #[command(group = clap::ArgGroup::new("excluding").arg("exclude").conflicts_with("path"))]
+    Validate {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath, required_unless_present_any = ["list_lints", "input_pattern"])]
+        path: Option<PathBuf>,
+        /// Check all files matching this glob pattern, like "inventory/input/**/*.png", or all cards in a folder
+        #[arg(long = "input", conflicts_with = "path")]
+        input_pattern: Option<String>,
+        /// Names of lints to skip, comma-separated
+        #[arg(long, value_delimiter = ',')]
+        disable: Vec<String>,
+        /// Warn about descriptions shorter than this many characters
+        #[arg(long, default_value_t = 50)]
+        min_description_length: usize,
+        /// Print all lints and exit
+        #[arg(long)]
+        list_lints: bool,
+        /// Check the card as this version, whatever version it declares
+        #[arg(long = "as", value_enum)]
+        as_version: Option<card_formats::SpecVersion>,
+        /// Report objects with the same key twice, which normally keep the last value silently
+        #[arg(long)]
+        strict: bool,
+        /// Warn about lorebooks with fewer entries than this
+        #[arg(long, default_value_t = 1)]
+        min_entries: usize,
+        /// Fail on warnings too, not only on errors, like for checks in CI
+        #[arg(long)]
+        fail_on_warning: bool,
+        #[command(flatten)]
+        dates: dates::DateFilter,
+        #[command(flatten)]
+        exclude: tools::ExcludeArgs,
+    },
+    /// List {{macros}} used in the card text and report the broken ones
+    #[command(arg_required_else_help = true)]
+    Macros {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+    },
+    /// Check if a downloaded card was changed at the site it was downloaded from
+    #[command(name = "compare_versions")]
+    #[command(arg_required_else_help = true)]
+    CompareVersions {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Replace the card with the current version, keeping the old one as <name>.bak.png
+        #[arg(long)]
+        update: bool,
+        /// Abort downloads larger than this many megabytes
+        #[arg(long, default_value_t = 50)]
+        max_size: u64,
+        /// Make at most this many requests per second, to not overload the site. 0 turns the limit off
+        #[arg(long, default_value_t = tools::DEFAULT_DOWNLOAD_RATE, value_parser = tools::parse_rate)]
+        rate: f64,
+        #[command(flatten)]
+        proxy: tools::ProxyArgs,
+    },
+    /// Check that every card in the folder reads and writes back without changes, before converting them in bulk
+    #[command(name = "verify_roundtrip")]
+    #[command(arg_required_else_help = true)]
+    VerifyRoundtrip {
+        /// Path to the folder with cards
+        #[arg(value_hint = ValueHint::DirPath)]
+        path: PathBuf,
+    },
+    /// Check that this tool works, by writing and reading back a sample card
+    #[command(name = "self-test")]
+    SelfTest,
+    /// Increment character_version of the card, semver-style
+    #[command(arg_required_else_help = true)]
+    #[command(group = clap::ArgGroup::new("part").required(true))]
+    Bump {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Increment major version: 1.2.3 -> 2.0.0
+        #[arg(long, group = "part")]
+        major: bool,
+        /// Increment minor version: 1.2.3 -> 1.3.0
+        #[arg(long, group = "part")]
+        minor: bool,
+        /// Increment patch version: 1.2.3 -> 1.2.4
+        #[arg(long, group = "part")]
+        patch: bool,
+        /// Path to output file. Defaults to overwriting the input card
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Add an alternate greeting to the card
+    #[command(name = "append_greeting")]
+    #[command(arg_required_else_help = true)]
+    AppendGreeting {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Text of the greeting, or path to a text file with it
+        #[arg(long)]
+        text: String,
+        /// Add to group-only greetings instead (V3 cards only)
+        #[arg(long)]
+        group: bool,
+        /// Path to output file. Defaults to overwriting the input card
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Make a new card from a base card, with some fields taken from an overlay card
+    #[command(arg_required_else_help = true)]
+    Merge {
+        /// Card that gives all fields not taken from the overlay
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        base: PathBuf,
+        /// Card to take the fields from
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        overlay: PathBuf,
+        /// Fields of card data to take from the overlay, like "description,character_book". "image" takes the image
+        #[arg(long, value_delimiter = ',', required = true)]
+        take_from_overlay: Vec<String>,
+        /// Path to the merged card
+        #[arg(value_hint = ValueHint::FilePath)]
+        output: PathBuf,
+    },
+    /// Set many fields of the card at once, from a TOML or JSON file of field paths and values
+    #[command(arg_required_else_help = true)]
+    Apply {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// File with fields to set, like {"description": "...", "extensions.depth_prompt.depth": 4}. Paths are relative to card data
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        fields_file: PathBuf,
+        /// Path to output file. Defaults to overwriting the input card
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Replace the image of the card, keeping the card data
+    #[command(name = "set_avatar")]
+    #[command(arg_required_else_help = true)]
+    SetAvatar {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Path to the new image
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        image: PathBuf,
+        /// Path to output file. Defaults to overwriting the input card
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Fix card data that was base64-encoded twice
+    #[command(arg_required_else_help = true)]
+    Repair {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Path to output file. Defaults to overwriting the input card
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Trim whitespace in card fields, lowercase tags and collapse double spaces in the name
+    #[command(arg_required_else_help = true)]
+    Tidy {
+        /// Path to image.png, or to a folder to tidy all cards in it in place
+        #[arg(value_hint = ValueHint::AnyPath)]
+        path: PathBuf,
+        /// Path to output file, for a single card. Defaults to overwriting the input card
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+        /// Also remove alternate greetings that repeat an earlier one or the first message
+        #[arg(long)]
+        dedupe_greetings: bool,
+    },
+    /// Set spec and spec_version of the card to match the fields it has
+    #[command(name = "fix_spec")]
+    #[command(arg_required_else_help = true)]
+    FixSpec {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Path to output file. Defaults to overwriting the input card
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
     },
+    /// Remove an alternate greeting from the card
+    #[command(name = "remove_greeting")]
+    #[command(arg_required_else_help = true)]
+    RemoveGreeting {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Index of the greeting, starting from 0
+        #[arg(long)]
+        index: usize,
+        /// Remove from group-only greetings instead (V3 cards only)
+        #[arg(long)]
+        group: bool,
+        /// Path to output file. Defaults to overwriting the input card
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Blank out creator info and other fields before sharing the card
+    #[command(arg_required_else_help = true)]
+    #[command(group = clap::ArgGroup::new("what").required(true).multiple(true))]
+    Redact {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Fields to blank, comma-separated, like "creator,creator_notes". Use "extensions.<key>" to remove a single extension
+        #[arg(long, value_delimiter = ',', group = "what")]
+        fields: Vec<String>,
+        /// Clear the whole extensions object
+        #[arg(long, group = "what")]
+        all_extensions: bool,
+        /// Path to output file. Defaults to overwriting the input card
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Save a copy of the card with all text replaced by placeholder text of the same length, for sharing in bug reports
+    #[command(arg_required_else_help = true)]
+    Anonymize {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Path to output file. Defaults to <name>.anonymized.png in the output directory
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Save the character definition, as a frontend puts it into the prompt, into a text file
+    #[command(name = "export_prompt")]
+    #[command(arg_required_else_help = true)]
+    ExportPrompt {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Path to the output text file. Defaults to "<output folder>/<filename>.txt"
+        #[arg(value_hint = ValueHint::FilePath)]
+        output_path: Option<PathBuf>,
+        /// Add example messages, before the greeting
+        #[arg(long)]
+        with_examples: bool,
+        /// Greeting to end the prompt with: 0 is the first message, 1 and up are alternate greetings
+        #[arg(long, default_value_t = 0)]
+        greeting: usize,
+        /// End the prompt with a random greeting instead, telling which one was picked
+        #[arg(long, conflicts_with = "greeting")]
+        random_greeting: bool,
+        /// Seed for the random greeting, to get the same pick again
+        #[arg(long, requires = "random_greeting")]
+        seed: Option<u64>,
+    },
+    /// Print a greeting of the card with {{char}}, {{user}} and {{random}} macros filled in
+    #[command(arg_required_else_help = true)]
+    Render {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Greeting to print: 0 is the first message, 1 and up are alternate greetings
+        #[arg(long, default_value_t = 0)]
+        greeting: usize,
+        /// Print a random greeting instead, telling which one was picked
+        #[arg(long, conflicts_with = "greeting")]
+        random_greeting: bool,
+        /// Name to put in place of {{user}}
+        #[arg(long, default_value = "User")]
+        user: String,
+        /// Seed for the random greeting and {{random}} picks, to get the same picks again
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+    /// Save the lorebook of the card as a SillyTavern world info file
+    #[command(name = "export_lorebook")]
+    #[command(arg_required_else_help = true)]
+    ExportLorebook {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Path to the output JSON file. Defaults to "<output folder>/<filename>.lorebook.json"
+        #[arg(value_hint = ValueHint::FilePath)]
+        output_path: Option<PathBuf>,
+    },
+    /// Manage lorebook entries of the card
+    #[command(arg_required_else_help = true)]
+    Lorebook {
+        #[command(subcommand)]
+        command: LorebookCommands,
+    },
+    /// Manage assets of V3 cards, like icons and backgrounds
+    #[command(arg_required_else_help = true)]
+    Assets {
+        #[command(subcommand)]
+        command: AssetsCommands,
+    },
+}
+
+#[derive(Parser, Debug)]
+enum LorebookCommands {
+    /// List lorebook entries
+    #[command(arg_required_else_help = true)]
+    List {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+    },
+    /// Add a new lorebook entry
+    #[command(arg_required_else_help = true)]
+    Add {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Comma-separated keys that trigger the entry
+        #[arg(long, value_delimiter = ',')]
+        keys: Vec<String>,
+        /// Text of the entry
+        #[arg(long)]
+        content: String,
+        /// Path to output file. Defaults to overwriting the input card
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Remove lorebook entry by its index, as shown by "list"
+    #[command(arg_required_else_help = true)]
+    Remove {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Index of the entry to remove
+        #[arg(long)]
+        index: usize,
+        /// Path to output file. Defaults to overwriting the input card
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Remove entries with the same keys and content as an earlier entry
+    #[command(arg_required_else_help = true)]
+    Dedupe {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Ignore case of all keys, even of case-sensitive entries
+        #[arg(long)]
+        ignore_case_keys: bool,
+        /// Path to output file. Defaults to overwriting the input card
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Parser, Debug)]
+enum AssetsCommands {
+    /// List assets of the card
+    #[command(arg_required_else_help = true)]
+    List {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+    },
+    /// Embed a file into the card as a new asset
+    #[command(arg_required_else_help = true)]
+    Add {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Type of the asset, like "icon", "background" or "emotion"
+        #[arg(long = "type")]
+        asset_type: String,
+        /// File to embed
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        file: PathBuf,
+        /// Name of the asset. Defaults to the file name without extension
+        #[arg(long)]
+        name: Option<String>,
+        /// Path to output file. Defaults to overwriting the input card
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Remove assets with the given name, and their embedded data
+    #[command(arg_required_else_help = true)]
+    Remove {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Name of the asset to remove, as shown by "list"
+        #[arg(long)]
+        name: String,
+        /// Path to output file. Defaults to overwriting the input card
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Parser, Debug)]
+enum ProvidersCommands {
+    /// List supported sites and the URLs they accept
+    List,
 }
 
 fn main() {
@@ -160,17 +944,34 @@ fn main() {
             .init();
     }
 
-    // Print intro
-    println!("tavern card tools v{}", APP_VERSION);
+    // Print intro to stderr, so that it does not mix with piped output
+    eprintln!("tavern card tools v{}", APP_VERSION);
 
     if let Err(err) = parse_args() {
-        println!("Error: {}", err);
+        println!("Error: {:#}", err);
         std::process::exit(1);
     }
 }
 
 fn parse_args() -> Result<()> {
     let args = Cli::parse();
+    wrap::init(args.width, args.no_wrap);
+    card_formats::set_preferred_version(args.prefer);
+    tools::set_text_encoding(args.encoding);
+    let file_options = tools::FileOptions {
+        lenient: args.lenient,
+        compress: args.compress,
+        preserve_chunk_order: args.preserve_chunk_order,
+        stamp_dates: args.stamp_dates,
+    };
+    let print = wrap::PrintOptions {
+        color: color::detect(args.no_color),
+        field_max_bytes: None,
+    };
+    if let Some(base_image) = &args.base_image {
+        tools::set_base_image(base_image, &file_options)?;
+    }
+    let config = config::Config::load(Path::new(config::CONFIG_FILE_NAME))?;
 
     if args.card_path.is_none() && args.command.is_none() {
         eprintln!("Error: No command given");
@@ -179,35 +980,741 @@ fn parse_args() -> Result<()> {
     }
 
     if let Some(card_path) = args.card_path {
-        actions::print_tavern_card_from_path(&card_path)?;
+        actions::print_tavern_card_from_path(
+            &card_path,
+            None,
+            None,
+            &file_options,
+            &print,
+        )?;
         return Ok(());
     }
 
+    // Per-command output paths take precedence over this
+    let global_output_dir = args.output_dir.unwrap_or(config.output_path);
+
     match args.command.unwrap() {
-        Commands::BayaGet { url, output_path } => {
-            card_providers::baya_download::download_card_from_baya_url(&url, &output_path)?
+        Commands::BayaGet {
+            url,
+            creator,
+            from_clipboard,
+            parallel_downloads,
+            limit,
+            fail_fast,
+            output_path,
+            legacy_output_path,
+            max_size,
+            rate,
+            proxy,
+            dated_subdir,
+            sanitize,
+        } => {
+            let mut output_path =
+                output_path.or(legacy_output_path).unwrap_or(global_output_dir);
+            if dated_subdir {
+                let run_name = chrono::Local::now().format("%Y-%m-%d_%H%M%S");
+                output_path = output_path.join(run_name.to_string());
+                std::fs::create_dir_all(&output_path)?;
+                println!("Saving into {}", output_path.display());
+            }
+            let options =
+                download_options(max_size, rate, proxy, file_options)?;
+            if let Some(creator) = creator {
+                card_providers::baya_download::download_creator_catalog(
+                    &creator,
+                    &output_path,
+                    limit,
+                    fail_fast,
+                    sanitize,
+                    &options,
+                    parallel_downloads.into(),
+                )?
+            } else if let Some(url) = url {
+                card_providers::baya_download::download_card_from_baya_url(
+                    &url,
+                    &output_path,
+                    sanitize,
+                    &options,
+                )?
+            } else if from_clipboard {
+                let url = card_providers::url_from_clipboard()?;
+                card_providers::baya_download::download_card_from_baya_url(
+                    &url,
+                    &output_path,
+                    sanitize,
+                    &options,
+                )?
+            }
+        }
+        Commands::Get {
+            url,
+            from_clipboard,
+            output_path,
+            legacy_output_path,
+            max_size,
+            rate,
+            proxy,
+            sanitize,
+        } => {
+            let url = match url {
+                Some(url) => url,
+                None if from_clipboard => card_providers::url_from_clipboard()?,
+                None => bail!("Give the URL, or --from-clipboard"),
+            };
+            let output_path =
+                output_path.or(legacy_output_path).unwrap_or(global_output_dir);
+            let options =
+                download_options(max_size, rate, proxy, file_options)?;
+            let provider = card_providers::find_provider(&url)?;
+            provider.download(&url, &output_path, sanitize, &options)?
+        }
+        Commands::Providers { command } => match command {
+            ProvidersCommands::List => card_providers::list_providers(),
+        },
+        Commands::De8 {
+            path,
+            force,
+            preview,
+            input_pattern,
+            fail_fast,
+            follow_symlinks,
+            dates,
+            exclude: tools::ExcludeArgs { exclude },
+        } => {
+            if let Some(input_pattern) = input_pattern {
+                deasterisk::deasterisk_directory(
+                    Path::new(&input_pattern),
+                    force,
+                    preview,
+                    fail_fast,
+                    follow_symlinks,
+                    &dates,
+                    &exclude,
+                    &file_options,
+                    &print,
+                )?
+            } else if path.is_dir() {
+                deasterisk::deasterisk_directory(
+                    &path,
+                    force,
+                    preview,
+                    fail_fast,
+                    follow_symlinks,
+                    &dates,
+                    &exclude,
+                    &file_options,
+                    &print,
+                )?
+            } else {
+                deasterisk::deasterisk_tavern_file(
+                    &path,
+                    force,
+                    preview,
+                    &file_options,
+                    &print,
+                )?
+            }
+        }
+        Commands::Hash { path, include_volatile, dates } => {
+            fingerprint::print_fingerprints(
+                &path,
+                include_volatile,
+                &dates,
+                &file_options,
+            )?
+        }
+        Commands::Search {
+            query,
+            path,
+            json,
+            exclude: tools::ExcludeArgs { exclude },
+        } => {
+            search::search_cards(&path, &query, json, &exclude, &file_options)?
+        }
+        Commands::Stats { path, tokens, tokenizer, spec_report, dates } => {
+            let tokenizer = tokens.then_some(tokenizer);
+            stats::print_stats(
+                &path,
+                tokenizer,
+                spec_report,
+                &dates,
+                &file_options,
+            )?
         }
-        Commands::De8 { path, force } => {
-            deasterisk::deasterisk_tavern_file(&path, force)?
+        Commands::Print {
+            path,
+            examples,
+            json,
+            as_version,
+            lang,
+            strict,
+            fields_only,
+            field_max_bytes,
+        } => {
+            let print = wrap::PrintOptions { field_max_bytes, ..print };
+            if strict {
+                actions::check_duplicate_keys_in_file(&path, &file_options)?;
+            }
+            if fields_only {
+                actions::print_fields_from_path(
+                    &path,
+                    as_version,
+                    &file_options,
+                )?
+            } else if examples {
+                actions::print_examples_from_path(
+                    &path,
+                    as_version,
+                    json,
+                    &file_options,
+                )?
+            } else {
+                actions::print_tavern_card_from_path(
+                    &path,
+                    as_version,
+                    lang.as_deref(),
+                    &file_options,
+                    &print,
+                )?
+            }
         }
-        Commands::Print { path } => {
-            actions::print_tavern_card_from_path(&path)?
+        Commands::Extensions { path, key } => {
+            actions::print_extensions_from_path(
+                &path,
+                key.as_deref(),
+                &file_options,
+            )?
+        }
+        Commands::Chunks { path } => {
+            actions::print_text_chunks(&path, &file_options)?
+        }
+        Commands::Info { path } => actions::print_info(&path, &file_options)?,
+        Commands::ScanMetadata { path } => {
+            metadata::scan_metadata(&path, &file_options)?
+        }
+        Commands::IsCard { path } => std::process::exit(
+            if convert::is_tavern_card(&path, &file_options) { 0 } else { 1 },
+        ),
+        Commands::PrintJson { path } => {
+            actions::print_json_from_path(&path, &file_options)?
+        }
+        Commands::PrintJsonFile { path, strict } => {
+            if strict {
+                actions::check_duplicate_keys_in_file(&path, &file_options)?;
+            }
+            actions::print_tavern_card_from_path(
+                &path,
+                None,
+                None,
+                &file_options,
+                &print,
+            )?
         }
-        Commands::PrintJson { path } => actions::print_json_from_path(&path)?,
-        Commands::PrintJsonFile { path } => actions::print_json_card_from_path(&path)?,
         Commands::ExtractJson {
             image_path,
             output_path,
-        } => actions::extract_json_from_png(&image_path, &output_path)?,
-        Commands::ExtractImage {
-            image_path,
-            output_path,
-        } => actions::extract_image_from_png(&image_path, &output_path)?,
+            charset,
+            include_image_hash,
+            as_version,
+            flatten,
+        } => actions::extract_json_from_png(
+            &image_path,
+            &output_path.unwrap_or_else(|| {
+                output_file_path(&global_output_dir, &image_path, "json")
+            }),
+            charset.as_deref(),
+            include_image_hash,
+            as_version,
+            flatten,
+            &file_options,
+        )?,
+        Commands::ExtractImage { image_path, output_path, format, quality } => {
+            actions::extract_image_from_png(
+                &image_path,
+                &output_path.unwrap_or_else(|| {
+                    output_file_path(&global_output_dir, &image_path, "png")
+                }),
+                format,
+                quality,
+                &file_options,
+            )?
+        }
+        Commands::New { name, version, output } => {
+            let output = output.unwrap_or_else(|| {
+                let file_name =
+                    tools::sanitize_filename(&name, Default::default());
+                global_output_dir.join(format!("{}.png", file_name))
+            });
+            new_card::write_new_card(&output, version, &name, &file_options)?
+        }
+        Commands::Convert {
+            input,
+            output,
+            format_in,
+            format_out,
+            image,
+            to_v2,
+            group_greetings,
+            dereference_assets,
+        } => convert::convert_card(
+            &input,
+            &output,
+            format_in,
+            format_out,
+            image.as_deref(),
+            to_v2.then_some(group_greetings),
+            dereference_assets,
+            &file_options,
+        )?,
         Commands::ProcessAll {
             input_dir,
+            input_pattern,
             output_dir,
             issue_dir,
-        } => actions::process_all_cards(&input_dir, &output_dir, &issue_dir)?,
+            only,
+            fail_fast,
+            follow_symlinks,
+            dates,
+            checksum_manifest,
+            output_template,
+            incremental,
+            dump_on_error,
+            copy_issues,
+            exclude: tools::ExcludeArgs { exclude },
+            report,
+            report_json,
+        } => {
+            let options = actions::ProcessOptions {
+                artifacts: only,
+                fail_fast,
+                follow_symlinks,
+                dates,
+                checksum_manifest,
+                template: output_template,
+                incremental,
+                dump_on_error,
+                copy_issues,
+                exclude,
+                report_path: report,
+                report_json,
+                file_options,
+            };
+            actions::process_all_cards(
+                &input_pattern
+                    .map(PathBuf::from)
+                    .or(input_dir)
+                    .unwrap_or(config.input_path),
+                &output_dir.unwrap_or(global_output_dir),
+                &issue_dir.unwrap_or(config.issue_path),
+                &config.issues,
+                &options,
+            )?
+        }
+        Commands::VerifyManifest { path } => {
+            manifest::verify_manifest(&path.unwrap_or(global_output_dir))?
+        }
+        Commands::Watch {
+            input_dir,
+            output_dir,
+            issue_dir,
+            only,
+            follow_symlinks,
+            debounce,
+        } => watch::watch_folder(
+            &input_dir.unwrap_or(config.input_path),
+            &output_dir.unwrap_or(global_output_dir),
+            &issue_dir.unwrap_or(config.issue_path),
+            &config.issues,
+            only,
+            follow_symlinks,
+            Duration::from_secs_f64(debounce),
+            &file_options,
+        )?,
+        Commands::Patch { path, patch, output } => patch::patch_tavern_file(
+            &path,
+            &patch,
+            output.as_deref(),
+            &file_options,
+        )?,
+        Commands::Diff { old_path, new_path, summary, fields } => {
+            diff::diff_card_files(
+                &old_path,
+                &new_path,
+                &fields,
+                summary,
+                &file_options,
+                &print,
+            )?
+        }
+        Commands::Validate {
+            path,
+            input_pattern,
+            disable,
+            min_description_length,
+            list_lints,
+            as_version,
+            strict,
+            min_entries,
+            fail_on_warning,
+            dates,
+            exclude: tools::ExcludeArgs { exclude },
+        } => {
+            let settings = validate::LintSettings {
+                disabled: disable,
+                min_description_length,
+                spec_version: as_version,
+                strict,
+                min_lorebook_entries: min_entries,
+            };
+            if list_lints {
+                validate::print_lints();
+            } else if let Some(input_pattern) = input_pattern {
+                let paths =
+                    tools::list_input_files(Path::new(&input_pattern), false)?;
+                let paths = tools::exclude_files(paths, &exclude);
+                let paths = dates::filter_cards(paths, &dates, &file_options);
+                validate::validate_tavern_files(
+                    &paths,
+                    &settings,
+                    fail_on_warning,
+                    &file_options,
+                    &print,
+                )?
+            } else if let Some(path) = path {
+                validate::validate_tavern_file(
+                    &path,
+                    &settings,
+                    fail_on_warning,
+                    &file_options,
+                    &print,
+                )?
+            }
+        }
+        Commands::Macros { path } => {
+            macros::print_macros_from_path(&path, &file_options)?
+        }
+        Commands::CompareVersions { path, update, max_size, rate, proxy } => {
+            let options =
+                download_options(max_size, rate, proxy, file_options)?;
+            actions::compare_with_source(
+                &path,
+                update,
+                &options,
+                &file_options,
+                &print,
+            )?
+        }
+        Commands::VerifyRoundtrip { path } => {
+            roundtrip::verify_roundtrip(&path, &file_options)?
+        }
+        Commands::SelfTest => self_test::run_self_test(&file_options)?,
+        Commands::Bump { path, major, minor, patch: _, output } => {
+            let part = if major {
+                edit::VersionPart::Major
+            } else if minor {
+                edit::VersionPart::Minor
+            } else {
+                edit::VersionPart::Patch
+            };
+            edit::bump_card_version(
+                &path,
+                part,
+                output.as_deref(),
+                &file_options,
+            )?
+        }
+        Commands::AppendGreeting { path, text, group, output } => {
+            edit::append_greeting(
+                &path,
+                &text,
+                group,
+                output.as_deref(),
+                &file_options,
+            )?
+        }
+        Commands::Merge { base, overlay, take_from_overlay, output } => {
+            edit::merge_cards(
+                &base,
+                &overlay,
+                &take_from_overlay,
+                &output,
+                &file_options,
+            )?
+        }
+        Commands::Apply { path, fields_file, output } => {
+            edit::apply_fields_file(
+                &path,
+                &fields_file,
+                output.as_deref(),
+                &file_options,
+            )?
+        }
+        Commands::SetAvatar { path, image, output } => {
+            edit::set_avatar(&path, &image, output.as_deref(), &file_options)?
+        }
+        Commands::Repair { path, output } => {
+            edit::repair_card(&path, output.as_deref(), &file_options)?
+        }
+        Commands::Tidy { path, output, dedupe_greetings } => {
+            if path.is_dir() {
+                if output.is_some() {
+                    bail!("--output can not be used with a folder");
+                }
+                edit::tidy_directory(&path, dedupe_greetings, &file_options)?
+            } else {
+                edit::tidy_card(
+                    &path,
+                    output.as_deref(),
+                    dedupe_greetings,
+                    &file_options,
+                )?
+            }
+        }
+        Commands::FixSpec { path, output } => {
+            edit::fix_spec(&path, output.as_deref(), &file_options)?
+        }
+        Commands::RemoveGreeting { path, index, group, output } => {
+            edit::remove_greeting(
+                &path,
+                index,
+                group,
+                output.as_deref(),
+                &file_options,
+            )?
+        }
+        Commands::Redact { path, fields, all_extensions, output } => {
+            edit::redact_card(
+                &path,
+                &fields,
+                all_extensions,
+                output.as_deref(),
+                &file_options,
+            )?
+        }
+        Commands::Anonymize { path, output } => {
+            let output = output.unwrap_or_else(|| {
+                output_file_path(&global_output_dir, &path, "anonymized.png")
+            });
+            anonymize::anonymize_card(&path, &output, &file_options)?
+        }
+        Commands::ExportPrompt {
+            path,
+            output_path,
+            with_examples,
+            greeting,
+            random_greeting,
+            seed,
+        } => {
+            let output_path = output_path.unwrap_or_else(|| {
+                output_file_path(&global_output_dir, &path, "txt")
+            });
+            let greeting = (!random_greeting).then_some(greeting);
+            actions::export_prompt(
+                &path,
+                &output_path,
+                with_examples,
+                greeting,
+                seed,
+                &file_options,
+            )?
+        }
+        Commands::Render { path, greeting, random_greeting, user, seed } => {
+            let greeting = (!random_greeting).then_some(greeting);
+            actions::render_greeting(
+                &path,
+                greeting,
+                &user,
+                seed,
+                &file_options,
+            )?
+        }
+        Commands::ExportLorebook { path, output_path } => {
+            let output_path = output_path.unwrap_or_else(|| {
+                output_file_path(&global_output_dir, &path, "lorebook.json")
+            });
+            lorebook::export_lorebook(&path, &output_path, &file_options)?
+        }
+        Commands::Lorebook { command } => match command {
+            LorebookCommands::List { path } => {
+                lorebook::list_entries(&path, &file_options)?
+            }
+            LorebookCommands::Add { path, keys, content, output } => {
+                lorebook::add_entry(
+                    &path,
+                    keys,
+                    content,
+                    output.as_deref(),
+                    &file_options,
+                )?
+            }
+            LorebookCommands::Remove { path, index, output } => {
+                lorebook::remove_entry(
+                    &path,
+                    index,
+                    output.as_deref(),
+                    &file_options,
+                )?
+            }
+            LorebookCommands::Dedupe { path, ignore_case_keys, output } => {
+                let output = output.as_deref();
+                lorebook::dedupe_entries(
+                    &path,
+                    ignore_case_keys,
+                    output,
+                    &file_options,
+                )?
+            }
+        },
+        Commands::Assets { command } => match command {
+            AssetsCommands::List { path } => {
+                assets::list_assets(&path, &file_options)?
+            }
+            AssetsCommands::Add { path, asset_type, file, name, output } => {
+                assets::add_asset(
+                    &path,
+                    &asset_type,
+                    &file,
+                    name.as_deref(),
+                    output.as_deref(),
+                    &file_options,
+                )?
+            }
+            AssetsCommands::Remove { path, name, output } => {
+                assets::remove_asset(
+                    &path,
+                    &name,
+                    output.as_deref(),
+                    &file_options,
+                )?
+            }
+        },
     };
     Ok(())
 }
+
+/// Builds download settings from command arguments.
+fn download_options(
+    max_size_mb: u64,
+    rate: f64,
+    proxy: tools::ProxyArgs,
+    file_options: tools::FileOptions,
+) -> Result<tools::DownloadOptions> {
+    let max_size = max_size_mb.checked_mul(1024 * 1024).with_context(|| {
+        format!("--max-size {} megabytes is too large", max_size_mb)
+    })?;
+    Ok(tools::DownloadOptions { max_size, rate, proxy, file_options })
+}
+
+/// Path for a file made from the input file: the same file name with
+/// another extension, in the output folder.
+fn output_file_path(
+    output_dir: &Path,
+    input_path: &Path,
+    extension: &str,
+) -> PathBuf {
+    let file_name = input_path.file_name().unwrap_or_default();
+    output_dir.join(file_name).with_extension(extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_baya_get_output() {
+        let args = ["tct", "baya_get", "--creator", "URL", "--output", "out"];
+        let Some(Commands::BayaGet { creator, output_path, .. }) =
+            Cli::try_parse_from(args).unwrap().command
+        else {
+            panic!("not baya_get");
+        };
+        assert_eq!(creator.as_deref(), Some("URL"));
+        assert_eq!(output_path, Some(PathBuf::from("out")));
+        let args = ["tct", "baya_get", "--creator", "URL", "out"];
+        assert!(Cli::try_parse_from(args).is_err());
+        let args = ["tct", "baya_get", "URL", "--limit", "5"];
+        assert!(Cli::try_parse_from(args).is_err());
+        let args = ["tct", "baya_get", "URL", "--parallel-downloads", "2"];
+        assert!(Cli::try_parse_from(args).is_err());
+        let args = ["tct", "baya_get", "--creator", "URL"];
+        assert!(Cli::try_parse_from(args).is_ok());
+    }
+
+    #[test]
+    fn test_field_max_bytes() {
+        let args = |n| ["tct", "print", "card.json", "--field-max-bytes", n];
+        assert!(Cli::try_parse_from(args("0")).is_err());
+        assert!(Cli::try_parse_from(args("1")).is_ok());
+    }
+
+    #[test]
+    fn test_positional_output() {
+        for command in ["baya_get", "get"] {
+            let output = |args: &[&str]| -> Result<_, clap::Error> {
+                let args = [&["tct", command, "URL"], args].concat();
+                match Cli::try_parse_from(args)?.command {
+                    Some(Commands::BayaGet {
+                        output_path,
+                        legacy_output_path,
+                        ..
+                    })
+                    | Some(Commands::Get {
+                        output_path,
+                        legacy_output_path,
+                        ..
+                    }) => Ok(output_path.or(legacy_output_path)),
+                    _ => panic!("not {}", command),
+                }
+            };
+            let out = Some(PathBuf::from("out"));
+            assert_eq!(output(&["out"]).unwrap(), out);
+            assert_eq!(output(&["--output", "out"]).unwrap(), out);
+            assert!(output(&["out", "--output", "other"]).is_err());
+            assert!(output(&["--from-clipboard", "out"]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_from_clipboard_output() {
+        for command in ["get", "baya_get"] {
+            let args = ["tct", command, "--from-clipboard", "--output", "out"];
+            let cli = Cli::try_parse_from(args).unwrap();
+            let (Some(Commands::Get { output_path, .. })
+            | Some(Commands::BayaGet { output_path, .. })) = cli.command
+            else {
+                panic!("not {}", command);
+            };
+            assert_eq!(output_path, Some(PathBuf::from("out")));
+            let args = ["tct", command, "--from-clipboard", "URL"];
+            assert!(Cli::try_parse_from(args).is_err());
+        }
+    }
+
+    #[test]
+    fn test_validate_exclude() {
+        let args = ["tct", "validate", "card.png", "--exclude", "*de8*"];
+        assert!(Cli::try_parse_from(args).is_err());
+        let args = ["tct", "validate", "--input", "in", "--exclude", "*de8*"];
+        assert!(Cli::try_parse_from(args).is_ok());
+    }
+
+    #[test]
+    fn test_global_flags_combine() {
+        let args = ["tct", "--compress", "--lenient", "print", "card.png"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert!(cli.compress && cli.lenient);
+        let args = ["tct", "--prefer", "v2", "--encoding", "utf8-bom", "a.png"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert_eq!(cli.prefer, card_formats::PreferredVersion::V2);
+        assert_eq!(cli.card_path, Some(PathBuf::from("a.png")));
+        let args = ["tct", "--width", "80", "--no-wrap", "a.png"];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! Checksum manifests of processed cards.
+//!
+//! The manifest is a `checksums.txt` file in the output folder, in the
+//! format of `sha256sum`: a hash and a file name relative to the folder on
+//! each line. It can also be checked with `sha256sum -c checksums.txt`.
+//!
+//! `process_all --incremental` keeps hashes of the input cards that were
+//! processed in a state file of the same format in the output folder.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+
+pub const MANIFEST_FILE_NAME: &str = "checksums.txt";
+
+/// State of incremental processing, in the output folder.
+pub const STATE_FILE_NAME: &str = ".process_all_state.txt";
+
+/// Returns SHA-256 of the file content, as hex.
+pub fn file_sha256(path: &Path) -> Result<String> {
+    let mut file = File::open(path)
+        .with_context(|| format!("Could not read {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Could not read {}", path.display()))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Reads manifest into a map from file name to hash.
+fn read_manifest(manifest_path: &Path) -> Result<BTreeMap<String, String>> {
+    let text = std::fs::read_to_string(manifest_path).with_context(|| {
+        format!("Could not read {}", manifest_path.display())
+    })?;
+    let mut entries = BTreeMap::new();
+    for (index, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        // sha256sum marks binary mode with '*' before the name
+        let Some((hash, name)) =
+            line.split_once("  ").or_else(|| line.split_once(" *"))
+        else {
+            bail!("Line {} of the manifest is not \"hash  name\"", index + 1);
+        };
+        entries.insert(name.to_string(), hash.to_string());
+    }
+    Ok(entries)
+}
+
+/// Adds hashes of the files to the manifest in the folder, replacing the
+/// entries of files hashed before. Entries of other files are kept.
+pub fn update_manifest(dir: &Path, files: &[PathBuf]) -> Result<PathBuf> {
+    let manifest_path = dir.join(MANIFEST_FILE_NAME);
+    let mut entries = if manifest_path.exists() {
+        read_manifest(&manifest_path)?
+    } else {
+        BTreeMap::new()
+    };
+    for file in files {
+        let name = file.strip_prefix(dir).unwrap_or(file);
+        entries.insert(name.to_string_lossy().to_string(), file_sha256(file)?);
+    }
+    write_manifest(&manifest_path, &entries)?;
+    Ok(manifest_path)
+}
+
+fn write_manifest(
+    manifest_path: &Path,
+    entries: &BTreeMap<String, String>,
+) -> Result<()> {
+    let text: String = entries
+        .iter()
+        .map(|(name, hash)| format!("{}  {}\n", hash, name))
+        .collect();
+    std::fs::write(manifest_path, text)
+        .with_context(|| format!("Could not write {}", manifest_path.display()))
+}
+
+/// Reads hashes of the input cards processed before into the output
+/// folder, by their path. Gives none if nothing was processed yet.
+pub fn read_state(output_dir: &Path) -> Result<BTreeMap<String, String>> {
+    let state_path = output_dir.join(STATE_FILE_NAME);
+    if !state_path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    read_manifest(&state_path)
+}
+
+/// Writes hashes of the processed input cards into the output folder.
+pub fn write_state(
+    output_dir: &Path,
+    state: &BTreeMap<String, String>,
+) -> Result<()> {
+    std::fs::create_dir_all(output_dir)?;
+    write_manifest(&output_dir.join(STATE_FILE_NAME), state)
+}
+
+/// Hashes the files of the manifest again and reports the ones that changed
+/// or are missing. Fails if any did.
+///
+/// `path` is the manifest file, or the folder that has it.
+pub fn verify_manifest(path: &Path) -> Result<()> {
+    let manifest_path = if path.is_dir() {
+        path.join(MANIFEST_FILE_NAME)
+    } else {
+        path.to_path_buf()
+    };
+    let dir = manifest_path.parent().unwrap_or(Path::new("."));
+    let entries = read_manifest(&manifest_path)?;
+    let mut drifted = 0;
+    for (name, hash) in &entries {
+        let file = dir.join(name);
+        if !file.exists() {
+            println!("MISSING  {}", name);
+            drifted += 1;
+        } else if file_sha256(&file)? != hash.to_lowercase() {
+            println!("CHANGED  {}", name);
+            drifted += 1;
+        }
+    }
+    println!(
+        "{} files checked, {} unchanged",
+        entries.len(),
+        entries.len() - drifted
+    );
+    if drifted > 0 {
+        bail!("{} files changed or are missing", drifted);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest() -> Result<()> {
+        let dir = std::env::temp_dir().join("taverntools_test_manifest");
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("a.json"), "hello")?;
+        std::fs::write(dir.join("b.png"), "image")?;
+        let files = [dir.join("a.json"), dir.join("b.png")];
+        let manifest_path = update_manifest(&dir, &files)?;
+        let text = std::fs::read_to_string(&manifest_path)?;
+        assert!(text.starts_with(
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824  \
+             a.json\n"
+        ));
+        verify_manifest(&dir)?;
+
+        std::fs::write(dir.join("a.json"), "changed")?;
+        assert!(verify_manifest(&manifest_path).is_err());
+
+        assert!(read_state(&dir)?.is_empty());
+        let state = BTreeMap::from([("in/card.png".into(), "abc".into())]);
+        write_state(&dir, &state)?;
+        assert_eq!(read_state(&dir)?, state);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
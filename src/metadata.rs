@@ -0,0 +1,162 @@
+//! Scan of PNG metadata that is not card data.
+//!
+//! Card art made with image generators often carries their metadata, like
+//! prompts and settings, or content credentials, in chunks of its own. The
+//! scan lists such chunks and flags the ones that look out of place, so that
+//! they can be stripped before the card is shared.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::assets::ASSET_CHUNK_PREFIX;
+use crate::card_formats::tavern_card_v2::TEXT_KEY_PNG;
+use crate::card_formats::tavern_card_v3::TEXT_KEY_CCV3;
+use crate::tools::{self, FileOptions, PngChunk};
+use crate::wrap;
+
+/// Chunks with the image itself.
+const CRITICAL_CHUNKS: &[&str] = &["IHDR", "PLTE", "IDAT", "IEND"];
+
+/// Ancillary chunks of the PNG specification, and of APNG.
+const STANDARD_CHUNKS: &[&str] = &[
+    "tEXt", "zTXt", "iTXt", "tIME", "pHYs", "gAMA", "cHRM", "sRGB", "iCCP",
+    "sBIT", "bKGD", "hIST", "tRNS", "sPLT", "eXIf", "cICP", "mDCV", "cLLI",
+    "acTL", "fcTL", "fdAT",
+];
+
+/// Chunk with C2PA content credentials.
+const CREDENTIALS_CHUNK: &str = "caBX";
+
+/// Keywords of text chunks written by image generators and editors.
+const GENERATOR_KEYWORDS: &[&str] = &[
+    "parameters",
+    "prompt",
+    "workflow",
+    "comment",
+    "sd-metadata",
+    "invokeai_metadata",
+    "dream",
+    "xml:com.adobe.xmp",
+];
+
+/// Ancillary chunks larger than this are flagged.
+const OVERSIZED_BYTES: usize = 64 * 1024;
+
+/// How much of the text of a chunk is shown.
+const SHOWN_BYTES: usize = 60;
+
+/// Returns true for the chunks that hold card data or embedded assets.
+fn is_card_chunk(chunk: &PngChunk) -> bool {
+    let Some((keyword, _)) = &chunk.text else {
+        return false;
+    };
+    keyword.eq_ignore_ascii_case(TEXT_KEY_PNG)
+        || keyword.eq_ignore_ascii_case(TEXT_KEY_CCV3)
+        || keyword.starts_with(ASSET_CHUNK_PREFIX)
+}
+
+/// Returns what looks out of place about the chunk, if anything.
+fn chunk_findings(chunk: &PngChunk) -> Vec<String> {
+    let mut findings = Vec::new();
+    let chunk_type = chunk.chunk_type.as_str();
+    if chunk_type == CREDENTIALS_CHUNK {
+        findings.push("C2PA content credentials".to_string());
+    } else if !STANDARD_CHUNKS.contains(&chunk_type) {
+        findings.push("non-standard chunk type".to_string());
+    }
+    if chunk.length > OVERSIZED_BYTES {
+        findings.push(format!("oversized, {} bytes", chunk.length));
+    }
+    let is_text = ["tEXt", "zTXt", "iTXt"].contains(&chunk_type);
+    match &chunk.text {
+        Some((keyword, _)) => {
+            let keyword = keyword.to_lowercase();
+            if GENERATOR_KEYWORDS.contains(&keyword.as_str()) {
+                findings.push("image generator metadata".to_string());
+            }
+        }
+        None if is_text => findings.push("text can not be read".to_string()),
+        None => {}
+    }
+    findings
+}
+
+/// Lists the ancillary chunks of the image that are not card data, and
+/// flags the suspicious ones: oversized, non-standard, unreadable, or
+/// written by image generators. Data after the end of the image is flagged
+/// too. Nothing is changed.
+pub fn scan_metadata(path: &Path, file_options: &FileOptions) -> Result<()> {
+    let image = tools::read_image_from_file(path, file_options)?;
+    let (chunks, trailing) = tools::list_png_chunks(&image)?;
+    let mut listed = 0;
+    let mut flagged = 0;
+    for chunk in &chunks {
+        if CRITICAL_CHUNKS.contains(&chunk.chunk_type.as_str())
+            || is_card_chunk(chunk)
+        {
+            continue;
+        }
+        listed += 1;
+        let mut line = format!("{} ({} bytes)", chunk.chunk_type, chunk.length);
+        if let Some((keyword, text)) = &chunk.text {
+            let text = text.escape_debug().to_string();
+            let text = wrap::truncate_to(&text, SHOWN_BYTES);
+            line = format!("{} {}: {}", line, keyword, text);
+        }
+        println!("{}", line);
+        let findings = chunk_findings(chunk);
+        if !findings.is_empty() {
+            flagged += 1;
+            println!("  Suspicious: {}", findings.join(", "));
+        }
+    }
+    if trailing > 0 {
+        flagged += 1;
+        println!("Suspicious: {} bytes after the end of the image", trailing);
+    }
+    if listed == 0 {
+        println!("The image has no metadata besides the card");
+    }
+    if flagged == 0 {
+        println!("Nothing suspicious found");
+    } else {
+        println!("Found {} suspicious items", flagged);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(chunk_type: &str, length: usize, keyword: &str) -> PngChunk {
+        let text = (!keyword.is_empty())
+            .then(|| (keyword.to_string(), "text".to_string()));
+        PngChunk { chunk_type: chunk_type.to_string(), length, text }
+    }
+
+    #[test]
+    fn test_chunk_findings() {
+        assert!(chunk_findings(&chunk("pHYs", 9, "")).is_empty());
+        assert!(chunk_findings(&chunk("tEXt", 10, "Title")).is_empty());
+        assert!(is_card_chunk(&chunk("tEXt", 10, "chara")));
+        assert!(!is_card_chunk(&chunk("tEXt", 10, "Title")));
+        assert_eq!(
+            chunk_findings(&chunk("tEXt", 100_000, "parameters")),
+            vec!["oversized, 100000 bytes", "image generator metadata"]
+        );
+        assert_eq!(
+            chunk_findings(&chunk("caBX", 10, "")),
+            vec!["C2PA content credentials"]
+        );
+        assert_eq!(
+            chunk_findings(&chunk("stEG", 10, "")),
+            vec!["non-standard chunk type"]
+        );
+        assert_eq!(
+            chunk_findings(&chunk("zTXt", 10, "")),
+            vec!["text can not be read"]
+        );
+    }
+}
@@ -0,0 +1,90 @@
+//! Creation of new cards from a template.
+//!
+//! The template is a minimal card that passes `validate`, with placeholder
+//! text in the fields a card needs, to be replaced by the author.
+
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+
+use crate::card_formats::{write_card_json, PreferredVersion};
+use crate::tools::{self, FileOptions};
+
+/// Returns JSON of a new card of the version, with placeholder text.
+fn template_card(version: PreferredVersion, name: &str) -> Value {
+    let (spec, spec_version) = match version {
+        PreferredVersion::V2 => ("chara_card_v2", "2.0"),
+        PreferredVersion::V3 => ("chara_card_v3", "3.0"),
+    };
+    let mut card = json!({
+        "spec": spec,
+        "spec_version": spec_version,
+        "data": {
+            "name": name,
+            "description": "Describe {{char}} here: appearance, background, \
+                and anything else the model should know.",
+            "personality": "Describe the personality of {{char}}.",
+            "scenario": "Describe where and how {{char}} meets {{user}}.",
+            "first_mes": "Write the first message of {{char}} to {{user}}.",
+            "mes_example": "<START>\n{{user}}: Hello!\n{{char}}: Hello, \
+                {{user}}!",
+            "creator_notes": "",
+            "system_prompt": "",
+            "post_history_instructions": "",
+            "alternate_greetings": [],
+            "tags": [],
+            "creator": "",
+            "character_version": "1.0",
+            "extensions": {}
+        }
+    });
+    if version == PreferredVersion::V3 {
+        card["data"]["group_only_greetings"] = json!([]);
+    }
+    card
+}
+
+/// Writes a new card of the version, named `name`, with placeholder text in
+/// its fields and the base image, see [`tools::base_image`]. Creates folders
+/// as needed, and does not overwrite an existing file.
+pub fn write_new_card(
+    output_path: &Path,
+    version: PreferredVersion,
+    name: &str,
+    file_options: &FileOptions,
+) -> Result<()> {
+    if output_path.exists() {
+        bail!("{} already exists", output_path.display());
+    }
+    if let Some(parent) = output_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let card_json = template_card(version, name);
+    let image =
+        write_card_json(&card_json, &tools::base_image(), file_options)?;
+    tools::write_image_to_file(&image, output_path)?;
+    println!(
+        "New {:?} card {} written to {}",
+        version,
+        name,
+        output_path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate::{lint_card, LintSettings};
+
+    #[test]
+    fn test_template_passes_validation() {
+        for version in [PreferredVersion::V2, PreferredVersion::V3] {
+            let card = template_card(version, "Test");
+            assert_eq!(lint_card(&card, &LintSettings::default()), vec![]);
+        }
+        let card = template_card(PreferredVersion::V2, "Test");
+        assert!(card["data"].get("group_only_greetings").is_none());
+    }
+}
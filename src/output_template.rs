@@ -0,0 +1,134 @@
+//! Output paths of processed cards, built from the card data.
+//!
+//! A template like `{creator}/{name}` gives the path of the outputs relative
+//! to the output folder, without extension. Placeholders are:
+//! `{name}`, `{creator}`, `{tag}` (the first tag), `{version}` (the
+//! character version) and `{stem}` (the input file name without extension).
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+use crate::tools::{self, FileOptions, SanitizeStrategy};
+
+/// Used for placeholders whose field is missing or empty.
+const UNKNOWN: &str = "unknown";
+
+/// Replaces the placeholders of the template with card fields.
+///
+/// Field values are sanitized to be safe file names, so they can not add
+/// folders. Fails on unknown placeholders and on templates leaving the
+/// output folder.
+pub fn expand_template(
+    template: &str,
+    card_json: &Value,
+    stem: &str,
+) -> Result<PathBuf> {
+    let data = card_json.get("data").unwrap_or(card_json);
+    let text = |field: &str| data.get(field).and_then(|x| x.as_str());
+    let first_tag = data
+        .get("tags")
+        .and_then(|x| x.as_array())
+        .and_then(|x| x.first())
+        .and_then(|x| x.as_str());
+
+    let mut path = PathBuf::new();
+    for component in template.split(['/', '\\']) {
+        if component.is_empty() || component == "." || component == ".." {
+            bail!("Invalid output template {}", template);
+        }
+        let mut expanded = String::new();
+        let mut rest = component;
+        while let Some(start) = rest.find('{') {
+            expanded += &rest[..start];
+            let Some(length) = rest[start..].find('}') else {
+                bail!("Unclosed placeholder in output template {}", template);
+            };
+            let value = match &rest[start + 1..start + length] {
+                "name" => text("name"),
+                "creator" => text("creator"),
+                "tag" => first_tag,
+                "version" => text("character_version"),
+                "stem" => Some(stem),
+                other => bail!("Unknown placeholder {{{}}} in output template, \
+                                use {{name}}, {{creator}}, {{tag}}, {{version}} \
+                                or {{stem}}", other),
+            };
+            let value = value.map(str::trim).filter(|x| !x.is_empty());
+            expanded += &tools::sanitize_filename(
+                value.unwrap_or(UNKNOWN),
+                SanitizeStrategy::Conservative,
+            );
+            rest = &rest[start + length + 1..];
+        }
+        expanded += rest;
+        path.push(expanded);
+    }
+    Ok(path)
+}
+
+/// Adds a numeric suffix to the path if it is in `used`, like "name_2",
+/// and marks the result as used.
+pub fn unique_path(path: PathBuf, used: &mut HashSet<PathBuf>) -> PathBuf {
+    let mut unique = path.clone();
+    let mut number = 2;
+    while used.contains(&unique) {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        unique = path.with_file_name(format!("{}_{}", name, number));
+        number += 1;
+    }
+    used.insert(unique.clone());
+    unique
+}
+
+/// Returns path of the outputs of the card in the output folder, without
+/// extension, from the template or the input file name.
+pub fn output_base(
+    file_path: &Path,
+    output_dir: &Path,
+    template: Option<&str>,
+    file_options: &FileOptions,
+) -> Result<PathBuf> {
+    let stem = file_path.file_stem().unwrap_or_default().to_string_lossy();
+    let Some(template) = template else {
+        return Ok(output_dir.join(stem.as_ref()));
+    };
+    let image = tools::read_image_from_file(file_path, file_options)?;
+    let card_json = crate::card_formats::read_card_json(&image)?;
+    Ok(output_dir.join(expand_template(template, &card_json, &stem)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_expand_template() -> Result<()> {
+        let card = json!({
+            "data": { "name": "Wolf/Dog", "creator": "", "tags": ["Fur"] }
+        });
+        let path =
+            expand_template("{creator}/{tag}/{name}-{stem}", &card, "a")?;
+        assert_eq!(path, PathBuf::from("unknown/Fur/Wolf_Dog-a"));
+        assert!(expand_template("{nope}", &card, "a").is_err());
+        assert!(expand_template("../{name}", &card, "a").is_err());
+        assert!(expand_template("{name", &card, "a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unique_path() {
+        let mut used = HashSet::new();
+        let path = PathBuf::from("out/A");
+        assert_eq!(unique_path(path.clone(), &mut used), path);
+        assert_eq!(
+            unique_path(path.clone(), &mut used),
+            PathBuf::from("out/A_2")
+        );
+        assert_eq!(unique_path(path, &mut used), PathBuf::from("out/A_3"));
+    }
+}
@@ -0,0 +1,129 @@
+//! Scripted edits of card JSON with JSON Patch (RFC 6902).
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use json_patch::{AddOperation, Patch, PatchOperation};
+use serde_json::Value;
+
+use crate::card_formats::validate_card_json;
+use crate::edit::edit_card_file;
+use crate::tools::{self, FileOptions};
+
+/// Turns patch document into the list of patch operations.
+///
+/// Accepts either a JSON Patch array, or an object where each key is a JSON
+/// Pointer and each value is the value to assign at that location.
+fn parse_patch(patch_doc: &Value) -> Result<Patch> {
+    match patch_doc {
+        Value::Array(_) => {
+            let patch = serde_json::from_value(patch_doc.clone())
+                .context("Not a valid JSON Patch document")?;
+            Ok(patch)
+        }
+        Value::Object(assignments) => {
+            let operations = assignments
+                .iter()
+                .map(|(path, value)| {
+                    PatchOperation::Add(AddOperation {
+                        path: path.clone(),
+                        value: value.clone(),
+                    })
+                })
+                .collect();
+            Ok(Patch(operations))
+        }
+        _ => bail!("Patch must be a JSON array or an object of assignments"),
+    }
+}
+
+/// Applies patch to card JSON.
+///
+/// The card JSON is left untouched if any operation fails or if the result
+/// is not a valid tavern card anymore.
+pub fn apply_patch(card_json: &mut Value, patch_doc: &Value) -> Result<()> {
+    let patch = parse_patch(patch_doc)?;
+    let mut patched = card_json.clone();
+    json_patch::patch(&mut patched, &patch)
+        .map_err(|e| anyhow::anyhow!("Patch failed: {}", e))?;
+    validate_card_json(&patched)?;
+    *card_json = patched;
+    Ok(())
+}
+
+/// Applies patch file to the card and writes the edited card.
+///
+/// Writes into `output_path` if given, otherwise overwrites the card.
+pub fn patch_tavern_file(
+    png_path: &Path,
+    patch_path: &Path,
+    output_path: Option<&Path>,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let patch_text = tools::read_text_file(patch_path)?;
+    let patch_doc: Value = serde_json::from_str(&patch_text)
+        .with_context(|| format!("Could not parse {}", patch_path.display()))?;
+
+    edit_card_file(png_path, output_path, file_options, |card_json| {
+        apply_patch(card_json, &patch_doc)
+    })?;
+    let output_path = output_path.unwrap_or(png_path);
+    println!("Patched card written to {}", output_path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn create_test_json() -> Value {
+        json!({
+            "spec": "chara_card_v2",
+            "spec_version": "2.0",
+            "data": {
+                "name": "Test name",
+                "tags": ["tag1"]
+            }
+        })
+    }
+
+    #[test]
+    fn test_json_patch() -> Result<()> {
+        let mut card = create_test_json();
+        let patch = json!([
+            { "op": "replace", "path": "/data/name", "value": "New name" },
+            { "op": "add", "path": "/data/tags/-", "value": "tag2" }
+        ]);
+        apply_patch(&mut card, &patch)?;
+        assert_eq!(card["data"]["name"], "New name");
+        assert_eq!(card["data"]["tags"], json!(["tag1", "tag2"]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pointer_assignments() -> Result<()> {
+        let mut card = create_test_json();
+        let patch = json!({ "/data/scenario": "Test scenario" });
+        apply_patch(&mut card, &patch)?;
+        assert_eq!(card["data"]["scenario"], "Test scenario");
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_patch_leaves_card_untouched() {
+        let mut card = create_test_json();
+        let patch = json!([
+            { "op": "replace", "path": "/data/name", "value": "New name" },
+            { "op": "replace", "path": "/data/missing/field", "value": 1 }
+        ]);
+        assert!(apply_patch(&mut card, &patch).is_err());
+        assert_eq!(card, create_test_json());
+
+        // Removing the data block makes it not a card anymore.
+        let patch = json!([{ "op": "remove", "path": "/data" }]);
+        assert!(apply_patch(&mut card, &patch).is_err());
+        assert_eq!(card, create_test_json());
+    }
+}
@@ -0,0 +1,127 @@
+//! Summary report of a `process_all` run.
+//!
+//! The report tells which cards were processed, which were skipped as
+//! unchanged, and which failed, by the issue subfolder they went to. It is
+//! written as text, or as JSON for scripts.
+
+use std::path::Path;
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::config::IssueLayout;
+use crate::tools;
+
+/// Cards of a `process_all` run, by what happened to them.
+#[derive(Debug, Default, PartialEq)]
+pub struct RunReport {
+    pub succeeded: Vec<String>,
+    /// Cards skipped by `--incremental`, as they did not change
+    pub skipped: Vec<String>,
+    /// Failed cards by issue subfolder, in the order of the issue layout
+    pub issues: Vec<(String, Vec<String>)>,
+}
+
+impl RunReport {
+    /// Creates an empty report, with every subfolder of the issue layout,
+    /// so that empty ones are reported too.
+    pub fn new(issue_layout: &IssueLayout) -> Self {
+        let issues = issue_layout
+            .folders()
+            .into_iter()
+            .map(|folder| (folder.to_string(), Vec::new()))
+            .collect();
+        RunReport { issues, ..Default::default() }
+    }
+
+    /// Records a card that failed and went to the issue subfolder.
+    pub fn add_issue(&mut self, folder: &str, file: &Path) {
+        let file = file.display().to_string();
+        match self.issues.iter_mut().find(|(name, _)| name == folder) {
+            Some((_, files)) => files.push(file),
+            None => self.issues.push((folder.to_string(), vec![file])),
+        }
+    }
+
+    /// Returns the report as text, counts first, then the lists of files.
+    pub fn to_text(&self) -> String {
+        let mut groups = vec![("Succeeded".to_string(), &self.succeeded)];
+        if !self.skipped.is_empty() {
+            groups.push(("Skipped as unchanged".to_string(), &self.skipped));
+        }
+        for (folder, files) in &self.issues {
+            groups.push((format!("Failed, in {}", folder), files));
+        }
+
+        let mut text = String::new();
+        for (title, files) in &groups {
+            text += &format!("{}: {}\n", title, files.len());
+        }
+        for (title, files) in groups.iter().filter(|x| !x.1.is_empty()) {
+            text += &format!("\n{}:\n", title);
+            for file in files.iter() {
+                text += &format!("  {}\n", file);
+            }
+        }
+        text
+    }
+
+    /// Returns the report as JSON, with counts and lists of files.
+    pub fn to_json(&self) -> Value {
+        let issues: serde_json::Map<String, Value> = self
+            .issues
+            .iter()
+            .map(|(folder, files)| (folder.clone(), json!(files)))
+            .collect();
+        json!({
+            "succeeded": self.succeeded.len(),
+            "skipped": self.skipped.len(),
+            "failed": self.issues.iter().map(|x| x.1.len()).sum::<usize>(),
+            "files": {
+                "succeeded": self.succeeded,
+                "skipped": self.skipped,
+                "issues": issues,
+            }
+        })
+    }
+
+    /// Writes the report into the file, as text or as JSON, in the encoding
+    /// set with `--encoding`.
+    pub fn write(&self, path: &Path, as_json: bool) -> Result<()> {
+        let text = if as_json {
+            serde_json::to_string_pretty(&self.to_json())? + "\n"
+        } else {
+            self.to_text()
+        };
+        tools::write_text_file(path, &text)?;
+        println!("Report written to {}", path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_report() {
+        let mut report = RunReport::new(&IssueLayout::default());
+        report.succeeded.push("in/a.png".to_string());
+        report.add_issue("format", Path::new("in/b.png"));
+        let expected = "Succeeded: 1\n\
+            Failed, in unsupported: 0\n\
+            Failed, in format: 1\n\
+            Failed, in no_data: 0\n\
+            Failed, in other: 0\n\
+            \n\
+            Succeeded:\n  in/a.png\n\
+            \n\
+            Failed, in format:\n  in/b.png\n";
+        assert_eq!(report.to_text(), expected);
+
+        let json = report.to_json();
+        assert_eq!(json["failed"], 1);
+        assert_eq!(json["files"]["issues"]["format"][0], "in/b.png");
+        assert_eq!(json["files"]["issues"]["other"], json!([]));
+    }
+}
@@ -0,0 +1,120 @@
+//! Check that cards survive being read and written by this tool.
+
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+use crate::actions::AnyTavernCard;
+use crate::card_formats::read_card_json;
+use crate::diff::diff_cards;
+use crate::fingerprint::normalize;
+use crate::tools::{self, FileOptions};
+
+/// Removes object keys set to `null`, at any depth.
+fn drop_nulls(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            map.retain(|_, x| !x.is_null());
+            map.values_mut().for_each(drop_nulls);
+        }
+        Value::Array(items) => items.iter_mut().for_each(drop_nulls),
+        _ => (),
+    }
+}
+
+/// Returns the fields that differ between the card JSON and the same card
+/// written back, like "description" or "spec".
+///
+/// Both are normalized as for hashing, so key order, line endings and
+/// trailing whitespace do not count, and neither do fields set to `null`
+/// instead of missing. Dates count.
+fn roundtrip_changes(original: &Value, written: &Value) -> Vec<String> {
+    let prepare = |value: &Value| {
+        let mut value = normalize(value, true);
+        drop_nulls(&mut value);
+        value
+    };
+    let (original, written) = (prepare(original), prepare(written));
+    let mut changes: Vec<String> =
+        diff_cards(&original, &written).into_iter().map(|x| x.field).collect();
+    let keys = |card: &Value| -> Vec<String> {
+        card.as_object()
+            .map(|x| x.keys().filter(|x| *x != "data").cloned().collect())
+            .unwrap_or_default()
+    };
+    let mut top_level = keys(&original);
+    top_level.extend(keys(&written));
+    top_level.sort();
+    top_level.dedup();
+    for key in top_level {
+        if original.get(&key) != written.get(&key) {
+            changes.push(key);
+        }
+    }
+    changes
+}
+
+/// Reads every card in the folder into the card structures and writes it
+/// back to JSON, reporting the cards that do not come back the same.
+///
+/// Such cards have fields that the tool would lose or change, for example
+/// when converting them, see [`roundtrip_changes`]. Returns error if any
+/// card does not round-trip, or can not be read.
+pub fn verify_roundtrip(
+    dir_path: &Path,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let png_paths = tools::list_png_files(dir_path, false)?;
+    let (mut lossy, mut failed) = (0, 0);
+    for png_path in &png_paths {
+        let image = tools::read_image_from_file(png_path, file_options);
+        let result = image.map_err(anyhow::Error::from).and_then(|image| {
+            let original = read_card_json(&image)?;
+            let card = AnyTavernCard::from_json(&original.to_string())?;
+            let written: Value = serde_json::from_str(&card.to_json()?)?;
+            Ok(roundtrip_changes(&original, &written))
+        });
+        match result {
+            Ok(changes) if changes.is_empty() => (),
+            Ok(changes) => {
+                println!("{}: {}", png_path.display(), changes.join(", "));
+                lossy += 1;
+            }
+            Err(e) => {
+                println!("{}: could not read: {:#}", png_path.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    let clean = png_paths.len() - lossy - failed;
+    println!("{} of {} cards round-trip cleanly", clean, png_paths.len());
+    if lossy + failed > 0 {
+        bail!("{} cards changed, {} could not be read", lossy, failed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_roundtrip_changes() {
+        let original = json!({
+            "spec": "chara_card_v3",
+            "data": { "name": "A\r\nB", "tags": ["x"], "custom": 1 }
+        });
+        let written = json!({
+            "data": { "tags": ["x"], "name": "A\nB ", "creator": null },
+            "spec": "chara_card_v3",
+            "spec_version": "3.0"
+        });
+        assert_eq!(
+            roundtrip_changes(&original, &written),
+            vec!["custom", "spec_version"]
+        );
+    }
+}
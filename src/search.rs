@@ -0,0 +1,164 @@
+//! Search of text in card fields.
+
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use glob::Pattern;
+use serde_json::Value;
+
+use crate::card_formats::read_card_json;
+use crate::tools::{self, FileOptions};
+
+/// How many characters around the match are shown.
+const CONTEXT_CHARS: usize = 40;
+
+/// Text found in a card.
+#[derive(serde::Serialize, Debug, PartialEq)]
+pub struct SearchMatch {
+    pub file: String,
+    /// Path of the field in card data, like "alternate_greetings[1]"
+    pub field: String,
+    /// Matched text with some text around it, on a single line
+    pub snippet: String,
+}
+
+/// Returns the match with up to `CONTEXT_CHARS` characters on each side,
+/// with line breaks replaced by spaces.
+fn snippet(text: &str, start: usize, end: usize) -> String {
+    let before = text[..start].chars().rev().take(CONTEXT_CHARS);
+    let before_len: usize = before.map(|x| x.len_utf8()).sum();
+    let after_len: usize =
+        text[end..].chars().take(CONTEXT_CHARS).map(|x| x.len_utf8()).sum();
+    let (from, to) = (start - before_len, end + after_len);
+    let prefix = if from > 0 { "..." } else { "" };
+    let suffix = if to < text.len() { "..." } else { "" };
+    let line = text[from..to].replace(['\r', '\n'], " ");
+    format!("{}{}{}", prefix, line, suffix)
+}
+
+/// Finds `query` in every text of the JSON, ignoring ASCII case. Returns
+/// field paths with snippets, one for every occurrence.
+fn find_text(value: &Value, path: &str, query: &str) -> Vec<(String, String)> {
+    match value {
+        Value::String(text) => {
+            let lower = text.to_ascii_lowercase();
+            lower
+                .match_indices(query)
+                .map(|(i, x)| (path.to_string(), snippet(text, i, i + x.len())))
+                .collect()
+        }
+        Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .flat_map(|(i, x)| find_text(x, &format!("{}[{}]", path, i), query))
+            .collect(),
+        Value::Object(map) => map
+            .iter()
+            .flat_map(|(key, x)| {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                find_text(x, &path, query)
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Searches card data of the card, or of every card in the folder or
+/// matching the glob pattern, for the text, ignoring ASCII case.
+///
+/// Prints `file: field: snippet` for every match. With `json`, prints every
+/// match as a JSON object on its own line instead, as soon as it is found,
+/// so that the output can be read while the search goes on. Cards that can
+/// not be read are reported on stderr. When searching many cards, those
+/// matching `exclude` patterns are skipped, see [`tools::exclude_files`].
+pub fn search_cards(
+    path: &Path,
+    query: &str,
+    json: bool,
+    exclude: &[Pattern],
+    file_options: &FileOptions,
+) -> Result<()> {
+    let query = query.to_ascii_lowercase();
+    if query.is_empty() {
+        bail!("Search text is empty");
+    }
+    let png_paths = if path.is_file() {
+        vec![path.to_path_buf()]
+    } else {
+        tools::exclude_files(tools::list_input_files(path, false)?, exclude)
+    };
+
+    let mut stdout = std::io::stdout().lock();
+    let (mut found, mut failed) = (0, 0);
+    for png_path in &png_paths {
+        let card_json = tools::read_image_from_file(png_path, file_options)
+            .and_then(|image| read_card_json(&image));
+        let card_json = match card_json {
+            Ok(card_json) => card_json,
+            Err(e) => {
+                eprintln!("Failed to read {}: {:#}", png_path.display(), e);
+                failed += 1;
+                continue;
+            }
+        };
+        let data = card_json.get("data").unwrap_or(&card_json);
+        for (field, snippet) in find_text(data, "", &query) {
+            let file = png_path.display().to_string();
+            let found_match = SearchMatch { file, field, snippet };
+            if json {
+                writeln!(stdout, "{}", serde_json::to_string(&found_match)?)?;
+            } else {
+                let SearchMatch { file, field, snippet } = found_match;
+                writeln!(stdout, "{}: {}: {}", file, field, snippet)?;
+            }
+            stdout.flush()?;
+            found += 1;
+        }
+    }
+    if !json && found == 0 {
+        println!("No matches");
+    }
+    if failed > 0 {
+        bail!("{} of {} cards could not be read", failed, png_paths.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_find_text() {
+        let data = json!({
+            "name": "Knight",
+            "alternate_greetings": ["Hi", "The KNIGHT\nbows"],
+            "extensions": { "note": "no match" }
+        });
+        let found = find_text(&data, "", "knight");
+        assert_eq!(
+            found,
+            vec![
+                ("name".to_string(), "Knight".to_string()),
+                (
+                    "alternate_greetings[1]".to_string(),
+                    "The KNIGHT bows".to_string()
+                ),
+            ]
+        );
+
+        let long = format!("{}needle{}", "a".repeat(50), "ü".repeat(50));
+        let snippet = snippet(&long, 50, 56);
+        assert_eq!(
+            snippet,
+            format!("...{}needle{}...", "a".repeat(40), "ü".repeat(40))
+        );
+    }
+}
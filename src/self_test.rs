@@ -0,0 +1,111 @@
+//! End-to-end check that cards can be written and read back.
+
+use anyhow::{bail, Context, Result};
+
+use crate::card_formats::character_book::CharacterBook;
+use crate::card_formats::tavern_card_v2::{TavernCardV2, TEXT_KEY_PNG};
+use crate::card_formats::tavern_card_v3::TavernCardV3;
+use crate::card_formats::{read_card_json, write_card_json, CardFile};
+use crate::tools::{self, FileOptions};
+
+/// Round-trips a built-in sample card through PNG and JSON.
+///
+/// Prints PASS or FAIL for every step. Stops at the first failed step and
+/// returns error, so that the process exits with non-zero code.
+pub fn run_self_test(file_options: &FileOptions) -> Result<()> {
+    let result = run_steps(file_options);
+    match &result {
+        Ok(()) => println!("Self-test passed"),
+        Err(_) => println!("Self-test FAILED"),
+    }
+    result
+}
+
+fn run_steps(file_options: &FileOptions) -> Result<()> {
+    let card = step("Create sample card", || Ok(create_sample_card()))?;
+
+    let image = step("Embed card into PNG", || {
+        card.write_to_png(&tools::get_default_image(), file_options)
+    })?;
+
+    let payload = step("Read card chunk", || {
+        tools::read_text_chunk(&image, TEXT_KEY_PNG)?
+            .context("Card chunk is missing")
+    })?;
+
+    step("Parse card as V2", || {
+        let mut parsed = TavernCardV2::from_png_image(&image)?;
+        parsed.image_data = None;
+        ensure_equal(&parsed, &card)
+    })?;
+
+    step("Parse card as V3", || {
+        let parsed = TavernCardV3::from_png_image(&image)?;
+        ensure_equal(&parsed.data.name, &card.data.name)
+    })?;
+
+    let json = step("Extract JSON", || {
+        let json = read_card_json(&image)?;
+        ensure_equal(&json, &serde_json::to_value(&card)?)?;
+        Ok(json)
+    })?;
+
+    step("Re-embed JSON", || {
+        let new_image = write_card_json(&json, &image, file_options)?;
+        let new_payload = tools::read_text_chunk(&new_image, TEXT_KEY_PNG)?;
+        ensure_equal(&new_payload, &Some(payload.clone()))
+    })?;
+
+    step("Remove card chunk", || {
+        let clean_image = tools::remove_text_chunk(&image, TEXT_KEY_PNG)?;
+        let chunk = tools::read_text_chunk(&clean_image, TEXT_KEY_PNG)?;
+        ensure_equal(&chunk, &None)
+    })?;
+    Ok(())
+}
+
+/// Runs a step of the test, printing its outcome.
+fn step<T, F>(name: &str, f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    let result = f();
+    match &result {
+        Ok(_) => println!("PASS: {}", name),
+        Err(e) => println!("FAIL: {}: {:#}", name, e),
+    }
+    result.with_context(|| format!("Step \"{}\" failed", name))
+}
+
+fn ensure_equal<T>(actual: &T, expected: &T) -> Result<()>
+where
+    T: PartialEq + std::fmt::Debug,
+{
+    if actual != expected {
+        bail!("expected {:?}, got {:?}", expected, actual);
+    }
+    Ok(())
+}
+
+fn create_sample_card() -> TavernCardV2 {
+    let mut card = TavernCardV2::new();
+    card.data.name = Some(String::from("Sample name"));
+    card.data.description = Some(String::from("Sample «description» ✓"));
+    card.data.first_mes = Some(String::from("*waves* Hello, {{user}}!"));
+    card.data.alternate_greetings = Some(vec![String::from("Hi!")]);
+    card.data.tags = Some(vec![String::from("sample")]);
+    let mut book = CharacterBook::default();
+    book.add_entry(vec![String::from("key")], String::from("Sample entry"));
+    card.data.character_book = Some(book);
+    card
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_self_test() -> Result<()> {
+        run_self_test(&FileOptions::default())
+    }
+}
@@ -0,0 +1,227 @@
+//! Statistics over a collection of cards.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+use crate::card_formats::read_card_json;
+use crate::dates::{self, DateFilter};
+use crate::edit::{detect_spec, fix_spec_fields};
+use crate::tokens::{permanent_tokens, Tokenizer};
+use crate::tools::{self, FileOptions};
+
+/// Upper bounds of histogram buckets, in tokens. The last bucket is open.
+const TOKEN_BUCKETS: &[usize] = &[500, 1000, 2000, 4000, 8000];
+
+/// How many of the largest cards are listed.
+const OUTLIERS_SHOWN: usize = 5;
+
+/// Longest histogram bar, in characters.
+const BAR_WIDTH: usize = 40;
+
+/// Spec compliance of a collection of cards.
+#[derive(Debug, Default, PartialEq)]
+struct SpecReport {
+    /// Cards without data block
+    v1: usize,
+    /// Cards by their fields, whatever they declare, see [`detect_spec`]
+    v2: usize,
+    v3: usize,
+    /// Cards whose `spec` and `spec_version` name different versions
+    mismatched: usize,
+    /// Cards that `fix_spec` would change
+    need_fix: usize,
+}
+
+impl SpecReport {
+    fn add(&mut self, card_json: &Value) {
+        let declared =
+            |field: &str| card_json.get(field).and_then(|x| x.as_str());
+        let spec_major = declared("spec").map(|x| match x {
+            "chara_card_v2" => "2",
+            "chara_card_v3" => "3",
+            _ => "unknown",
+        });
+        let version_major =
+            declared("spec_version").map(|x| x.split('.').next().unwrap_or(x));
+        if spec_major != version_major {
+            self.mismatched += 1;
+        }
+        match detect_spec(card_json) {
+            Err(_) => self.v1 += 1,
+            Ok((_, "3.0")) => self.v3 += 1,
+            Ok(_) => self.v2 += 1,
+        }
+        let mut fixed = card_json.clone();
+        if fix_spec_fields(&mut fixed).is_ok_and(|x| !x.is_empty()) {
+            self.need_fix += 1;
+        }
+    }
+
+    fn print(&self) {
+        println!("\nSpec report, by the fields the cards have:");
+        println!("  V1 (no data block): {}", self.v1);
+        println!("  V2: {}", self.v2);
+        println!("  V3: {}", self.v3);
+        println!("  spec and spec_version disagree: {}", self.mismatched);
+        println!("  need fix_spec: {}", self.need_fix);
+    }
+}
+
+/// Prints statistics of all cards in the folder.
+///
+/// Counts cards by spec. With `tokens`, also prints a histogram and percentiles
+/// of permanent tokens of the cards, counted with the tokenizer, and the
+/// largest cards. With `spec_report`, also prints how many cards are V1, V2 and
+/// V3 by their fields, and how many declare their version wrong. Cards that can
+/// not be read are reported and left out, and so are cards outside the range of
+/// `dates`.
+pub fn print_stats(
+    dir_path: &Path,
+    tokens: Option<Tokenizer>,
+    spec_report: bool,
+    dates: &DateFilter,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let png_paths = tools::list_png_files(dir_path, false)?;
+    let png_paths = dates::filter_cards(png_paths, dates, file_options);
+    let mut cards: Vec<(PathBuf, Value)> = Vec::new();
+    let mut failed = 0;
+    for png_path in png_paths {
+        let card_json = tools::read_image_from_file(&png_path, file_options)
+            .and_then(|image| read_card_json(&image));
+        match card_json {
+            Ok(card_json) => cards.push((png_path, card_json)),
+            Err(e) => {
+                println!("Skipping {}: {:#}", png_path.display(), e);
+                failed += 1;
+            }
+        }
+    }
+
+    let mut specs: BTreeMap<&str, usize> = BTreeMap::new();
+    for (_, card_json) in &cards {
+        let spec = card_json.get("spec").and_then(|x| x.as_str());
+        *specs.entry(spec.unwrap_or("no spec")).or_default() += 1;
+    }
+    println!("Cards: {}", cards.len());
+    for (spec, count) in &specs {
+        println!("  {}: {}", spec, count);
+    }
+
+    if spec_report {
+        let mut report = SpecReport::default();
+        for (_, card_json) in &cards {
+            report.add(card_json);
+        }
+        report.print();
+    }
+
+    if let Some(tokenizer) = tokens {
+        let mut counts: Vec<(usize, &Path)> = cards
+            .iter()
+            .map(|(path, card_json)| {
+                let tokens = permanent_tokens(card_json, tokenizer);
+                (tokens.iter().map(|x| x.1).sum(), path.as_path())
+            })
+            .collect();
+        counts.sort();
+        print_token_stats(&counts, tokenizer);
+    }
+
+    if failed > 0 {
+        bail!("{} cards could not be read", failed);
+    }
+    Ok(())
+}
+
+/// Prints histogram, percentiles and outliers of sorted token counts.
+fn print_token_stats(counts: &[(usize, &Path)], tokenizer: Tokenizer) {
+    if counts.is_empty() {
+        return;
+    }
+    let tokens: Vec<usize> = counts.iter().map(|x| x.0).collect();
+
+    println!("\nPermanent tokens ({:?} tokenizer):", tokenizer);
+    let histogram = histogram(&tokens);
+    let largest = histogram.iter().map(|x| x.1).max().unwrap_or(1).max(1);
+    for (label, count) in &histogram {
+        let bar = "#".repeat((count * BAR_WIDTH).div_ceil(largest));
+        let line = format!("  {:>11} | {:<5} {}", label, count, bar);
+        println!("{}", line.trim_end());
+    }
+
+    print!("\nPercentiles:");
+    for p in [50, 90, 95, 99] {
+        print!(" p{}={}", p, percentile(&tokens, p));
+    }
+    println!(" max={}", tokens.last().unwrap_or(&0));
+
+    println!("\nLargest cards:");
+    for (count, path) in counts.iter().rev().take(OUTLIERS_SHOWN) {
+        println!("  {:>6}  {}", count, path.display());
+    }
+}
+
+/// Counts values into `TOKEN_BUCKETS`, returning bucket labels with counts.
+fn histogram(tokens: &[usize]) -> Vec<(String, usize)> {
+    let mut buckets = Vec::new();
+    let mut lower = 0;
+    for &upper in TOKEN_BUCKETS {
+        let count = tokens.iter().filter(|&&x| x >= lower && x < upper);
+        buckets.push((format!("{}-{}", lower, upper), count.count()));
+        lower = upper;
+    }
+    let count = tokens.iter().filter(|&&x| x >= lower).count();
+    buckets.push((format!("{}+", lower), count));
+    buckets
+}
+
+/// Nearest-rank percentile of sorted values.
+fn percentile(sorted: &[usize], p: usize) -> usize {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_histogram_and_percentile() {
+        let tokens = vec![100, 200, 700, 1500, 9000];
+        let histogram = histogram(&tokens);
+        assert_eq!(histogram[0], ("0-500".to_string(), 2));
+        assert_eq!(histogram[1], ("500-1000".to_string(), 1));
+        assert_eq!(histogram[5], ("8000+".to_string(), 1));
+        assert_eq!(percentile(&tokens, 50), 700);
+        assert_eq!(percentile(&tokens, 99), 9000);
+        assert_eq!(percentile(&[], 50), 0);
+    }
+
+    #[test]
+    fn test_spec_report() {
+        let mut report = SpecReport::default();
+        report.add(&json!({ "name": "V1 card" }));
+        report.add(&json!({
+            "spec": "chara_card_v2",
+            "spec_version": "2.0",
+            "data": { "name": "A" }
+        }));
+        report.add(&json!({
+            "spec": "chara_card_v2",
+            "spec_version": "3.0",
+            "data": { "name": "B", "assets": [] }
+        }));
+        let expected =
+            SpecReport { v1: 1, v2: 1, v3: 1, mismatched: 1, need_fix: 1 };
+        assert_eq!(report, expected);
+    }
+}
@@ -0,0 +1,214 @@
+//! Token counts of card text.
+//!
+//! Tokenizers differ between models, so every count here is an estimate of
+//! what the model of the user sees. The default estimate is close to what
+//! common BPE tokenizers give for English text: a token for every four
+//! letters of a word, a token for every punctuation mark, and a token for
+//! every character of scripts without spaces, like Chinese. The GPT-2 and
+//! cl100k tokenizers give exact counts for the OpenAI models that use them,
+//! and are good guesses for other BPE models. None of them know the tokens
+//! that a frontend adds around the fields, or what macros expand to.
+//!
+//! Counts are also off for text in other languages than English, where the
+//! estimate is rougher and BPE tokenizers split words into more tokens than
+//! the tokenizers made for those languages do.
+
+use std::sync::OnceLock;
+
+use serde_json::Value;
+use tiktoken_rs::CoreBPE;
+
+use crate::card_formats::TavernCard;
+
+/// Fields that stay in the prompt for the whole chat.
+pub const PERMANENT_FIELDS: &[&str] = &[
+    "description",
+    "personality",
+    "scenario",
+    "system_prompt",
+    "post_history_instructions",
+];
+
+/// Text fields of card data that are counted, in order.
+const COUNTED_FIELDS: &[&str] = &[
+    "name",
+    "description",
+    "personality",
+    "scenario",
+    "first_mes",
+    "mes_example",
+    "system_prompt",
+    "post_history_instructions",
+    "creator_notes",
+    "alternate_greetings",
+];
+
+/// How tokens are counted.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Tokenizer {
+    /// Estimate from word lengths, fast and good for English text
+    #[default]
+    Estimate,
+    /// Count words separated by whitespace, a rough lower bound
+    Whitespace,
+    /// GPT-2 BPE tokenizer, as used by GPT-2 and GPT-3
+    Gpt2,
+    /// cl100k_base BPE tokenizer, as used by GPT-3.5 and GPT-4
+    Cl100k,
+}
+
+impl Tokenizer {
+    /// Counts tokens in the text.
+    pub fn count(self, text: &str) -> usize {
+        static GPT2: OnceLock<CoreBPE> = OnceLock::new();
+        static CL100K: OnceLock<CoreBPE> = OnceLock::new();
+        let bpe = match self {
+            Tokenizer::Estimate => return estimate_tokens(text),
+            Tokenizer::Whitespace => return text.split_whitespace().count(),
+            Tokenizer::Gpt2 => load_bpe(&GPT2, tiktoken_rs::r50k_base),
+            Tokenizer::Cl100k => load_bpe(&CL100K, tiktoken_rs::cl100k_base),
+        };
+        bpe.encode_ordinary(text).len()
+    }
+}
+
+/// Returns the BPE tokenizer, loading it on first use.
+///
+/// The vocabularies are built into the binary, so loading them only fails
+/// if they are broken, and that is a bug.
+fn load_bpe(
+    cell: &'static OnceLock<CoreBPE>,
+    load: fn() -> anyhow::Result<CoreBPE>,
+) -> &'static CoreBPE {
+    cell.get_or_init(|| load().expect("built-in BPE vocabulary loads"))
+}
+
+/// Token counts of the text fields of a card.
+#[derive(Debug, Default, PartialEq)]
+pub struct FieldTokenCounts {
+    /// Field names with their token counts, without empty fields.
+    /// Alternate greetings are counted together.
+    pub fields: Vec<(&'static str, usize)>,
+    /// Tokens of all the fields
+    pub total: usize,
+}
+
+impl FieldTokenCounts {
+    /// Tokens of the fields that stay in the prompt for the whole chat.
+    pub fn permanent(&self) -> usize {
+        self.fields
+            .iter()
+            .filter(|x| PERMANENT_FIELDS.contains(&x.0))
+            .map(|x| x.1)
+            .sum()
+    }
+}
+
+/// Counts tokens of every text field of the card with the tokenizer. Works
+/// for cards of any version, and for card JSON.
+///
+/// Lorebook entries are not counted, as only some of them are in the prompt
+/// at a time. See the module docs for how close the counts are.
+pub fn count_tokens(
+    card: &dyn TavernCard,
+    tokenizer: Tokenizer,
+) -> FieldTokenCounts {
+    let mut counts = FieldTokenCounts::default();
+    for field in COUNTED_FIELDS {
+        let texts = card.field_texts(field);
+        let tokens: usize = texts.iter().map(|x| tokenizer.count(x)).sum();
+        if tokens > 0 {
+            counts.fields.push((*field, tokens));
+            counts.total += tokens;
+        }
+    }
+    counts
+}
+
+/// Estimates the number of tokens in the text.
+pub fn estimate_tokens(text: &str) -> usize {
+    let mut tokens = 0;
+    let mut word_length: usize = 0;
+    for c in text.chars() {
+        if c.is_ascii_alphanumeric() {
+            word_length += 1;
+            continue;
+        }
+        tokens += word_length.div_ceil(4);
+        word_length = 0;
+        if !c.is_whitespace() {
+            tokens += 1;
+        }
+    }
+    tokens + word_length.div_ceil(4)
+}
+
+/// Counts tokens of every permanent field of the card JSON.
+///
+/// Returns field names with their token counts, skipping empty fields.
+pub fn permanent_tokens(
+    card_json: &Value,
+    tokenizer: Tokenizer,
+) -> Vec<(&'static str, usize)> {
+    let counts = count_tokens(card_json, tokenizer);
+    let permanent = counts.fields.into_iter();
+    permanent.filter(|x| PERMANENT_FIELDS.contains(&x.0)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::card_formats::tavern_card_v2::TavernCardV2;
+    use crate::card_formats::tavern_card_v3::TavernCardV3;
+    use crate::card_formats::CardFile;
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("Hello, world!"), 6);
+        assert_eq!(estimate_tokens("{{char}} is"), 6);
+        assert_eq!(estimate_tokens("初音ミク"), 4);
+    }
+
+    #[test]
+    fn test_permanent_tokens() {
+        let card = json!({
+            "data": {
+                "description": "A tall knight",
+                "personality": "",
+                "first_mes": "Hello"
+            }
+        });
+        let tokens = permanent_tokens(&card, Tokenizer::Estimate);
+        assert_eq!(tokens, vec![("description", 4)]);
+    }
+
+    #[test]
+    fn test_count_tokens() {
+        let card = json!({
+            "data": {
+                "name": "Knight",
+                "description": "A tall knight, loyal to the crown.",
+                "alternate_greetings": ["Hello there.", "Halt!"]
+            }
+        });
+        let counts = count_tokens(&card, Tokenizer::Whitespace);
+        assert_eq!(
+            counts.fields,
+            vec![("name", 1), ("description", 7), ("alternate_greetings", 3)]
+        );
+        assert_eq!(counts.total, 11);
+        assert_eq!(counts.permanent(), 7);
+
+        // Cards count the same as their JSON
+        let card_v2 = TavernCardV2::from_json(&card.to_string()).unwrap();
+        let card_v3 = TavernCardV3::from_json(&card.to_string()).unwrap();
+        assert_eq!(count_tokens(&card_v2, Tokenizer::Whitespace), counts);
+        assert_eq!(count_tokens(&card_v3, Tokenizer::Whitespace), counts);
+
+        assert_eq!(Tokenizer::Cl100k.count("Hello, world!"), 4);
+        assert_eq!(Tokenizer::Gpt2.count("Hello, world!"), 4);
+    }
+}
@@ -0,0 +1,576 @@
+//! Checks of card for spec compliance and common quality problems.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+use crate::card_formats::strict::check_duplicate_keys;
+use crate::card_formats::{
+    check_spec_version, read_card_json, read_card_text, validate_card_json,
+    validate_card_json_as, SpecVersion,
+};
+use crate::color::Color;
+use crate::lorebook::read_character_book;
+use crate::tools::{self, FileOptions};
+use crate::wrap::PrintOptions;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Settings that change what the lints look for.
+#[derive(Debug)]
+pub struct LintSettings {
+    /// Names of lints that should not run.
+    pub disabled: Vec<String>,
+    /// Descriptions shorter than this many characters are reported.
+    pub min_description_length: usize,
+    /// Check the spec against this version, whatever the card declares.
+    pub spec_version: Option<SpecVersion>,
+    /// Report objects with the same key twice as errors.
+    pub strict: bool,
+    /// Lorebooks with fewer entries than this are reported.
+    pub min_lorebook_entries: usize,
+}
+
+impl Default for LintSettings {
+    fn default() -> Self {
+        LintSettings {
+            disabled: Vec::new(),
+            min_description_length: 50,
+            spec_version: None,
+            strict: false,
+            min_lorebook_entries: 1,
+        }
+    }
+}
+
+pub struct Lint {
+    pub name: &'static str,
+    pub severity: Severity,
+    pub description: &'static str,
+    check: fn(&Value, &LintSettings) -> Vec<String>,
+}
+
+/// A problem found by a lint.
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    pub lint: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+pub const LINTS: &[Lint] = &[
+    Lint {
+        name: "unsupported_spec",
+        severity: Severity::Error,
+        description: "Card does not declare a spec newer than V3",
+        check: check_unsupported_spec,
+    },
+    Lint {
+        name: "spec",
+        severity: Severity::Error,
+        description: "Card matches V2 or V3 specification",
+        check: check_spec,
+    },
+    Lint {
+        name: "missing_name",
+        severity: Severity::Error,
+        description: "Card has a name",
+        check: check_name,
+    },
+    Lint {
+        name: "empty_field",
+        severity: Severity::Warning,
+        description: "Description and first message are not empty",
+        check: check_empty_fields,
+    },
+    Lint {
+        name: "short_description",
+        severity: Severity::Warning,
+        description: "Description is not too short",
+        check: check_description_length,
+    },
+    Lint {
+        name: "first_mes_macros",
+        severity: Severity::Warning,
+        description: "First message uses {{char}} or {{user}}",
+        check: check_first_mes_macros,
+    },
+    Lint {
+        name: "example_start",
+        severity: Severity::Warning,
+        description: "Example messages are separated with <START>",
+        check: check_example_start,
+    },
+    Lint {
+        name: "duplicate_lorebook_keys",
+        severity: Severity::Warning,
+        description: "Lorebook entries do not share keys",
+        check: check_duplicate_lorebook_keys,
+    },
+    Lint {
+        name: "lorebook_entries",
+        severity: Severity::Warning,
+        description: "Lorebook can be read, has enough entries, all with keys and content",
+        check: check_lorebook_entries,
+    },
+    Lint {
+        name: "multilingual_notes",
+        severity: Severity::Warning,
+        description: "Translated creator notes are filled, with a default",
+        check: check_multilingual_notes,
+    },
+];
+
+/// Runs all enabled lints on the card JSON.
+///
+/// Findings are sorted by severity, errors first.
+pub fn lint_card(card_json: &Value, settings: &LintSettings) -> Vec<Finding> {
+    let mut findings: Vec<Finding> = LINTS
+        .iter()
+        .filter(|lint| !settings.disabled.iter().any(|x| x == lint.name))
+        .flat_map(|lint| {
+            (lint.check)(card_json, settings).into_iter().map(|message| {
+                Finding { lint: lint.name, severity: lint.severity, message }
+            })
+        })
+        .collect();
+    findings.sort_by_key(|x| x.severity);
+    findings
+}
+
+/// Checks the card and prints findings grouped by severity.
+///
+/// Returns error if any lint of error severity failed. Warnings alone do not
+/// fail the validation, unless `fail_on_warning` is set.
+pub fn validate_tavern_file(
+    png_path: &Path,
+    settings: &LintSettings,
+    fail_on_warning: bool,
+    file_options: &FileOptions,
+    print: &PrintOptions,
+) -> Result<()> {
+    for name in &settings.disabled {
+        if !LINTS.iter().any(|lint| lint.name == name) {
+            bail!("Unknown lint \"{}\"", name);
+        }
+    }
+    let image_data = tools::read_image_from_file(png_path, file_options)?;
+    let card_json = read_card_json(&image_data)?;
+    let mut findings = lint_card(&card_json, settings);
+    if settings.strict {
+        let text = read_card_text(&image_data)?;
+        if let Err(e) = check_duplicate_keys(&text) {
+            let message = format!("{:#}", e);
+            let finding =
+                Finding { lint: "strict", severity: Severity::Error, message };
+            findings.insert(0, finding);
+        }
+    }
+    if let Some(version) = settings.spec_version {
+        let forced_ok = validate_card_json_as(&card_json, version).is_ok();
+        if forced_ok && validate_card_json(&card_json).is_err() {
+            println!("Read as {:?}, automatic detection failed", version);
+        }
+    }
+
+    if let Ok(Some(book)) = read_character_book(&card_json) {
+        println!("Lorebook: {} entries", book.entries.len());
+    }
+
+    let count =
+        |severity| findings.iter().filter(|x| x.severity == severity).count();
+    let errors = count(Severity::Error);
+    let warnings = count(Severity::Warning);
+    for (severity, title, title_color) in [
+        (Severity::Error, "Errors:", Color::Red),
+        (Severity::Warning, "Warnings:", Color::Yellow),
+    ] {
+        if count(severity) == 0 {
+            continue;
+        }
+        println!("{}", print.paint(title, title_color));
+        for finding in findings.iter().filter(|x| x.severity == severity) {
+            println!("  [{}] {}", finding.lint, finding.message);
+        }
+    }
+    println!("{} errors, {} warnings", errors, warnings);
+    if errors > 0 {
+        bail!("Card failed validation");
+    }
+    if fail_on_warning && warnings > 0 {
+        bail!("Card failed validation, warnings count as errors");
+    }
+    Ok(())
+}
+
+/// Checks every card of the list, printing findings of each.
+///
+/// Returns error at the end if any card failed validation, see
+/// [`validate_tavern_file`].
+pub fn validate_tavern_files(
+    png_paths: &[PathBuf],
+    settings: &LintSettings,
+    fail_on_warning: bool,
+    file_options: &FileOptions,
+    print: &PrintOptions,
+) -> Result<()> {
+    let mut failed = 0;
+    for png_path in png_paths {
+        println!("{}:", png_path.display());
+        let result = validate_tavern_file(
+            png_path,
+            settings,
+            fail_on_warning,
+            file_options,
+            print,
+        );
+        if let Err(e) = result {
+            println!("{:#}", e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} cards failed validation", failed, png_paths.len());
+    }
+    Ok(())
+}
+
+/// Prints all lints with their severity.
+pub fn print_lints() {
+    for lint in LINTS {
+        println!("{:<24} {:?}: {}", lint.name, lint.severity, lint.description);
+    }
+}
+
+/// Returns text of the card data field, or "" if it is not text.
+pub(crate) fn get_text<'a>(card_json: &'a Value, field: &str) -> &'a str {
+    card_json
+        .get("data")
+        .and_then(|data| data.get(field))
+        .and_then(|x| x.as_str())
+        .unwrap_or_default()
+}
+
+fn check_unsupported_spec(
+    card_json: &Value,
+    settings: &LintSettings,
+) -> Vec<String> {
+    match check_spec_version(card_json) {
+        Err(e) if settings.spec_version.is_none() => vec![format!("{:#}", e)],
+        _ => Vec::new(),
+    }
+}
+
+fn check_spec(card_json: &Value, settings: &LintSettings) -> Vec<String> {
+    let result = match settings.spec_version {
+        Some(version) => validate_card_json_as(card_json, version),
+        // Newer specs are reported by unsupported_spec alone
+        None if check_spec_version(card_json).is_err() => Ok(()),
+        None => validate_card_json(card_json),
+    };
+    match result {
+        Ok(()) => Vec::new(),
+        Err(e) => vec![format!("{:#}", e)],
+    }
+}
+
+fn check_name(card_json: &Value, _: &LintSettings) -> Vec<String> {
+    if get_text(card_json, "name").trim().is_empty() {
+        return vec!["Name is empty".to_string()];
+    }
+    Vec::new()
+}
+
+fn check_empty_fields(card_json: &Value, _: &LintSettings) -> Vec<String> {
+    ["description", "first_mes"]
+        .iter()
+        .filter(|field| is_empty_text(get_text(card_json, field)))
+        .map(|field| format!("Field {} is empty", field))
+        .collect()
+}
+
+/// Returns true for text that the `empty_field` lint reports: nothing but
+/// whitespace.
+pub(crate) fn is_empty_text(text: &str) -> bool {
+    text.trim().is_empty()
+}
+
+fn check_description_length(
+    card_json: &Value,
+    settings: &LintSettings,
+) -> Vec<String> {
+    let length = get_text(card_json, "description").trim().chars().count();
+    // Empty description is reported by its own lint
+    if length == 0 || length >= settings.min_description_length {
+        return Vec::new();
+    }
+    vec![format!(
+        "Description is {} characters long, expected at least {}",
+        length, settings.min_description_length
+    )]
+}
+
+fn check_first_mes_macros(card_json: &Value, _: &LintSettings) -> Vec<String> {
+    let first_mes = get_text(card_json, "first_mes");
+    if first_mes.trim().is_empty()
+        || first_mes.contains("{{char}}")
+        || first_mes.contains("{{user}}")
+    {
+        return Vec::new();
+    }
+    vec!["First message uses neither {{char}} nor {{user}}".to_string()]
+}
+
+fn check_example_start(card_json: &Value, _: &LintSettings) -> Vec<String> {
+    let examples = get_text(card_json, "mes_example");
+    if examples.trim().is_empty() || examples.contains("<START>") {
+        return Vec::new();
+    }
+    vec!["Example messages are not separated with <START>".to_string()]
+}
+
+fn check_duplicate_lorebook_keys(
+    card_json: &Value,
+    _: &LintSettings,
+) -> Vec<String> {
+    let Ok(Some(book)) = read_character_book(card_json) else {
+        return Vec::new();
+    };
+    let mut entries_by_key: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (index, entry) in book.entries.iter().enumerate() {
+        for key in &entry.keys {
+            let key = key.trim().to_lowercase();
+            let entries = entries_by_key.entry(key).or_default();
+            if !entries.contains(&index) {
+                entries.push(index);
+            }
+        }
+    }
+    entries_by_key
+        .into_iter()
+        .filter(|(_, entries)| entries.len() > 1)
+        .map(|(key, entries)| {
+            format!("Key \"{}\" is used by entries {:?}", key, entries)
+        })
+        .collect()
+}
+
+fn check_lorebook_entries(
+    card_json: &Value,
+    settings: &LintSettings,
+) -> Vec<String> {
+    let book = match read_character_book(card_json) {
+        Ok(Some(book)) => book,
+        Ok(None) => return Vec::new(),
+        Err(e) => return vec![format!("{:#}", e)],
+    };
+    let mut problems = Vec::new();
+    if book.entries.len() < settings.min_lorebook_entries {
+        problems.push(format!(
+            "Lorebook has {} entries, expected at least {}",
+            book.entries.len(),
+            settings.min_lorebook_entries
+        ));
+    }
+    for (index, entry) in book.entries.iter().enumerate() {
+        // Constant entries are always inserted, keys or not
+        let constant = entry.constant.unwrap_or_default();
+        if !constant && entry.keys.iter().all(|x| x.trim().is_empty()) {
+            problems.push(format!("Entry {} has no keys", index));
+        }
+        if entry.content.trim().is_empty() {
+            problems.push(format!("Entry {} has no content", index));
+        }
+    }
+    problems
+}
+
+fn check_multilingual_notes(
+    card_json: &Value,
+    _: &LintSettings,
+) -> Vec<String> {
+    let Some(notes) = card_json
+        .get("data")
+        .and_then(|data| data.get("creator_notes_multilingual"))
+        .and_then(|x| x.as_object())
+    else {
+        return Vec::new();
+    };
+    let mut problems: Vec<String> = notes
+        .iter()
+        .filter(|(_, note)| note.as_str().is_none_or(|x| x.trim().is_empty()))
+        .map(|(lang, _)| format!("Creator notes for \"{}\" are empty", lang))
+        .collect();
+    if !notes.is_empty()
+        && get_text(card_json, "creator_notes").trim().is_empty()
+    {
+        problems.push(
+            "Creator notes are translated, but default creator_notes is empty"
+                .to_string(),
+        );
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::card_formats::write_card_json;
+
+    fn lint_names(
+        card_json: &Value,
+        settings: &LintSettings,
+    ) -> Vec<&'static str> {
+        lint_card(card_json, settings).iter().map(|x| x.lint).collect()
+    }
+
+    #[test]
+    fn test_lints() {
+        let card = json!({
+            "spec": "chara_card_v2",
+            "spec_version": "2.0",
+            "data": {
+                "name": "Test name",
+                "description": "Too short",
+                "first_mes": "Hello there",
+                "mes_example": "{{char}}: Hi",
+                "character_book": {
+                    "entries": [
+                        { "keys": ["Castle", "king"], "content": "A" },
+                        { "keys": ["castle"], "content": "B" }
+                    ]
+                }
+            }
+        });
+        let settings = LintSettings::default();
+        assert_eq!(
+            lint_names(&card, &settings),
+            vec![
+                "short_description",
+                "first_mes_macros",
+                "example_start",
+                "duplicate_lorebook_keys"
+            ]
+        );
+
+        let settings = LintSettings {
+            disabled: vec!["example_start".to_string()],
+            min_description_length: 5,
+            ..Default::default()
+        };
+        assert_eq!(
+            lint_names(&card, &settings),
+            vec!["first_mes_macros", "duplicate_lorebook_keys"]
+        );
+    }
+
+    #[test]
+    fn test_errors_come_first() {
+        let card = json!({ "spec": "chara_card_v2", "data": 5 });
+        let findings = lint_card(&card, &LintSettings::default());
+        assert_eq!(findings[0].severity, Severity::Error);
+        assert_eq!(
+            lint_names(&card, &LintSettings::default()),
+            vec!["spec", "missing_name", "empty_field", "empty_field"]
+        );
+    }
+
+    #[test]
+    fn test_unsupported_spec() {
+        let card = json!({
+            "spec": "chara_card_v4",
+            "spec_version": "4.0",
+            "data": { "name": "Test", "first_mes": "Hi {{user}}" }
+        });
+        let settings = LintSettings::default();
+        assert_eq!(
+            lint_names(&card, &settings),
+            vec!["unsupported_spec", "empty_field"]
+        );
+        let settings =
+            LintSettings { spec_version: Some(SpecVersion::V3), ..settings };
+        assert_eq!(lint_names(&card, &settings), vec!["empty_field"]);
+    }
+
+    #[test]
+    fn test_fail_on_warning() -> Result<()> {
+        let card = json!({
+            "spec": "chara_card_v2",
+            "data": { "name": "Test", "first_mes": "Hi {{user}}" }
+        });
+        let path = std::env::temp_dir().join("taverntools_test_warnings.png");
+        let files = FileOptions::default();
+        let image =
+            write_card_json(&card, &tools::get_default_image(), &files)?;
+        tools::write_image_to_file(&image, &path)?;
+        let settings = LintSettings::default();
+        let validate = |fail_on_warning| {
+            let print = PrintOptions::default();
+            validate_tavern_file(
+                &path,
+                &settings,
+                fail_on_warning,
+                &files,
+                &print,
+            )
+        };
+        assert!(validate(false).is_ok());
+        assert!(validate(true).is_err());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_lorebook_entries() {
+        let card = json!({
+            "data": {
+                "character_book": {
+                    "entries": [
+                        { "keys": [" "], "content": "A" },
+                        { "keys": [], "content": "B", "constant": true },
+                        { "keys": ["c"], "content": "" }
+                    ]
+                }
+            }
+        });
+        let settings = LintSettings::default();
+        assert_eq!(
+            check_lorebook_entries(&card, &settings),
+            vec!["Entry 0 has no keys", "Entry 2 has no content"]
+        );
+        let settings = LintSettings { min_lorebook_entries: 5, ..settings };
+        assert_eq!(check_lorebook_entries(&card, &settings).len(), 3);
+
+        let card = json!({ "data": { "character_book": { "entries": 5 } } });
+        assert_eq!(check_lorebook_entries(&card, &settings).len(), 1);
+    }
+
+    #[test]
+    fn test_multilingual_notes() {
+        let card = json!({
+            "data": {
+                "creator_notes_multilingual": { "de": "Hallo", "fr": " " }
+            }
+        });
+        assert_eq!(
+            check_multilingual_notes(&card, &LintSettings::default()).len(),
+            2
+        );
+        let card = json!({
+            "data": {
+                "creator_notes": "Hello",
+                "creator_notes_multilingual": { "de": "Hallo" }
+            }
+        });
+        assert!(check_multilingual_notes(&card, &LintSettings::default())
+            .is_empty());
+    }
+}
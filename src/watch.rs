@@ -0,0 +1,187 @@
+//! Watching the input folder for new cards.
+//!
+//! File system events of the folder are debounced: a card is processed once
+//! no events came for it for the debounce time, so that files still being
+//! copied or downloaded are left alone. Its size and modification time are
+//! remembered, so that events that do not change the card, like reading it,
+//! do not process it again.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use notify_debouncer_mini::notify::RecursiveMode;
+use notify_debouncer_mini::{new_debouncer, DebouncedEventKind};
+
+use crate::actions::{self, Artifacts};
+use crate::config::IssueLayout;
+use crate::output_template;
+use crate::tools::{self, FileOptions};
+
+/// Shortest debounce time, in seconds. Shorter ones would process cards
+/// that are still being written.
+const MIN_DEBOUNCE: f64 = 0.1;
+
+/// Longest debounce time, in seconds.
+const MAX_DEBOUNCE: f64 = 3600.0;
+
+/// Parses debounce time in seconds. Fails for times that are not numbers,
+/// and for times shorter than 0.1 seconds or longer than an hour.
+pub fn parse_debounce(text: &str) -> Result<f64, String> {
+    let seconds: f64 =
+        text.trim().parse().map_err(|_| format!("{} is not a number", text))?;
+    if !(MIN_DEBOUNCE..=MAX_DEBOUNCE).contains(&seconds) {
+        return Err(format!(
+            "{} is not a debounce time: give seconds from {} to {}",
+            text, MIN_DEBOUNCE, MAX_DEBOUNCE
+        ));
+    }
+    Ok(seconds)
+}
+
+/// Size and modification time of a file, to tell when it changed.
+type FileState = (u64, SystemTime);
+
+fn file_state(path: &Path) -> Option<FileState> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+/// Returns true for PNG files that watching processes.
+fn is_watched_card(path: &Path, follow_symlinks: bool) -> bool {
+    let is_png =
+        path.extension().is_some_and(|x| x.eq_ignore_ascii_case("png"));
+    is_png && path.is_file() && (follow_symlinks || !path.is_symlink())
+}
+
+/// Watches the input folder, extracting cards that appear or change in it
+/// like `process_all` does. Runs until interrupted.
+///
+/// Cards already in the folder when watching starts are left alone. Cards
+/// that fail are moved into the issue folder, and watching goes on.
+#[allow(clippy::too_many_arguments)]
+pub fn watch_folder(
+    input_dir: &Path,
+    output_dir: &Path,
+    issue_dir: &Path,
+    issue_layout: &IssueLayout,
+    artifacts: Artifacts,
+    follow_symlinks: bool,
+    debounce: Duration,
+    file_options: &FileOptions,
+) -> Result<()> {
+    let mut processed: HashMap<PathBuf, FileState> =
+        tools::list_png_files(input_dir, true)?
+            .into_iter()
+            .filter(|path| is_watched_card(path, follow_symlinks))
+            .filter_map(|path| Some((path.clone(), file_state(&path)?)))
+            .collect();
+
+    let (sender, receiver) = mpsc::channel();
+    let mut debouncer = new_debouncer(debounce, sender)?;
+    debouncer
+        .watcher()
+        .watch(input_dir, RecursiveMode::Recursive)
+        .with_context(|| format!("Could not watch {}", input_dir.display()))?;
+    println!(
+        "Watching {} for new cards, {} already there. Press Ctrl+C to stop",
+        input_dir.display(),
+        processed.len()
+    );
+
+    for events in receiver {
+        let events = match events {
+            Ok(events) => events,
+            Err(e) => {
+                warn!("Could not watch {}: {:#}", input_dir.display(), e);
+                continue;
+            }
+        };
+        for event in events {
+            // Files still being written get more events later
+            if event.kind != DebouncedEventKind::Any {
+                continue;
+            }
+            let path = event.path;
+            if !is_watched_card(&path, follow_symlinks) {
+                processed.remove(&path);
+                continue;
+            }
+            let Some(state) = file_state(&path) else {
+                continue;
+            };
+            if processed.insert(path.clone(), state) == Some(state) {
+                continue;
+            }
+            process_new_card(
+                &path,
+                output_dir,
+                issue_dir,
+                issue_layout,
+                artifacts,
+                file_options,
+            );
+        }
+    }
+    Ok(())
+}
+
+fn process_new_card(
+    path: &Path,
+    output_dir: &Path,
+    issue_dir: &Path,
+    issue_layout: &IssueLayout,
+    artifacts: Artifacts,
+    file_options: &FileOptions,
+) {
+    let output_base =
+        output_template::output_base(path, output_dir, None, file_options);
+    let result = output_base.and_then(|base| {
+        actions::extract_artifacts(path, &base, artifacts, file_options)
+    });
+    match result {
+        Ok(_) => {
+            info!("Successfully processed {}", path.display());
+            println!("Processed {}", path.display());
+        }
+        Err(e) => {
+            match actions::move_to_issues(
+                path,
+                issue_dir,
+                issue_layout,
+                &e,
+                false,
+            ) {
+                Ok(issue_path) => println!(
+                    "Moved {} to {} due to error: {:#}",
+                    path.display(),
+                    issue_path.display(),
+                    e
+                ),
+                Err(move_error) => println!(
+                    "Failed to process {}: {:#}, and could not move it: {:#}",
+                    path.display(),
+                    e,
+                    move_error
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_debounce() {
+        assert_eq!(parse_debounce("2"), Ok(2.0));
+        assert_eq!(parse_debounce(" 0.1 "), Ok(0.1));
+        for text in ["0", "-1", "NaN", "inf", "1e300", "3601", "soon"] {
+            assert!(parse_debounce(text).is_err(), "{}", text);
+        }
+    }
+}
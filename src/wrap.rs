@@ -0,0 +1,121 @@
+//! Decides how wide printed text is wrapped.
+//!
+//! Text is wrapped to `--width` when given, otherwise to the terminal width
+//! when stdout is a terminal. Piped output and `--no-wrap` are not wrapped,
+//! so that logs and files keep the original lines.
+//!
+//! Printed card fields can also be cut at `print --field-max-bytes`.
+
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use is_terminal::IsTerminal;
+use textwrap::{Options, WrapAlgorithm};
+
+use crate::color::{self, Color};
+
+/// Wrap width, zero means no wrapping.
+static WIDTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the width was given by the user, rather than detected.
+static EXPLICIT: AtomicBool = AtomicBool::new(false);
+
+/// How cards and reports are printed, besides the wrap width.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PrintOptions {
+    /// Color the output, see [`color::detect`].
+    pub color: bool,
+    /// Longest printed card field, in bytes.
+    pub field_max_bytes: Option<usize>,
+}
+
+impl PrintOptions {
+    /// Wraps text in ANSI codes of the color, if colors are on.
+    pub fn paint(&self, text: &str, color: Color) -> String {
+        color::paint(text, color, self.color)
+    }
+
+    /// Cuts the printed field value at `field_max_bytes`, see
+    /// [`truncate_to`].
+    pub fn truncate_field<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        match self.field_max_bytes {
+            Some(max_bytes) => truncate_to(value, max_bytes),
+            None => Cow::Borrowed(value),
+        }
+    }
+}
+
+/// Sets the wrap width, from `--width` and `--no-wrap`.
+pub fn init(width: Option<usize>, no_wrap: bool) {
+    let (width, explicit) = match width {
+        _ if no_wrap => (0, false),
+        Some(width) => (width.max(1), true),
+        None if std::io::stdout().is_terminal() => {
+            (textwrap::termwidth(), false)
+        }
+        None => (0, false),
+    };
+    WIDTH.store(width, Ordering::Relaxed);
+    EXPLICIT.store(explicit, Ordering::Relaxed);
+}
+
+/// Returns the wrap width, or `None` if text is not wrapped.
+///
+/// Detected terminal width is capped at `max`, a width given by the user is
+/// not.
+pub fn width(max: usize) -> Option<usize> {
+    match WIDTH.load(Ordering::Relaxed) {
+        0 => None,
+        width if EXPLICIT.load(Ordering::Relaxed) => Some(width),
+        width => Some(width.min(max)),
+    }
+}
+
+/// Returns options for `textwrap::fill` with the wrap width, see [`width`].
+///
+/// Without wrapping, lines are kept whole, but indents still apply.
+pub fn options(max: usize) -> Options<'static> {
+    match width(max) {
+        Some(width) => Options::new(width),
+        // Optimal fit overflows on huge widths, first fit does not
+        None => {
+            Options::new(usize::MAX).wrap_algorithm(WrapAlgorithm::FirstFit)
+        }
+    }
+}
+
+/// Cuts the text at `max_bytes` on a character boundary, and tells how much
+/// was left out.
+pub fn truncate_to(value: &str, max_bytes: usize) -> Cow<'_, str> {
+    if value.len() <= max_bytes {
+        return Cow::Borrowed(value);
+    }
+    let mut end = max_bytes;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    let more = value.len() - end;
+    Cow::Owned(format!("{}… ({} more bytes)", &value[..end], more))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_wrap_options() {
+        let text = "word ".repeat(100);
+        let options = options(80).subsequent_indent("  ");
+        let filled = textwrap::fill(&format!("{}\nnext", text), &options);
+        assert_eq!(filled, format!("{}\n  next", text.trim_end()));
+    }
+
+    #[test]
+    fn test_truncate_to() {
+        assert_eq!(truncate_to("Long text", 0), "… (9 more bytes)");
+        assert_eq!(truncate_to("Short", 5), "Short");
+        assert_eq!(truncate_to("Long text", 5), "Long … (4 more bytes)");
+        // "ü" takes two bytes, and is not cut in half
+        assert_eq!(truncate_to("Tschüss", 5), "Tsch… (4 more bytes)");
+    }
+}